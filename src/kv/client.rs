@@ -1,17 +1,38 @@
-use zeyrho::zeyrho::kv_store::SetRequest;
 use zeyrho::zeyrho::kv_store::kv_store_client::KvStoreClient;
+use zeyrho::zeyrho::kv_store::set_request::Value;
+use zeyrho::zeyrho::kv_store::{GetRequest, SetRequest};
 
+// not called from the server, it's here to poke at a running one
+#[allow(dead_code)]
 pub async fn execute_queries() -> Result<Vec<String>, tonic::transport::Error> {
     let mut client = KvStoreClient::connect("http://localhost:8080").await?;
 
     let request = tonic::Request::new(SetRequest {
-        key: "Something".to_string(),
-        value: 1000,
+        key: b"Something".to_vec(),
+        value: Some(Value::BytesValue(b"{\"count\": 1000}".to_vec())),
+        wait_for_apply: false,
     });
 
     let response = client.set(request).await.unwrap();
 
     println!("RESPONSE: {}", response.get_ref().confirmation);
 
+    // callers from before values were bytes can keep sending and reading ints
+    let request = tonic::Request::new(SetRequest {
+        key: b"Counter".to_vec(),
+        value: Some(Value::Int32Value(1000)),
+        wait_for_apply: false,
+    });
+    let sequence = client.set(request).await.unwrap().get_ref().sequence;
+
+    // reading with the sequence of the set makes sure the get sees it
+    let request = tonic::Request::new(GetRequest {
+        key: b"Counter".to_vec(),
+        min_sequence: sequence,
+    });
+    let response = client.get(request).await.unwrap();
+
+    println!("COUNTER: {:?}", response.get_ref().int32_value);
+
     Ok(Vec::new())
 }
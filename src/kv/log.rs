@@ -0,0 +1,218 @@
+use rmp_serde::{Deserializer, Serializer};
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use zeyrho::queue::wal::segmented::SegmentedWal;
+use zeyrho::queue::wal::wal::Wal;
+
+// how many records go into a segment before the log rolls over to a new one
+pub const DEFAULT_SEGMENT_RECORDS: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Mutation {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogRecord {
+    pub sequence: u64,
+    pub mutation: Mutation,
+}
+
+impl LogRecord {
+    fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = Vec::new();
+        self.serialize(&mut Serializer::new(&mut buf))
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        Ok(buf)
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, Error> {
+        LogRecord::deserialize(&mut Deserializer::new(buf))
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+// The kv store's write-ahead log. Every mutation is appended here with the next sequence number before it is applied,
+// the log is a SegmentedWal that rolls over every `segment_records` records. Sequence numbers start at 1, so the record
+// with sequence s is the wal entry at index s - 1.
+#[derive(Debug)]
+pub struct KvLog {
+    wal: SegmentedWal,
+}
+
+impl KvLog {
+    pub fn open(dir: impl Into<PathBuf>, segment_records: usize) -> Result<Self, Error> {
+        let wal = SegmentedWal::open(dir, usize::MAX)?.with_segment_records(segment_records);
+        let log = KvLog { wal };
+
+        // the sequence numbers are implied by where a record sits, make sure that still agrees with the records
+        if log.wal.size() > log.wal.base_index() {
+            let last = LogRecord::decode(&log.wal.read(log.wal.size() - 1)?)?;
+            if last.sequence != log.last_sequence() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "kv log ends at sequence {} but its last record has sequence {}",
+                        log.last_sequence(),
+                        last.sequence
+                    ),
+                ));
+            }
+        }
+
+        Ok(log)
+    }
+
+    // sequence number of the last record in the log, 0 when it is empty
+    pub fn last_sequence(&self) -> u64 {
+        self.wal.size() as u64
+    }
+
+    pub fn append(&mut self, mutation: Mutation) -> Result<LogRecord, Error> {
+        let record = LogRecord {
+            sequence: self.last_sequence() + 1,
+            mutation,
+        };
+        self.wal.write(&record.encode()?)?;
+
+        Ok(record)
+    }
+
+    // the records from `from_sequence` onwards, in order, or from the first one still in the log if that is later
+    pub fn replay(
+        &self,
+        from_sequence: u64,
+    ) -> Result<impl Iterator<Item = Result<LogRecord, Error>>, Error> {
+        let index = (from_sequence.saturating_sub(1) as usize).max(self.wal.base_index());
+        Ok(self
+            .wal
+            .iter_from(index)?
+            .map(|payload| payload.and_then(|payload| LogRecord::decode(&payload))))
+    }
+
+    // Drops the segments that only hold records up to `sequence`, once a snapshot covers them. The segment being
+    // appended to always stays, so the next sequence number is still known.
+    pub fn truncate_until(&mut self, sequence: u64) -> Result<(), Error> {
+        self.wal.clean_until(sequence as usize)
+    }
+
+    // sequence number of the first record still in the log
+    pub fn first_sequence(&self) -> u64 {
+        self.wal.base_index() as u64 + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn read_all(log: &KvLog) -> Vec<LogRecord> {
+        log.replay(0).unwrap().map(Result::unwrap).collect()
+    }
+
+    fn set(key: &[u8], value: &[u8]) -> Mutation {
+        Mutation::Set {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_append_and_read() {
+        let dir = tempdir().unwrap();
+        let mut log = KvLog::open(dir.path(), DEFAULT_SEGMENT_RECORDS).unwrap();
+        assert_eq!(log.last_sequence(), 0);
+
+        let record = log.append(set(b"a", b"1")).unwrap();
+        assert_eq!(record.sequence, 1);
+        let record = log.append(Mutation::Delete { key: b"a".to_vec() }).unwrap();
+        assert_eq!(record.sequence, 2);
+
+        assert_eq!(log.last_sequence(), 2);
+        assert_eq!(read_all(&log), vec![
+            LogRecord {
+                sequence: 1,
+                mutation: set(b"a", b"1"),
+            },
+            LogRecord {
+                sequence: 2,
+                mutation: Mutation::Delete { key: b"a".to_vec() },
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_segments_roll_over_and_reopen() {
+        let dir = tempdir().unwrap();
+
+        {
+            let mut log = KvLog::open(dir.path(), 3).unwrap();
+            for i in 0..10u8 {
+                log.append(set(&[i], &[i])).unwrap();
+            }
+        }
+
+        let segment_files = fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension().unwrap() == "wal")
+            .count();
+        assert_eq!(segment_files, 4);
+
+        let mut log = KvLog::open(dir.path(), 3).unwrap();
+        assert_eq!(log.last_sequence(), 10);
+        for (i, record) in read_all(&log).into_iter().enumerate() {
+            assert_eq!(record.sequence, i as u64 + 1);
+            assert_eq!(record.mutation, set(&[i as u8], &[i as u8]));
+        }
+
+        assert_eq!(log.append(set(b"k", b"v")).unwrap().sequence, 11);
+    }
+
+    #[test]
+    fn test_truncate_until() {
+        let dir = tempdir().unwrap();
+        let mut log = KvLog::open(dir.path(), 4).unwrap();
+        for i in 0..10u8 {
+            log.append(set(&[i], &[i])).unwrap();
+        }
+
+        // sequence 6 is in the middle of the second segment, so only the first one can go
+        log.truncate_until(6).unwrap();
+        assert_eq!(log.first_sequence(), 5);
+        log.truncate_until(8).unwrap();
+        assert_eq!(log.first_sequence(), 9);
+
+        // the active segment stays even once everything in it is covered
+        log.truncate_until(10).unwrap();
+        assert_eq!(log.first_sequence(), 9);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+
+        let log = KvLog::open(dir.path(), 4).unwrap();
+        assert_eq!(log.first_sequence(), 9);
+        assert_eq!(log.last_sequence(), 10);
+        let sequences: Vec<u64> = read_all(&log).iter().map(|r| r.sequence).collect();
+        assert_eq!(sequences, vec![9, 10]);
+    }
+
+    #[test]
+    fn test_replay_from_the_middle() {
+        let dir = tempdir().unwrap();
+        let mut log = KvLog::open(dir.path(), 4).unwrap();
+        for i in 0..10u8 {
+            log.append(set(&[i], &[i])).unwrap();
+        }
+
+        for from in 1..=11 {
+            let sequences: Vec<u64> = log
+                .replay(from)
+                .unwrap()
+                .map(|record| record.unwrap().sequence)
+                .collect();
+            assert_eq!(sequences, (from..=10).collect::<Vec<_>>());
+        }
+    }
+}
@@ -1,23 +1,37 @@
 mod client;
+mod log;
+mod scan;
+mod snapshot;
+mod storage;
 
-use nanoid::nanoid;
-use rmp_serde::{Deserializer, Serializer};
+use log::{DEFAULT_SEGMENT_RECORDS, KvLog, LogRecord, Mutation};
+use rmp_serde::Deserializer;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use snapshot::Snapshot;
 use std::fs;
-use std::fs::File;
-use std::io::{Read, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::channel;
 use std::sync::{Arc, Mutex};
 use std::thread::spawn;
+use std::time::Duration;
+use storage::{Engine, Storage};
+use tokio::sync::{oneshot, watch};
 use tonic::service::Interceptor;
+use tonic::service::interceptor::InterceptedService;
 use tonic::{Request, Response, Status, async_trait, transport::Server};
 use zeyrho::zeyrho::kv_store::kv_store_server::{KvStore, KvStoreServer};
+use zeyrho::zeyrho::kv_store::set_request::Value;
 use zeyrho::zeyrho::kv_store::{
-    DeleteRequest, DeleteResponse, GetRequest, GetResponse, SetRequest, SetResponse,
+    DeleteRequest, DeleteResponse, GetRequest, GetResponse, ScanRequest, ScanResponse, SetRequest,
+    SetResponse, SnapshotRequest, SnapshotResponse,
 };
 
 const DATA_DIR: &str = "data";
+// the kv write-ahead log lives in this directory under DATA_DIR
+const LOG_DIR: &str = "kv-log";
+// how often a snapshot is taken on top of the ones asked for through the Snapshot rpc
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
 mod proto {
     pub(crate) const FILE_DESCRIPTOR_SET: &[u8] =
@@ -28,39 +42,46 @@ mod proto {
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let address = "127.0.0.1:8080".parse().unwrap();
 
-    let (sender, receiver) = channel();
+    // the engine can be picked with the first argument, e.g. `cargo run --bin kv -- hashmap`
+    let engine = match std::env::args().nth(1) {
+        Some(arg) => arg.parse::<Engine>()?,
+        None => Engine::default(),
+    };
+    println!("using the {} storage engine", engine);
 
     let service = tonic_reflection::server::Builder::configure()
         .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
         .build_v1()
         .unwrap();
 
-    let queue = Arc::new(Mutex::new(HashMap::new()));
-    let cloned_queue = queue.clone();
-    let queue_service = SimpleKvStore {
-        hash_map: queue,
-        sender,
-    };
-
-    let handler = spawn(move || {
-        for journaled in receiver {
-            // TODO: We should not be cloning the queue mutex every time we process a message, there should just be one owned queue mutex in this thread
-            let mut grabbed_lock = cloned_queue.lock().unwrap();
-
-            // I don't believe that this is good rust code, but I'm not sure how else to just pass the contents of the lock...
-            let journal_result = process_journal_file(journaled, &mut grabbed_lock);
-            match journal_result {
-                Ok(_) => continue,
-                Err(e) => println!("error processing journal: {}", e),
+    let queue_service = Arc::new(SimpleKvStore::new(
+        engine.create(),
+        PathBuf::from(DATA_DIR),
+    )?);
+
+    let snapshot_service = queue_service.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval_at(
+            tokio::time::Instant::now() + SNAPSHOT_INTERVAL,
+            SNAPSHOT_INTERVAL,
+        );
+        loop {
+            interval.tick().await;
+            match snapshot_service.take_snapshot().await {
+                Ok(sequence) => println!("snapshotted the kv store at sequence {}", sequence),
+                Err(status) => println!("error snapshotting the kv store: {}", status.message()),
             }
         }
     });
 
     Server::builder()
         .add_service(service)
-        .add_service(KvStoreServer::with_interceptor(queue_service, LoadShed {
-            shed: Arc::new(Mutex::new(false)),
-        }))
+        .add_service(InterceptedService::new(
+            KvStoreServer::from_arc(queue_service),
+            LoadShed {
+                shed: Arc::new(Mutex::new(false)),
+            },
+        ))
         .serve(address)
         .await?;
 
@@ -68,8 +89,245 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 struct SimpleKvStore {
-    hash_map: Arc<Mutex<HashMap<String, i32>>>,
-    sender: std::sync::mpsc::Sender<String>,
+    storage: Arc<dyn Storage>,
+    data_dir: PathBuf,
+    // sequence numbers are handed out by the log under this lock, so they reach the apply thread in order. Appending
+    // syncs the log, so it is an async lock and the append itself runs on a blocking thread
+    journal: Arc<tokio::sync::Mutex<Journal>>,
+    // the sequence number of the last mutation the apply thread got through
+    applied: watch::Receiver<u64>,
+    // snapshots are written one at a time, this is held while one is written out on a blocking thread
+    snapshotting: tokio::sync::Mutex<()>,
+}
+
+struct Journal {
+    log: KvLog,
+    sender: std::sync::mpsc::Sender<Applying>,
+}
+
+// what the apply thread works through, in the order it was logged
+enum Applying {
+    Mutation {
+        sequence: u64,
+        mutation: Mutation,
+        // told whether the key was there before, for callers that need to know
+        existed: Option<oneshot::Sender<bool>>,
+    },
+    // copies the storage out between two mutations, so the snapshot is exactly the state as of one sequence number
+    Snapshot(oneshot::Sender<Snapshot>),
+}
+
+impl SimpleKvStore {
+    fn new(storage: Arc<dyn Storage>, data_dir: PathBuf) -> Result<Self, std::io::Error> {
+        let mut log = KvLog::open(data_dir.join(LOG_DIR), DEFAULT_SEGMENT_RECORDS)?;
+
+        // the latest snapshot is the starting point, the log only has to be replayed from after it
+        let mut from_sequence = 1;
+        if let Some(snapshot) = Snapshot::latest(&data_dir)? {
+            if snapshot.sequence > log.last_sequence() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "kv snapshot is at sequence {} but the log ends at {}",
+                        snapshot.sequence,
+                        log.last_sequence()
+                    ),
+                ));
+            }
+
+            println!("loading the kv snapshot at sequence {}", snapshot.sequence);
+            from_sequence = snapshot.sequence + 1;
+            for (key, value) in snapshot.pairs {
+                storage.set(key, value);
+            }
+        } else if log.first_sequence() > 1 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "kv log starts at sequence {} but there is no snapshot before it",
+                    log.first_sequence()
+                ),
+            ));
+        }
+
+        // everything in the log was acknowledged, so all of it is applied before serving anything
+        let recovered = recover(storage.as_ref(), &log, from_sequence)?;
+        if recovered > 0 {
+            println!("recovered {} records from the kv log", recovered);
+        }
+
+        let logged_before = log.last_sequence();
+        let migrated = migrate_journal_files(&data_dir, &mut log)?;
+
+        let (sender, receiver) = channel::<Applying>();
+        let (applied_sender, applied) = watch::channel(logged_before);
+
+        let cloned_storage = storage.clone();
+        spawn(move || {
+            for applying in receiver {
+                match applying {
+                    Applying::Mutation {
+                        sequence,
+                        mutation,
+                        existed,
+                    } => {
+                        let was_there = apply(cloned_storage.as_ref(), mutation);
+                        applied_sender.send_replace(sequence);
+
+                        if let Some(sender) = existed {
+                            let _ = sender.send(was_there);
+                        }
+                    }
+                    Applying::Snapshot(sender) => {
+                        let _ = sender.send(Snapshot {
+                            sequence: *applied_sender.borrow(),
+                            pairs: cloned_storage.scan(
+                                Bound::Unbounded,
+                                Bound::Unbounded,
+                                false,
+                                usize::MAX,
+                            ),
+                        });
+                    }
+                }
+            }
+        });
+
+        for record in migrated {
+            sender
+                .send(Applying::Mutation {
+                    sequence: record.sequence,
+                    mutation: record.mutation,
+                    existed: None,
+                })
+                .expect("apply thread is gone");
+        }
+
+        Ok(SimpleKvStore {
+            storage,
+            data_dir,
+            journal: Arc::new(tokio::sync::Mutex::new(Journal { log, sender })),
+            applied,
+            snapshotting: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    // logs the mutation and hands it to the apply thread, returning its sequence number
+    async fn append(
+        &self,
+        mutation: Mutation,
+        existed: Option<oneshot::Sender<bool>>,
+    ) -> Result<u64, Status> {
+        let mut journal = self.journal.clone().lock_owned().await;
+        // the log syncs every append, which would hold up everything else on this tokio worker
+        tokio::task::spawn_blocking(move || {
+            let record = journal
+                .log
+                .append(mutation)
+                .map_err(|e| Status::internal(format!("error journaling request: {}", e)))?;
+
+            journal
+                .sender
+                .send(Applying::Mutation {
+                    sequence: record.sequence,
+                    mutation: record.mutation,
+                    existed,
+                })
+                .map_err(|_| Status::internal("error queueing journal for processing"))?;
+
+            Ok(record.sequence)
+        })
+        .await
+        .map_err(|e| Status::internal(format!("error journaling request: {}", e)))?
+    }
+
+    // Writes out everything applied so far and drops the log segments the snapshot covers, returning the sequence
+    // number it was taken at.
+    async fn take_snapshot(&self) -> Result<u64, Status> {
+        let (sender, receiver) = oneshot::channel();
+        self.journal
+            .lock()
+            .await
+            .sender
+            .send(Applying::Snapshot(sender))
+            .map_err(|_| Status::internal("error queueing snapshot"))?;
+
+        let snapshot = receiver
+            .await
+            .map_err(|_| Status::unavailable("journal is no longer being applied"))?;
+
+        let _snapshotting = self.snapshotting.lock().await;
+        let data_dir = self.data_dir.clone();
+        let journal = self.journal.clone();
+        tokio::task::spawn_blocking(move || {
+            snapshot
+                .write(&data_dir)
+                .map_err(|e| Status::internal(format!("error writing snapshot: {}", e)))?;
+
+            // nothing before the snapshot is needed for recovery any more
+            journal
+                .blocking_lock()
+                .log
+                .truncate_until(snapshot.sequence)
+                .map_err(|e| Status::internal(format!("error truncating journal: {}", e)))?;
+
+            Ok(snapshot.sequence)
+        })
+        .await
+        .map_err(|e| Status::internal(format!("error snapshotting: {}", e)))?
+    }
+
+    async fn wait_for_applied(&self, sequence: u64) -> Result<(), Status> {
+        if sequence == 0 {
+            return Ok(());
+        }
+
+        // waiting on a sequence that was never handed out would never finish
+        if sequence > self.journal.lock().await.log.last_sequence() {
+            return Err(Status::invalid_argument(format!(
+                "sequence {} has not been handed out",
+                sequence
+            )));
+        }
+
+        self.applied
+            .clone()
+            .wait_for(|applied| *applied >= sequence)
+            .await
+            .map(|_| ())
+            .map_err(|_| Status::unavailable("journal is no longer being applied"))
+    }
+}
+
+// replays the log from `from_sequence` into the storage, returning how many records that was
+fn recover(storage: &dyn Storage, log: &KvLog, from_sequence: u64) -> Result<u64, std::io::Error> {
+    let mut expected = from_sequence;
+    for record in log.replay(from_sequence)? {
+        let record = record?;
+        if record.sequence != expected {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "kv log skips from sequence {} to {}",
+                    expected, record.sequence
+                ),
+            ));
+        }
+
+        apply(storage, record.mutation);
+        expected += 1;
+    }
+
+    Ok(expected - from_sequence)
+}
+
+// returns whether the key was there before
+fn apply(storage: &dyn Storage, mutation: Mutation) -> bool {
+    match mutation {
+        Mutation::Set { key, value } => storage.set(key, value).is_some(),
+        // deleting a key that isn't there still gets logged, replaying it is a no-op
+        Mutation::Delete { key } => storage.delete(&key).is_some(),
+    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -94,60 +352,131 @@ impl Interceptor for LoadShed {
     }
 }
 
-fn journal_request(request: &SetRequest) -> Result<String, std::io::Error> {
-    let id = nanoid!();
+// Before the log every set was written to its own journal file in DATA_DIR, named by a nanoid. Any of those still
+// around are moved into the log in the order they were written.
+fn migrate_journal_files(
+    data_dir: &Path,
+    log: &mut KvLog,
+) -> Result<Vec<LogRecord>, std::io::Error> {
+    let mut journal_files = Vec::new();
+    for entry in fs::read_dir(data_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file()
+            && is_journal_file_name(&entry.file_name().to_string_lossy())
+        {
+            journal_files.push((entry.metadata()?.modified()?, entry.path()));
+        }
+    }
+    journal_files.sort();
+
+    let mut records = Vec::new();
+    for (_, path) in journal_files {
+        let journaled = JournaledSet::decode(&fs::read(&path)?)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        records.push(log.append(Mutation::Set {
+            key: journaled.key,
+            value: journaled.value,
+        })?);
+        fs::remove_file(&path)?;
+    }
 
-    let mut buf = Vec::new();
-    request.serialize(&mut Serializer::new(&mut buf)).unwrap();
+    if !records.is_empty() {
+        println!("moved {} journal files into the kv log", records.len());
+    }
+    Ok(records)
+}
 
-    let mut file = File::create(&("data/".to_string() + &id)).expect("creating file failed");
-    file.write_all(&buf)?;
-    file.flush()?;
+fn is_journal_file_name(name: &str) -> bool {
+    name.len() == 21
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
 
-    Ok(id)
+// what a journal file held, with an int32 value already turned into its bytes
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct JournaledSet {
+    key: Vec<u8>,
+    value: Vec<u8>,
 }
 
-fn process_journal_file(
-    file_name: String,
-    hash_map: &mut HashMap<String, i32>,
-) -> Result<(), std::io::Error> {
-    let mut buf = Vec::new();
-    let mut file = File::open(&("data/".to_string() + &file_name)).expect("opening file failed");
-    file.read_to_end(&mut buf)?;
+// the journal files from before values were bytes were the SetRequest itself
+#[derive(Debug, Deserialize)]
+struct LegacyJournaledSet {
+    key: String,
+    value: i32,
+}
 
-    let byte_slice: &[u8] = &buf;
+impl JournaledSet {
+    fn decode(buf: &[u8]) -> Result<Self, rmp_serde::decode::Error> {
+        match JournaledSet::deserialize(&mut Deserializer::new(buf)) {
+            Ok(journaled) => Ok(journaled),
+            Err(_) => {
+                let legacy = LegacyJournaledSet::deserialize(&mut Deserializer::new(buf))?;
+                Ok(JournaledSet {
+                    key: legacy.key.into_bytes(),
+                    value: int32_to_value(legacy.value),
+                })
+            }
+        }
+    }
+}
 
-    let mut de = Deserializer::new(byte_slice);
-    let request_body: SetRequest = Deserialize::deserialize(&mut de).unwrap();
+fn set_value(request: &SetRequest) -> Vec<u8> {
+    match &request.value {
+        Some(Value::BytesValue(bytes)) => bytes.clone(),
+        Some(Value::Int32Value(int)) => int32_to_value(*int),
+        // an unset oneof is what an older caller setting 0 sends, but also what an empty bytes value looks like
+        None => Vec::new(),
+    }
+}
 
-    let key_name = request_body.key.clone();
-    hash_map.insert(request_body.key, request_body.value);
+fn int32_to_value(int: i32) -> Vec<u8> {
+    int.to_le_bytes().to_vec()
+}
 
-    fs::remove_file(&("data/".to_string() + &file_name))?;
-    println!("key was: {}, value was: {}", key_name, request_body.value);
-    Ok(())
+// the other way around for the older callers still reading ints, an empty value reads as the proto default of 0
+fn value_to_int32(value: &[u8]) -> Option<i32> {
+    match value.len() {
+        0 => Some(0),
+        4 => Some(i32::from_le_bytes(value.try_into().unwrap())),
+        _ => None,
+    }
 }
 
 #[async_trait]
 impl KvStore for SimpleKvStore {
     async fn set(&self, request: Request<SetRequest>) -> Result<Response<SetResponse>, Status> {
-        let journal_id = journal_request(request.get_ref())
-            .map_err(|_| Status::internal("error journaling request"))?;
-
-        self.sender
-            .send(journal_id)
-            .map_err(|e| Status::internal("error queueing journal for processing"))?;
+        let sequence = self
+            .append(
+                Mutation::Set {
+                    key: request.get_ref().key.clone(),
+                    value: set_value(request.get_ref()),
+                },
+                None,
+            )
+            .await?;
+
+        if request.get_ref().wait_for_apply {
+            self.wait_for_applied(sequence).await?;
+        }
 
-        Ok(Response::new(SetResponse { confirmation: true }))
+        Ok(Response::new(SetResponse {
+            confirmation: true,
+            sequence,
+        }))
     }
 
     async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
-        let q = self.hash_map.lock().unwrap();
+        self.wait_for_applied(request.get_ref().min_sequence)
+            .await?;
 
-        let val = q.get(&request.get_ref().key);
+        let val = self.storage.get(&request.get_ref().key);
 
         Ok(Response::new(GetResponse {
-            value: val.copied(),
+            int32_value: val.as_deref().and_then(value_to_int32),
+            value: val,
         }))
     }
 
@@ -155,12 +484,329 @@ impl KvStore for SimpleKvStore {
         &self,
         request: Request<DeleteRequest>,
     ) -> Result<Response<DeleteResponse>, Status> {
-        let mut q = self.hash_map.lock().unwrap();
-
-        let val = q.remove(&request.get_ref().key);
+        // a delete is a tombstone in the log that gets applied in order with the sets around it. The response says
+        // whether the key was there, so unlike a set it always waits for the apply thread
+        let (existed_sender, existed) = oneshot::channel();
+        let sequence = self
+            .append(
+                Mutation::Delete {
+                    key: request.get_ref().key.clone(),
+                },
+                Some(existed_sender),
+            )
+            .await?;
+
+        let existed = existed
+            .await
+            .map_err(|_| Status::unavailable("journal is no longer being applied"))?;
 
         Ok(Response::new(DeleteResponse {
-            confirmation: val.is_some(),
+            confirmation: existed,
+            sequence,
         }))
     }
+
+    type ScanStream = tonic::codegen::BoxStream<ScanResponse>;
+
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> Result<Response<Self::ScanStream>, Status> {
+        let request = request.get_ref();
+        self.wait_for_applied(request.min_sequence).await?;
+
+        // a scan reads one page out of the storage up front and streams that, the cursor of the last pair picks up the
+        // next page
+        let pairs = match scan::scan_bounds(request) {
+            Some((start, end)) => self.storage.scan(
+                start.as_ref().map(Vec::as_slice),
+                end.as_ref().map(Vec::as_slice),
+                request.reverse,
+                scan::scan_limit(request),
+            ),
+            None => Vec::new(),
+        };
+
+        let responses = pairs.into_iter().map(|(key, value)| {
+            Ok(ScanResponse {
+                cursor: key.clone(),
+                key,
+                value,
+            })
+        });
+
+        Ok(Response::new(Box::pin(tokio_stream::iter(responses))))
+    }
+
+    async fn snapshot(
+        &self,
+        _request: Request<SnapshotRequest>,
+    ) -> Result<Response<SnapshotResponse>, Status> {
+        let sequence = self.take_snapshot().await?;
+
+        Ok(Response::new(SnapshotResponse { sequence }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nanoid::nanoid;
+    use rmp_serde::Serializer;
+    use tempfile::tempdir;
+
+    fn set_request(key: &[u8], value: &[u8], wait_for_apply: bool) -> Request<SetRequest> {
+        Request::new(SetRequest {
+            key: key.to_vec(),
+            value: Some(Value::BytesValue(value.to_vec())),
+            wait_for_apply,
+        })
+    }
+
+    fn get_request(key: &[u8], min_sequence: u64) -> Request<GetRequest> {
+        Request::new(GetRequest {
+            key: key.to_vec(),
+            min_sequence,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_set_waiting_for_apply() {
+        let dir = tempdir().unwrap();
+        let store = SimpleKvStore::new(Engine::BTree.create(), dir.path().to_path_buf()).unwrap();
+
+        for i in 0..20u8 {
+            let response = store.set(set_request(b"key", &[i], true)).await.unwrap();
+            assert_eq!(response.get_ref().sequence, i as u64 + 1);
+
+            let response = store.get(get_request(b"key", 0)).await.unwrap();
+            assert_eq!(response.get_ref().value, Some(vec![i]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_waiting_on_sequence() {
+        let dir = tempdir().unwrap();
+        let store = SimpleKvStore::new(Engine::HashMap.create(), dir.path().to_path_buf()).unwrap();
+
+        for i in 0..20u8 {
+            let response = store.set(set_request(&[i], b"value", false)).await.unwrap();
+            let sequence = response.get_ref().sequence;
+
+            let response = store.get(get_request(&[i], sequence)).await.unwrap();
+            assert_eq!(response.get_ref().value, Some(b"value".to_vec()));
+        }
+
+        let status = store.get(get_request(b"key", 100)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_sets_and_deletes_are_logged() {
+        let dir = tempdir().unwrap();
+        let store = SimpleKvStore::new(Engine::BTree.create(), dir.path().to_path_buf()).unwrap();
+
+        store.set(set_request(b"a", b"1", true)).await.unwrap();
+        store
+            .delete(Request::new(DeleteRequest { key: b"a".to_vec() }))
+            .await
+            .unwrap();
+        let response = store.set(set_request(b"b", b"2", false)).await.unwrap();
+
+        assert_eq!(response.get_ref().sequence, 3);
+        drop(store);
+
+        let log = KvLog::open(dir.path().join(LOG_DIR), DEFAULT_SEGMENT_RECORDS).unwrap();
+        assert_eq!(log.last_sequence(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_delete_is_ordered_after_sets() {
+        let dir = tempdir().unwrap();
+        let store = SimpleKvStore::new(Engine::BTree.create(), dir.path().to_path_buf()).unwrap();
+
+        for i in 0..50u8 {
+            // none of these wait, so the delete right after has to queue up behind them
+            store.set(set_request(b"key", &[i], false)).await.unwrap();
+            let response = store
+                .delete(Request::new(DeleteRequest {
+                    key: b"key".to_vec(),
+                }))
+                .await
+                .unwrap();
+            assert!(response.get_ref().confirmation);
+
+            let response = store
+                .get(get_request(b"key", response.get_ref().sequence))
+                .await
+                .unwrap();
+            assert_eq!(response.get_ref().value, None);
+        }
+
+        let response = store
+            .delete(Request::new(DeleteRequest {
+                key: b"key".to_vec(),
+            }))
+            .await
+            .unwrap();
+        assert!(!response.get_ref().confirmation);
+    }
+
+    #[tokio::test]
+    async fn test_restart_recovers_acknowledged_state() {
+        let dir = tempdir().unwrap();
+
+        for engine in [Engine::HashMap, Engine::BTree] {
+            {
+                let store = SimpleKvStore::new(engine.create(), dir.path().to_path_buf()).unwrap();
+                for i in 0..50u8 {
+                    store.set(set_request(&[i], &[i], false)).await.unwrap();
+                }
+                store.set(set_request(&[0], b"new", false)).await.unwrap();
+                store
+                    .delete(Request::new(DeleteRequest { key: vec![1] }))
+                    .await
+                    .unwrap();
+            }
+
+            // the same directory again, now with whatever engine is up next
+            let store = SimpleKvStore::new(engine.create(), dir.path().to_path_buf()).unwrap();
+
+            let response = store.get(get_request(&[0], 0)).await.unwrap();
+            assert_eq!(response.get_ref().value, Some(b"new".to_vec()));
+            let response = store.get(get_request(&[1], 0)).await.unwrap();
+            assert_eq!(response.get_ref().value, None);
+            for i in 2..50u8 {
+                let response = store.get(get_request(&[i], 0)).await.unwrap();
+                assert_eq!(response.get_ref().value, Some(vec![i]));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restart_from_snapshot() {
+        let dir = tempdir().unwrap();
+
+        {
+            let store =
+                SimpleKvStore::new(Engine::BTree.create(), dir.path().to_path_buf()).unwrap();
+            for i in 0..50u8 {
+                store.set(set_request(&[i], &[i], false)).await.unwrap();
+            }
+            store
+                .delete(Request::new(DeleteRequest { key: vec![1] }))
+                .await
+                .unwrap();
+
+            // the snapshot is taken after every mutation that was logged before it
+            let response = store
+                .snapshot(Request::new(SnapshotRequest {}))
+                .await
+                .unwrap();
+            assert_eq!(response.get_ref().sequence, 51);
+
+            // these are only in the log
+            store.set(set_request(&[0], b"new", false)).await.unwrap();
+            store
+                .delete(Request::new(DeleteRequest { key: vec![2] }))
+                .await
+                .unwrap();
+        }
+
+        let snapshot = Snapshot::latest(dir.path()).unwrap().unwrap();
+        assert_eq!(snapshot.sequence, 51);
+        assert_eq!(snapshot.pairs.len(), 49);
+
+        let store = SimpleKvStore::new(Engine::HashMap.create(), dir.path().to_path_buf()).unwrap();
+        let response = store.get(get_request(&[0], 0)).await.unwrap();
+        assert_eq!(response.get_ref().value, Some(b"new".to_vec()));
+        for i in 1..3u8 {
+            let response = store.get(get_request(&[i], 0)).await.unwrap();
+            assert_eq!(response.get_ref().value, None);
+        }
+        for i in 3..50u8 {
+            let response = store.get(get_request(&[i], 0)).await.unwrap();
+            assert_eq!(response.get_ref().value, Some(vec![i]));
+        }
+
+        let response = store.set(set_request(b"next", b"", false)).await.unwrap();
+        assert_eq!(response.get_ref().sequence, 54);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_log_without_snapshot() {
+        let dir = tempdir().unwrap();
+
+        {
+            let mut log = KvLog::open(dir.path().join(LOG_DIR), 2).unwrap();
+            for i in 0..5u8 {
+                log.append(Mutation::Delete { key: vec![i] }).unwrap();
+            }
+            log.truncate_until(4).unwrap();
+        }
+
+        let err = SimpleKvStore::new(Engine::BTree.create(), dir.path().to_path_buf())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_journal_files_are_moved_into_the_log() {
+        let dir = tempdir().unwrap();
+
+        let mut buf = Vec::new();
+        JournaledSet {
+            key: b"doc".to_vec(),
+            value: b"{\"a\": 1}".to_vec(),
+        }
+        .serialize(&mut Serializer::new(&mut buf))
+        .unwrap();
+        fs::write(dir.path().join(nanoid!()), &buf).unwrap();
+
+        let store = SimpleKvStore::new(Engine::BTree.create(), dir.path().to_path_buf()).unwrap();
+
+        let response = store.get(get_request(b"doc", 1)).await.unwrap();
+        assert_eq!(response.get_ref().value, Some(b"{\"a\": 1}".to_vec()));
+
+        // only the log directory is left behind
+        let files: Vec<_> = fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(files.len(), 1);
+    }
+
+    #[test]
+    fn test_legacy_journal_file() {
+        #[derive(Serialize)]
+        struct OldSetRequest {
+            key: String,
+            value: i32,
+        }
+
+        let mut buf = Vec::new();
+        OldSetRequest {
+            key: "Something".to_string(),
+            value: 1000,
+        }
+        .serialize(&mut Serializer::new(&mut buf))
+        .unwrap();
+
+        let journaled = JournaledSet::decode(&buf).unwrap();
+        assert_eq!(journaled.key, b"Something");
+        assert_eq!(value_to_int32(&journaled.value), Some(1000));
+    }
+
+    #[test]
+    fn test_int32_values() {
+        let request = SetRequest {
+            key: b"counter".to_vec(),
+            value: Some(Value::Int32Value(-7)),
+            wait_for_apply: false,
+        };
+        let value = set_value(&request);
+
+        assert_eq!(value, (-7i32).to_le_bytes());
+        assert_eq!(value_to_int32(&value), Some(-7));
+        assert_eq!(value_to_int32(&[]), Some(0));
+        assert_eq!(value_to_int32(b"not an int"), None);
+    }
 }
@@ -0,0 +1,220 @@
+use std::ops::Bound;
+use zeyrho::zeyrho::kv_store::ScanRequest;
+
+// the most pairs a single scan streams back, clients page through anything bigger with the cursor
+pub const MAX_SCAN_LIMIT: usize = 1000;
+
+pub type KeyBounds = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+// The key range a scan request covers once the prefix and cursor are folded into its start and end. None when nothing
+// can match, e.g. a prefix outside of start and end.
+pub fn scan_bounds(request: &ScanRequest) -> Option<KeyBounds> {
+    let mut start = Bound::Included(request.start.clone());
+    let mut end = match &request.end {
+        Some(end) => Bound::Excluded(end.clone()),
+        None => Bound::Unbounded,
+    };
+
+    if let Some(prefix) = &request.prefix {
+        start = tighter_start(start, Bound::Included(prefix.clone()));
+        if let Some(prefix_end) = prefix_end(prefix) {
+            end = tighter_end(end, Bound::Excluded(prefix_end));
+        }
+    }
+
+    // the cursor is the last key handed out, which is at the start of what is left or the end when going backwards
+    if let Some(cursor) = &request.cursor {
+        if request.reverse {
+            end = tighter_end(end, Bound::Excluded(cursor.clone()));
+        } else {
+            start = tighter_start(start, Bound::Excluded(cursor.clone()));
+        }
+    }
+
+    if is_empty(&start, &end) {
+        None
+    } else {
+        Some((start, end))
+    }
+}
+
+pub fn scan_limit(request: &ScanRequest) -> usize {
+    match request.limit as usize {
+        0 => MAX_SCAN_LIMIT,
+        limit => limit.min(MAX_SCAN_LIMIT),
+    }
+}
+
+// the first key that comes after every key starting with `prefix`, None when there is none
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+
+    None
+}
+
+fn tighter_start(a: Bound<Vec<u8>>, b: Bound<Vec<u8>>) -> Bound<Vec<u8>> {
+    match (&a, &b) {
+        (Bound::Unbounded, _) => b,
+        (_, Bound::Unbounded) => a,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+            if x > y || (x == y && matches!(a, Bound::Excluded(_))) {
+                a
+            } else {
+                b
+            }
+        }
+    }
+}
+
+fn tighter_end(a: Bound<Vec<u8>>, b: Bound<Vec<u8>>) -> Bound<Vec<u8>> {
+    match (&a, &b) {
+        (Bound::Unbounded, _) => b,
+        (_, Bound::Unbounded) => a,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+            if x < y || (x == y && matches!(a, Bound::Excluded(_))) {
+                a
+            } else {
+                b
+            }
+        }
+    }
+}
+
+fn is_empty(start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (
+            Bound::Included(start) | Bound::Excluded(start),
+            Bound::Included(end) | Bound::Excluded(end),
+        ) => start >= end,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> ScanRequest {
+        ScanRequest {
+            start: Vec::new(),
+            end: None,
+            prefix: None,
+            limit: 0,
+            reverse: false,
+            cursor: None,
+            min_sequence: 0,
+        }
+    }
+
+    #[test]
+    fn test_start_and_end() {
+        assert_eq!(
+            scan_bounds(&request()),
+            Some((Bound::Included(Vec::new()), Bound::Unbounded))
+        );
+
+        let request = ScanRequest {
+            start: b"b".to_vec(),
+            end: Some(b"d".to_vec()),
+            ..request()
+        };
+        assert_eq!(
+            scan_bounds(&request),
+            Some((
+                Bound::Included(b"b".to_vec()),
+                Bound::Excluded(b"d".to_vec())
+            ))
+        );
+    }
+
+    #[test]
+    fn test_prefix() {
+        let request = ScanRequest {
+            prefix: Some(b"user:".to_vec()),
+            ..request()
+        };
+        assert_eq!(
+            scan_bounds(&request),
+            Some((
+                Bound::Included(b"user:".to_vec()),
+                Bound::Excluded(b"user;".to_vec())
+            ))
+        );
+
+        assert_eq!(prefix_end(b"a\xff\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_end(b"\xff"), None);
+        assert_eq!(prefix_end(b""), None);
+    }
+
+    #[test]
+    fn test_prefix_outside_of_range() {
+        let request = ScanRequest {
+            start: b"m".to_vec(),
+            prefix: Some(b"a".to_vec()),
+            ..request()
+        };
+        assert_eq!(scan_bounds(&request), None);
+    }
+
+    #[test]
+    fn test_cursor() {
+        let forward = ScanRequest {
+            start: b"a".to_vec(),
+            end: Some(b"z".to_vec()),
+            cursor: Some(b"k".to_vec()),
+            ..request()
+        };
+        assert_eq!(
+            scan_bounds(&forward),
+            Some((
+                Bound::Excluded(b"k".to_vec()),
+                Bound::Excluded(b"z".to_vec())
+            ))
+        );
+
+        let reverse = ScanRequest {
+            reverse: true,
+            ..forward
+        };
+        assert_eq!(
+            scan_bounds(&reverse),
+            Some((
+                Bound::Included(b"a".to_vec()),
+                Bound::Excluded(b"k".to_vec())
+            ))
+        );
+
+        let exhausted = ScanRequest {
+            cursor: Some(b"a".to_vec()),
+            ..reverse
+        };
+        assert_eq!(scan_bounds(&exhausted), None);
+    }
+
+    #[test]
+    fn test_limit() {
+        assert_eq!(scan_limit(&request()), MAX_SCAN_LIMIT);
+        assert_eq!(
+            scan_limit(&ScanRequest {
+                limit: 5,
+                ..request()
+            }),
+            5
+        );
+        assert_eq!(
+            scan_limit(&ScanRequest {
+                limit: 5000,
+                ..request()
+            }),
+            MAX_SCAN_LIMIT
+        );
+    }
+}
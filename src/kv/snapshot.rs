@@ -0,0 +1,189 @@
+use rmp_serde::{Deserializer, Serializer};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+// A snapshot file is the magic, the format version, a CRC32C of everything after it, the sequence number of the last
+// mutation the snapshot includes, and then the msgpack encoded pairs in key order. Integers are little endian.
+const MAGIC: &[u8; 4] = b"ZKVS";
+const VERSION: u32 = 1;
+const HEADER_LEN: usize = MAGIC.len() + size_of::<u32>() + size_of::<u32>();
+
+const FILE_PREFIX: &str = "snapshot-";
+const TEMP_EXTENSION: &str = "tmp";
+
+// everything in the kv store as of the mutation with `sequence`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub sequence: u64,
+    pub pairs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Snapshot {
+    // Writes the snapshot into `data_dir` and removes any older ones. It is written to a temporary file first and
+    // renamed into place, so a crash part way through leaves the previous snapshot as the latest.
+    pub fn write(&self, data_dir: &Path) -> Result<PathBuf, Error> {
+        let path = snapshot_path(data_dir, self.sequence);
+        let temp_path = path.with_extension(TEMP_EXTENSION);
+
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&self.encode()?)?;
+        file.sync_all()?;
+        drop(file);
+
+        fs::rename(&temp_path, &path)?;
+        // the rename itself only survives a crash once the directory is synced
+        File::open(data_dir)?.sync_all()?;
+
+        // two snapshots can finish out of order, whichever is newest is kept
+        let mut sequences = snapshot_sequences(data_dir)?;
+        sequences.pop();
+        for sequence in sequences {
+            fs::remove_file(snapshot_path(data_dir, sequence))?;
+        }
+
+        Ok(path)
+    }
+
+    // the newest snapshot in `data_dir`, None when there isn't one yet
+    pub fn latest(data_dir: &Path) -> Result<Option<Self>, Error> {
+        match snapshot_sequences(data_dir)?.last() {
+            Some(sequence) => Ok(Some(Snapshot::decode(&fs::read(snapshot_path(
+                data_dir, *sequence,
+            ))?)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut body = self.sequence.to_le_bytes().to_vec();
+        self.pairs
+            .serialize(&mut Serializer::new(&mut body))
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        let mut buf = Vec::with_capacity(HEADER_LEN + body.len());
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.extend_from_slice(&crc32c::crc32c(&body).to_le_bytes());
+        buf.extend_from_slice(&body);
+        Ok(buf)
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < HEADER_LEN + size_of::<u64>() || &buf[..MAGIC.len()] != MAGIC {
+            return Err(invalid_data("not a kv snapshot"));
+        }
+
+        let version = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+        if version != VERSION {
+            return Err(invalid_data(&format!(
+                "kv snapshot is version {}, only version {} is understood",
+                version, VERSION
+            )));
+        }
+
+        let checksum = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+        let body = &buf[HEADER_LEN..];
+        if crc32c::crc32c(body) != checksum {
+            return Err(invalid_data("kv snapshot checksum doesn't match"));
+        }
+
+        let sequence = u64::from_le_bytes(body[..8].try_into().unwrap());
+        let pairs = Vec::deserialize(&mut Deserializer::new(&body[8..]))
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        Ok(Snapshot { sequence, pairs })
+    }
+}
+
+fn snapshot_path(data_dir: &Path, sequence: u64) -> PathBuf {
+    data_dir.join(format!("{}{:020}", FILE_PREFIX, sequence))
+}
+
+// the sequence numbers of the snapshots in `data_dir`, oldest first
+fn snapshot_sequences(data_dir: &Path) -> Result<Vec<u64>, Error> {
+    let mut sequences = Vec::new();
+    for entry in fs::read_dir(data_dir)? {
+        let name = entry?.file_name();
+        // temporary files left behind by a crash have an extension, so they don't parse
+        if let Some(sequence) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(FILE_PREFIX))
+            .and_then(|sequence| sequence.parse::<u64>().ok())
+        {
+            sequences.push(sequence);
+        }
+    }
+
+    sequences.sort_unstable();
+    Ok(sequences)
+}
+
+fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn snapshot(sequence: u64) -> Snapshot {
+        Snapshot {
+            sequence,
+            pairs: (0..10u8).map(|i| (vec![i], vec![i; i as usize])).collect(),
+        }
+    }
+
+    #[test]
+    fn test_write_and_read_latest() {
+        let dir = tempdir().unwrap();
+        assert_eq!(Snapshot::latest(dir.path()).unwrap(), None);
+
+        snapshot(5).write(dir.path()).unwrap();
+        snapshot(12).write(dir.path()).unwrap();
+        assert_eq!(Snapshot::latest(dir.path()).unwrap(), Some(snapshot(12)));
+
+        // an older snapshot finishing late doesn't replace the newer one
+        snapshot(9).write(dir.path()).unwrap();
+        assert_eq!(Snapshot::latest(dir.path()).unwrap(), Some(snapshot(12)));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_leftover_temporary_file() {
+        let dir = tempdir().unwrap();
+        snapshot(5).write(dir.path()).unwrap();
+        fs::write(
+            snapshot_path(dir.path(), 8).with_extension(TEMP_EXTENSION),
+            b"half written",
+        )
+        .unwrap();
+
+        assert_eq!(Snapshot::latest(dir.path()).unwrap(), Some(snapshot(5)));
+    }
+
+    #[test]
+    fn test_corruption_is_detected() {
+        let mut buf = snapshot(5).encode().unwrap();
+        assert_eq!(Snapshot::decode(&buf).unwrap(), snapshot(5));
+
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        let err = Snapshot::decode(&buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        assert!(Snapshot::decode(b"ZKV").is_err());
+        assert!(Snapshot::decode(&[0; 64]).is_err());
+    }
+
+    #[test]
+    fn test_unknown_version() {
+        let mut buf = snapshot(5).encode().unwrap();
+        buf[4..8].copy_from_slice(&2u32.to_le_bytes());
+
+        let err = Snapshot::decode(&buf).unwrap_err();
+        assert!(err.to_string().contains("version 2"));
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::ops::{Bound, RangeBounds};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use zeyrho::zeyrho::btree::concurrent::ConcurrentBPlusTree;
+
+// What the kv service keeps its data in. Requests come in on many tokio tasks at once so the engines have to handle
+// their own locking, which is why everything takes `&self`.
+pub trait Storage: Send + Sync {
+    // returns the value that was there before, if any
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Option<Vec<u8>>;
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+
+    fn delete(&self, key: &[u8]) -> Option<Vec<u8>>;
+
+    // up to `limit` pairs within the bounds in key order, or in reverse key order. The bounds must not cross
+    fn scan(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        reverse: bool,
+        limit: usize,
+    ) -> Vec<(Vec<u8>, Vec<u8>)>;
+}
+
+impl Storage for Mutex<HashMap<Vec<u8>, Vec<u8>>> {
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Option<Vec<u8>> {
+        self.lock().unwrap().insert(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.lock().unwrap().get(key).cloned()
+    }
+
+    fn delete(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.lock().unwrap().remove(key)
+    }
+
+    // there is no order to a hash map, so this has to look at every key and sort the ones in range
+    fn scan(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        reverse: bool,
+        limit: usize,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = self
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(k, _)| RangeBounds::<[u8]>::contains(&(start, end), k.as_slice()))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+
+        pairs.sort_unstable_by(|(a, _), (b, _)| if reverse { b.cmp(a) } else { a.cmp(b) });
+        pairs.truncate(limit);
+        pairs
+    }
+}
+
+// the plain `BPlusTree` is built on Rc so it can't be shared between tasks, its concurrent variant can
+impl Storage for ConcurrentBPlusTree<Vec<u8>, Vec<u8>> {
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Option<Vec<u8>> {
+        self.insert(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        ConcurrentBPlusTree::get(self, key).map(|v| v.clone())
+    }
+
+    fn delete(&self, key: &[u8]) -> Option<Vec<u8>> {
+        ConcurrentBPlusTree::delete(self, key)
+    }
+
+    fn scan(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        reverse: bool,
+        limit: usize,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let range = self.range::<[u8], _>((start, end));
+        let pairs = range.map(|(k, v)| (k.as_ref().clone(), v));
+
+        if reverse {
+            pairs.rev().take(limit).collect()
+        } else {
+            pairs.take(limit).collect()
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    HashMap,
+    #[default]
+    BTree,
+}
+
+impl Engine {
+    pub fn create(self) -> Arc<dyn Storage> {
+        match self {
+            Engine::HashMap => Arc::new(Mutex::new(HashMap::new())),
+            Engine::BTree => Arc::new(ConcurrentBPlusTree::new()),
+        }
+    }
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hashmap" => Ok(Engine::HashMap),
+            "btree" => Ok(Engine::BTree),
+            other => Err(format!(
+                "unknown storage engine '{}', expected 'hashmap' or 'btree'",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for Engine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Engine::HashMap => f.write_str("hashmap"),
+            Engine::BTree => f.write_str("btree"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_engines_behave_the_same() {
+        for engine in [Engine::HashMap, Engine::BTree] {
+            let storage = engine.create();
+
+            assert_eq!(
+                storage.set(b"a".to_vec(), b"1".to_vec()),
+                None,
+                "{}",
+                engine
+            );
+            assert_eq!(
+                storage.set(b"b".to_vec(), b"2".to_vec()),
+                None,
+                "{}",
+                engine
+            );
+            assert_eq!(
+                storage.set(b"a".to_vec(), b"3".to_vec()),
+                Some(b"1".to_vec()),
+                "{}",
+                engine
+            );
+
+            assert_eq!(storage.get(b"a"), Some(b"3".to_vec()), "{}", engine);
+            assert_eq!(storage.get(b"c"), None, "{}", engine);
+
+            assert_eq!(storage.delete(b"b"), Some(b"2".to_vec()), "{}", engine);
+            assert_eq!(storage.delete(b"b"), None, "{}", engine);
+            assert_eq!(storage.get(b"b"), None, "{}", engine);
+        }
+    }
+
+    #[test]
+    fn test_engines_scan_the_same() {
+        for engine in [Engine::HashMap, Engine::BTree] {
+            let storage = engine.create();
+            for (i, key) in ["e", "a", "d", "b", "c", "f"].into_iter().enumerate() {
+                storage.set(key.as_bytes().to_vec(), vec![i as u8]);
+            }
+
+            let keys = |pairs: Vec<(Vec<u8>, Vec<u8>)>| {
+                pairs
+                    .into_iter()
+                    .map(|(k, _)| String::from_utf8(k).unwrap())
+                    .collect::<Vec<_>>()
+            };
+
+            let all = storage.scan(Bound::Unbounded, Bound::Unbounded, false, usize::MAX);
+            assert_eq!(keys(all), ["a", "b", "c", "d", "e", "f"], "{}", engine);
+
+            let some = storage.scan(
+                Bound::Included(b"b".as_slice()),
+                Bound::Excluded(b"e".as_slice()),
+                false,
+                usize::MAX,
+            );
+            assert_eq!(keys(some), ["b", "c", "d"], "{}", engine);
+
+            let reversed = storage.scan(
+                Bound::Excluded(b"b".as_slice()),
+                Bound::Included(b"e".as_slice()),
+                true,
+                2,
+            );
+            assert_eq!(keys(reversed), ["e", "d"], "{}", engine);
+
+            let first = storage.scan(Bound::Unbounded, Bound::Unbounded, false, 1);
+            assert_eq!(first, vec![(b"a".to_vec(), vec![1])], "{}", engine);
+        }
+    }
+
+    #[test]
+    fn test_parse_engine() {
+        assert_eq!("hashmap".parse(), Ok(Engine::HashMap));
+        assert_eq!("btree".parse(), Ok(Engine::BTree));
+        assert!("lsm".parse::<Engine>().is_err());
+    }
+}
@@ -1,3 +1,5 @@
+// only the tree is used here, the rest of the module is for the other binaries
+#[allow(dead_code)]
 mod zeyrho;
 
 use crate::zeyrho::btree::tree::BPlusTree;
@@ -1,6 +1,8 @@
 use zeyrho::zeyrho::queue::EnqueueRequest;
 use zeyrho::zeyrho::queue::queue_client::QueueClient;
 
+// not called from the server, it's here to poke at a running one
+#[allow(dead_code)]
 pub async fn execute_queries() -> Result<Vec<String>, tonic::transport::Error> {
     let mut client = QueueClient::connect("http://localhost:8080").await?;
 
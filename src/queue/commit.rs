@@ -0,0 +1,233 @@
+use std::fmt::{Debug, Formatter};
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{Notify, oneshot};
+use tonic::Status;
+use tracing::error;
+use zeyrho::queue::wal::segmented::SegmentedWal;
+use zeyrho::queue::wal::wal::{Durability, Wal};
+
+// Lets concurrent enqueues share one sync of the WAL. Each write waits until the WAL's committed size has gone past
+// it. With group commit a background task commits everything that built up once the window after the first of them is
+// over, with the other durabilities a write is committed by the time it returns. Since committing syncs the file the
+// WAL is only ever written to on tokio's blocking threads.
+//
+// A commit that fails drops everything written since the last one from the WAL, the writes waiting on it fail and
+// `on_roll_back` is told so whatever they did in `on_write` can be undone.
+pub struct GroupCommit {
+    wal: Arc<Mutex<SegmentedWal>>,
+    // woken by a write that is waiting for the background task
+    pending: Notify,
+    // the WAL's committed size after the last commit that worked
+    committed_size: AtomicUsize,
+    // writes that aren't committed yet by their index, only touched with the WAL locked
+    waiting: Mutex<Vec<Waiter>>,
+    on_roll_back: Box<dyn Fn(usize) + Send + Sync>,
+}
+
+// how the commit a write was waiting on went
+type Committed = Result<(), ErrorKind>;
+
+// the index of a write and where to tell it how its commit went
+type Waiter = (usize, oneshot::Sender<Committed>);
+
+impl Debug for GroupCommit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GroupCommit")
+            .field("wal", &self.wal)
+            .field("committed_size", &self.committed_size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl GroupCommit {
+    // Has to be called from inside the tokio runtime, group commit starts its background task here. `on_roll_back` is
+    // called with the WAL's new size, while it is still locked, whenever a failed commit drops entries from it.
+    pub fn new(
+        wal: SegmentedWal,
+        on_roll_back: impl Fn(usize) + Send + Sync + 'static,
+    ) -> Arc<Self> {
+        let durability = wal.durability();
+        let group_commit = Arc::new(GroupCommit {
+            committed_size: AtomicUsize::new(wal.committed_size()),
+            wal: Arc::new(Mutex::new(wal)),
+            pending: Notify::new(),
+            waiting: Mutex::new(Vec::new()),
+            on_roll_back: Box::new(on_roll_back),
+        });
+
+        if let Durability::GroupCommit { window, .. } = durability {
+            tokio::spawn(group_commit.clone().run(window));
+        }
+
+        group_commit
+    }
+
+    // The queue can be locked while the WAL is, like `on_write` in write does, never the other way round. Anything
+    // that needs both locks this first.
+    pub fn wal(&self) -> &Mutex<SegmentedWal> {
+        &self.wal
+    }
+
+    // the index one past the last entry that has been committed
+    pub fn committed_size(&self) -> usize {
+        self.committed_size.load(Ordering::Acquire)
+    }
+
+    // Writes `record` to the WAL and waits for it to be committed, returning its index. `on_write` is called with the
+    // index while the WAL is still locked, so whatever it does happens in the same order as the writes.
+    pub async fn write(
+        self: &Arc<Self>,
+        record: Vec<u8>,
+        on_write: impl FnOnce(usize) + Send + 'static,
+    ) -> Result<usize, Status> {
+        // writing can sync the WAL, which would hold up everything else on this tokio worker
+        let group_commit = self.clone();
+        let (index, receiver) =
+            tokio::task::spawn_blocking(move || group_commit.write_blocking(&record, on_write))
+                .await
+                .map_err(|e| Status::internal(format!("error writing to WAL: {}", e)))??;
+
+        // the write committed itself, along with anything before it that was waiting
+        let Some(receiver) = receiver else {
+            return Ok(index);
+        };
+
+        self.pending.notify_one();
+        match receiver.await {
+            Ok(Ok(())) => Ok(index),
+            Ok(Err(kind)) => Err(Status::internal(format!("error committing WAL: {}", kind))),
+            Err(_) => Err(Status::unavailable("WAL is no longer being committed")),
+        }
+    }
+
+    // the index of the write, and what to wait on unless it was committed straight away
+    fn write_blocking(
+        &self,
+        record: &[u8],
+        on_write: impl FnOnce(usize),
+    ) -> Result<(usize, Option<oneshot::Receiver<Committed>>), Error> {
+        let mut wal = self.wal.lock().unwrap();
+        let written = wal.write(record);
+        self.settle(&wal, written)?;
+
+        let index = wal.size() - 1;
+        on_write(index);
+        if wal.committed_size() > index {
+            return Ok((index, None));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        self.waiting.lock().unwrap().push((index, sender));
+        Ok((index, Some(receiver)))
+    }
+
+    async fn run(self: Arc<Self>, window: Duration) {
+        loop {
+            self.pending.notified().await;
+            tokio::time::sleep(window).await;
+
+            let group_commit = self.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || group_commit.commit()).await {
+                error!("error running WAL commit: {}", e);
+            }
+        }
+    }
+
+    fn commit(&self) {
+        let mut wal = self.wal.lock().unwrap();
+        let committed = wal.commit();
+        if let Err(e) = self.settle(&wal, committed) {
+            error!("error committing WAL: {}", e);
+        }
+    }
+
+    // Lets the writes waiting on the WAL know how they went after it was written to or committed, whether or not that
+    // worked. The WAL has to be locked for as long as this runs.
+    fn settle(&self, wal: &SegmentedWal, result: Result<(), Error>) -> Result<(), Error> {
+        let committed_size = wal.committed_size();
+        // anything from here on didn't make it into the WAL
+        let size = wal.size();
+        if result.is_err() {
+            (self.on_roll_back)(size);
+        }
+        self.committed_size.store(committed_size, Ordering::Release);
+
+        let mut waiting = self.waiting.lock().unwrap();
+        for (index, sender) in std::mem::take(&mut *waiting) {
+            let outcome = match &result {
+                _ if index < committed_size => Ok(()),
+                Err(e) if index >= size => Err(e.kind()),
+                _ => {
+                    waiting.push((index, sender));
+                    continue;
+                }
+            };
+            // the writer may have given up on it, which is fine
+            let _ = sender.send(outcome);
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use zeyrho::queue::wal::segmented::DEFAULT_SEGMENT_BYTES;
+
+    #[tokio::test]
+    async fn test_concurrent_writes_share_a_commit() {
+        let dir = tempdir().unwrap();
+        let wal = SegmentedWal::open(dir.path(), DEFAULT_SEGMENT_BYTES)
+            .unwrap()
+            .with_durability(Durability::GroupCommit {
+                window: Duration::from_millis(50),
+                max_bytes: usize::MAX,
+            });
+        let group_commit = GroupCommit::new(wal, |_| ());
+
+        let mut writes = Vec::new();
+        for i in 0..10u8 {
+            let group_commit = group_commit.clone();
+            writes.push(tokio::spawn(async move {
+                group_commit.write(vec![i], |_| ()).await.unwrap()
+            }));
+        }
+
+        // nothing is committed until the window is over, then all of it is
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(group_commit.wal.lock().unwrap().committed_size(), 0);
+        let mut indices = Vec::new();
+        for write in writes {
+            indices.push(write.await.unwrap());
+        }
+        indices.sort_unstable();
+        assert_eq!(indices, (0..10).collect::<Vec<_>>());
+        assert_eq!(group_commit.wal.lock().unwrap().committed_size(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_writes_commit_themselves_without_group_commit() {
+        let dir = tempdir().unwrap();
+        let wal = SegmentedWal::open(dir.path(), DEFAULT_SEGMENT_BYTES).unwrap();
+        let group_commit = GroupCommit::new(wal, |_| ());
+
+        let written = Arc::new(Mutex::new(Vec::new()));
+        for (i, record) in [&b"first"[..], b"second"].into_iter().enumerate() {
+            let written = written.clone();
+            let index = group_commit
+                .write(record.to_vec(), move |index| {
+                    written.lock().unwrap().push(index)
+                })
+                .await
+                .unwrap();
+            assert_eq!(index, i);
+        }
+        assert_eq!(*written.lock().unwrap(), vec![0, 1]);
+        assert_eq!(group_commit.committed_size(), 2);
+    }
+}
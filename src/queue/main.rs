@@ -1,25 +1,40 @@
 mod client;
+mod commit;
+mod record;
+mod state;
 
-use bytes::Bytes;
+use crate::commit::GroupCommit;
+use crate::record::QueueRecord;
+use crate::state::{QueueState, Queued};
 use nanoid::nanoid;
-use prost::Message;
-use std::collections::VecDeque;
-use std::io::Write;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tonic::codegen::tokio_stream::Stream;
 use tonic::service::Interceptor;
 use tonic::{Request, Response, Status, Streaming, async_trait, transport::Server};
-use tracing::{info, instrument};
-use zeyrho::queue::wal::wal::{FileWal, Wal};
+use tracing::{error, info, instrument};
+use zeyrho::queue::wal::segmented::{DEFAULT_SEGMENT_BYTES, SegmentedWal};
+use zeyrho::queue::wal::wal::{Durability, Wal};
 use zeyrho::zeyrho::queue::dequeue_response::QueueMessage;
 use zeyrho::zeyrho::queue::queue_server::{Queue, QueueServer};
 use zeyrho::zeyrho::queue::{
-    DequeueRequest, DequeueResponse, EnqueueRequest, EnqueueResponse, ReplicateDataRequest,
-    ReplicateDataResponse, SizeRequest, SizeResponse,
+    AckRequest, AckResponse, DequeueRequest, DequeueResponse, EnqueueRequest, EnqueueResponse,
+    ExtendLeaseRequest, ExtendLeaseResponse, ListDeadLettersRequest, ListDeadLettersResponse,
+    NackRequest, NackResponse, PurgeDeadLettersRequest, PurgeDeadLettersResponse,
+    RedriveDeadLettersRequest, RedriveDeadLettersResponse, ReplicateDataRequest,
+    ReplicateDataResponse, SizeRequest, SizeResponse, list_dead_letters_response,
 };
 
 const DATA_DIR: &str = "data";
+// the WAL segments live in this directory under DATA_DIR
+const WAL_DIR: &str = "wal";
+// how long a dequeued message is out of sight for when the request doesn't say
+const DEFAULT_VISIBILITY_TIMEOUT: Duration = Duration::from_secs(30);
+// how many times a message can be dequeued before the next dequeue dead-letters it instead
+const DEFAULT_MAX_RECEIVES: u32 = 10;
 
 mod proto {
     pub(crate) const FILE_DESCRIPTOR_SET: &[u8] =
@@ -32,19 +47,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     tracing_subscriber::fmt::init();
 
+    // how the WAL is synced can be picked with the first argument, e.g. `cargo run --bin queue -- group`
+    let durability = match std::env::args().nth(1) {
+        Some(arg) => arg.parse::<Durability>()?,
+        None => Durability::default(),
+    };
+    info!("WAL durability: {}", durability);
+    // and the max receives with the second, 0 turns dead-lettering off
+    let max_receives = match std::env::args().nth(2) {
+        Some(arg) => arg.parse::<u32>()?,
+        None => DEFAULT_MAX_RECEIVES,
+    };
+    info!("max receives: {}", max_receives);
+
     let service = tonic_reflection::server::Builder::configure()
         .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
         .build_v1()
         .unwrap();
 
-    let queue = Arc::new(Mutex::new(VecDeque::new()));
-
-    let wal = FileWal::new("data/wal.bin", "data/wal.meta").unwrap();
-
-    let queue_service = SimpleQueue {
-        queue,
-        wal: Arc::new(Mutex::new(wal)),
-    };
+    let queue_service = SimpleQueue::new(Path::new(DATA_DIR), DEFAULT_SEGMENT_BYTES, durability)?
+        .with_max_receives(max_receives);
 
     Server::builder()
         .add_service(service)
@@ -59,8 +81,191 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 #[derive(Debug)]
 struct SimpleQueue {
-    queue: Arc<Mutex<VecDeque<QueueMessage>>>,
-    wal: Arc<Mutex<FileWal>>,
+    queue: Arc<Mutex<QueueState>>,
+    wal: Arc<GroupCommit>,
+}
+
+impl SimpleQueue {
+    // opens the WAL in `data_dir` and rebuilds the queue from it, this has to be called from inside the tokio runtime
+    fn new(
+        data_dir: &Path,
+        segment_bytes: usize,
+        durability: Durability,
+    ) -> Result<Self, std::io::Error> {
+        migrate_single_file_wal(data_dir)?;
+        let wal =
+            SegmentedWal::open(data_dir.join(WAL_DIR), segment_bytes)?.with_durability(durability);
+
+        let queue = recover(&wal)?;
+        info!(
+            "recovered {} messages and {} dead letters from the WAL",
+            queue.len(Instant::now()),
+            queue.dead_letters(0).len()
+        );
+
+        let queue = Arc::new(Mutex::new(queue));
+        let on_roll_back = {
+            let queue = queue.clone();
+            move |size| queue.lock().unwrap().roll_back(size)
+        };
+        let queue = SimpleQueue {
+            queue,
+            wal: GroupCommit::new(wal, on_roll_back),
+        };
+        queue.clean_wal()?;
+
+        Ok(queue)
+    }
+
+    fn with_max_receives(self, max_receives: u32) -> Self {
+        self.queue.lock().unwrap().set_max_receives(max_receives);
+        self
+    }
+
+    // writes that `dead_letters` went to the dead-letter queue, they are put back with the waiting messages if it fails
+    async fn dead_letter(&self, dead_letters: Vec<Queued>) -> Result<(), Status> {
+        let record = QueueRecord::DeadLetter {
+            ids: dead_letters.iter().map(|q| q.message.id.clone()).collect(),
+        };
+        let written = match record.encode() {
+            Ok(buf) => self.wal.write(buf, |_| ()).await,
+            Err(e) => Err(e.into()),
+        };
+        self.queue
+            .lock()
+            .unwrap()
+            .finish_dead_letter(dead_letters, written.is_ok());
+        written?;
+
+        Ok(())
+    }
+
+    // drops the WAL segments that only hold messages which have been acked
+    fn clean_wal(&self) -> Result<(), std::io::Error> {
+        let mut wal = self.wal.wal().lock().unwrap();
+        let until = self
+            .queue
+            .lock()
+            .unwrap()
+            .first_needed()
+            .unwrap_or(wal.size());
+        wal.clean_until(until)
+    }
+}
+
+// a visibility timeout from a request, where 0 means the default
+fn lease_deadline(now: Instant, visibility_timeout_ms: u32) -> Instant {
+    match visibility_timeout_ms {
+        0 => now + DEFAULT_VISIBILITY_TIMEOUT,
+        ms => now + Duration::from_millis(ms as u64),
+    }
+}
+
+// Replays the WAL into the messages that haven't been acked and the dead letters, in the order they were enqueued.
+// Receives are written down, so every message comes back with the receive count it had. Leases aren't, so messages that
+// were leased out are waiting to be dequeued again.
+fn recover(wal: &SegmentedWal) -> Result<QueueState, std::io::Error> {
+    let mut messages = BTreeMap::new();
+    let mut dead_letters = BTreeMap::new();
+    let mut indices = HashMap::new();
+    for (index, entry) in (wal.base_index()..).zip(wal.iter_from(wal.base_index())?) {
+        match QueueRecord::decode(&entry?, index)? {
+            QueueRecord::Enqueue { id, payload } => {
+                indices.insert(id.clone(), index);
+                messages.insert(index, Queued {
+                    index,
+                    message: QueueMessage { id, payload },
+                    receives: 0,
+                });
+            }
+            QueueRecord::Dequeue { ids } | QueueRecord::Ack { ids } => {
+                for id in ids {
+                    if let Some(index) = indices.remove(&id) {
+                        messages.remove(&index);
+                    }
+                }
+            }
+            QueueRecord::Receive { ids } => {
+                for id in ids {
+                    if let Some(queued) = indices.get(&id).and_then(|index| messages.get_mut(index))
+                    {
+                        queued.receives += 1;
+                    }
+                }
+            }
+            QueueRecord::DeadLetter { ids } => {
+                move_messages(&ids, &indices, &mut messages, &mut dead_letters)
+            }
+            QueueRecord::Redrive { ids } => {
+                move_messages(&ids, &indices, &mut dead_letters, &mut messages);
+                // a redrive starts the count over
+                for id in ids {
+                    if let Some(queued) = indices.get(&id).and_then(|index| messages.get_mut(index))
+                    {
+                        queued.receives = 0;
+                    }
+                }
+            }
+            QueueRecord::Purge { ids } => {
+                for id in ids {
+                    if let Some(index) = indices.remove(&id) {
+                        dead_letters.remove(&index);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(QueueState::new(
+        messages.into_values(),
+        dead_letters.into_values(),
+        DEFAULT_MAX_RECEIVES,
+    ))
+}
+
+// moves the messages with `ids` that are in `from` over to `to`, both by WAL index
+fn move_messages(
+    ids: &[String],
+    indices: &HashMap<String, usize>,
+    from: &mut BTreeMap<usize, Queued>,
+    to: &mut BTreeMap<usize, Queued>,
+) {
+    for id in ids {
+        if let Some((index, queued)) = indices.get(id).and_then(|index| from.remove_entry(index)) {
+            to.insert(index, queued);
+        }
+    }
+}
+
+// The WAL used to be a single data/wal.bin, with data/wal.meta next to it. If that is still around it becomes the
+// first segment, which starts at index 0.
+fn migrate_single_file_wal(data_dir: &Path) -> Result<(), std::io::Error> {
+    let wal_path = data_dir.join("wal.bin");
+    if !wal_path.exists() {
+        return Ok(());
+    }
+
+    let wal_dir = data_dir.join(WAL_DIR);
+    std::fs::create_dir_all(&wal_dir)?;
+    if std::fs::read_dir(&wal_dir)?.next().is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            "both data/wal.bin and WAL segments exist, not sure which one to use",
+        ));
+    }
+
+    let segment_name = format!("{:020}", 0);
+    std::fs::rename(&wal_path, wal_dir.join(format!("{}.wal", segment_name)))?;
+    let metadata_path = data_dir.join("wal.meta");
+    if metadata_path.exists() {
+        std::fs::rename(
+            metadata_path,
+            wal_dir.join(format!("{}.meta", segment_name)),
+        )?;
+    }
+    info!("moved data/wal.bin into the first WAL segment");
+
+    Ok(())
 }
 
 #[derive(Debug, Default, Clone)]
@@ -92,15 +297,28 @@ impl Queue for SimpleQueue {
         &self,
         request: Request<EnqueueRequest>,
     ) -> Result<Response<EnqueueResponse>, Status> {
-        let mut buf = Vec::new();
         let message_id = nanoid!();
-        let queue_message = QueueMessage {
+        let payload = request.into_inner().payload;
+        let record = QueueRecord::Enqueue {
+            id: message_id.clone(),
+            payload: payload.clone(),
+        };
+
+        // it goes in the queue in WAL order, but can only be handed out once it is committed
+        let message = QueueMessage {
             id: message_id.clone(),
-            payload: request.get_ref().payload.clone(),
+            payload,
         };
-        self.queue.lock().unwrap().push_back(queue_message);
-        request.into_inner().encode(&mut buf).unwrap();
-        self.wal.lock().unwrap().write(&buf)?;
+        let queue = self.queue.clone();
+        self.wal
+            .write(record.encode()?, move |index| {
+                queue.lock().unwrap().push(Queued {
+                    index,
+                    message,
+                    receives: 0,
+                })
+            })
+            .await?;
 
         Ok(Response::new(EnqueueResponse {
             message_id: { message_id },
@@ -111,25 +329,193 @@ impl Queue for SimpleQueue {
         &self,
         request: Request<DequeueRequest>,
     ) -> Result<Response<DequeueResponse>, Status> {
-        let num_to_pop = request.get_ref().number;
-        let mut return_vec = Vec::new();
-        let mut q = self.queue.lock().unwrap();
-        for _ in 0..num_to_pop {
-            match q.pop_front() {
-                Some(n) => return_vec.push(n),
-                None => continue,
+        let num_to_pop = request.get_ref().number as usize;
+        let now = Instant::now();
+        let deadline = lease_deadline(now, request.get_ref().visibility_timeout_ms);
+        let committed_size = self.wal.committed_size();
+
+        let (received, dead_letters) =
+            self.queue
+                .lock()
+                .unwrap()
+                .start_dequeue(num_to_pop, committed_size, now);
+
+        // the receives are counted in the WAL before the messages are handed out, so a restart doesn't reset them
+        let written = if received.is_empty() {
+            Ok(0)
+        } else {
+            let record = QueueRecord::Receive {
+                ids: received.iter().map(|q| q.message.id.clone()).collect(),
+            };
+            match record.encode() {
+                Ok(buf) => self.wal.write(buf, |_| ()).await,
+                Err(e) => Err(e.into()),
+            }
+        };
+        let messages =
+            self.queue
+                .lock()
+                .unwrap()
+                .finish_dequeue(received, deadline, written.is_ok());
+
+        if !dead_letters.is_empty() {
+            info!("dead-lettering {} messages", dead_letters.len());
+            if let Err(e) = self.dead_letter(dead_letters).await {
+                error!("error dead-lettering messages: {}", e);
             }
         }
+        written?;
+
+        Ok(Response::new(DequeueResponse { messages }))
+    }
+
+    async fn ack(&self, request: Request<AckRequest>) -> Result<Response<AckResponse>, Status> {
+        let message_id = request.into_inner().message_id;
+        let lease = match self
+            .queue
+            .lock()
+            .unwrap()
+            .start_ack(&message_id, Instant::now())
+        {
+            Some(lease) => lease,
+            None => return Ok(Response::new(AckResponse { acked: false })),
+        };
+
+        // the message is only gone once the ack is in the WAL, until then it stays leased
+        let record = QueueRecord::Ack {
+            ids: vec![message_id],
+        };
+        let written = match record.encode() {
+            Ok(buf) => self.wal.write(buf, |_| ()).await,
+            Err(e) => Err(e.into()),
+        };
+        self.queue
+            .lock()
+            .unwrap()
+            .finish_ack(lease, written.is_ok());
+        written?;
+
+        // the ack has happened whether or not this works, it is tried again next time
+        if let Err(e) = self.clean_wal() {
+            error!("error cleaning the WAL: {}", e);
+        }
+
+        Ok(Response::new(AckResponse { acked: true }))
+    }
+
+    async fn nack(&self, request: Request<NackRequest>) -> Result<Response<NackResponse>, Status> {
+        let nacked = self
+            .queue
+            .lock()
+            .unwrap()
+            .nack(&request.get_ref().message_id, Instant::now());
+
+        Ok(Response::new(NackResponse { nacked }))
+    }
+
+    async fn extend_lease(
+        &self,
+        request: Request<ExtendLeaseRequest>,
+    ) -> Result<Response<ExtendLeaseResponse>, Status> {
+        let request = request.into_inner();
+        let now = Instant::now();
+        let extended = self.queue.lock().unwrap().extend_lease(
+            &request.message_id,
+            now,
+            lease_deadline(now, request.visibility_timeout_ms),
+        );
+
+        Ok(Response::new(ExtendLeaseResponse { extended }))
+    }
+
+    async fn list_dead_letters(
+        &self,
+        request: Request<ListDeadLettersRequest>,
+    ) -> Result<Response<ListDeadLettersResponse>, Status> {
+        let messages = self
+            .queue
+            .lock()
+            .unwrap()
+            .dead_letters(request.get_ref().number as usize)
+            .into_iter()
+            .map(|queued| list_dead_letters_response::DeadLetter {
+                id: queued.message.id,
+                payload: queued.message.payload,
+                receives: queued.receives,
+            })
+            .collect();
+
+        Ok(Response::new(ListDeadLettersResponse { messages }))
+    }
+
+    async fn redrive_dead_letters(
+        &self,
+        request: Request<RedriveDeadLettersRequest>,
+    ) -> Result<Response<RedriveDeadLettersResponse>, Status> {
+        let redriven = self
+            .queue
+            .lock()
+            .unwrap()
+            .take_dead_letters(&request.get_ref().message_ids);
+        if redriven.is_empty() {
+            return Ok(Response::new(RedriveDeadLettersResponse { redriven: 0 }));
+        }
 
-        let response = DequeueResponse {
-            messages: return_vec,
+        let record = QueueRecord::Redrive {
+            ids: redriven.iter().map(|q| q.message.id.clone()).collect(),
         };
+        let count = redriven.len() as u64;
+        let written = match record.encode() {
+            Ok(buf) => self.wal.write(buf, |_| ()).await,
+            Err(e) => Err(e.into()),
+        };
+        self.queue
+            .lock()
+            .unwrap()
+            .finish_redrive(redriven, written.is_ok());
+        written?;
 
-        Ok(Response::new(response))
+        Ok(Response::new(RedriveDeadLettersResponse {
+            redriven: count,
+        }))
     }
 
-    async fn size(&self, request: Request<SizeRequest>) -> Result<Response<SizeResponse>, Status> {
-        let s = self.queue.lock().unwrap().len() as u64;
+    async fn purge_dead_letters(
+        &self,
+        request: Request<PurgeDeadLettersRequest>,
+    ) -> Result<Response<PurgeDeadLettersResponse>, Status> {
+        let purged = self
+            .queue
+            .lock()
+            .unwrap()
+            .take_dead_letters(&request.get_ref().message_ids);
+        if purged.is_empty() {
+            return Ok(Response::new(PurgeDeadLettersResponse { purged: 0 }));
+        }
+
+        let record = QueueRecord::Purge {
+            ids: purged.iter().map(|q| q.message.id.clone()).collect(),
+        };
+        let count = purged.len() as u64;
+        let written = match record.encode() {
+            Ok(buf) => self.wal.write(buf, |_| ()).await,
+            Err(e) => Err(e.into()),
+        };
+        self.queue
+            .lock()
+            .unwrap()
+            .finish_purge(purged, written.is_ok());
+        written?;
+
+        if let Err(e) = self.clean_wal() {
+            error!("error cleaning the WAL: {}", e);
+        }
+
+        Ok(Response::new(PurgeDeadLettersResponse { purged: count }))
+    }
+
+    async fn size(&self, _request: Request<SizeRequest>) -> Result<Response<SizeResponse>, Status> {
+        let s = self.queue.lock().unwrap().len(Instant::now()) as u64;
 
         Ok(Response::new(SizeResponse { size: { s } }))
     }
@@ -139,7 +525,7 @@ impl Queue for SimpleQueue {
 
     async fn replicate_data(
         &self,
-        request: Request<Streaming<ReplicateDataRequest>>,
+        _request: Request<Streaming<ReplicateDataRequest>>,
     ) -> Result<Response<Self::ReplicateDataStream>, Status> {
         todo!()
     }
@@ -149,6 +535,365 @@ impl Queue for SimpleQueue {
 mod tests {
     use super::*;
     use bytes::Bytes;
+    use prost::Message;
+    use tempfile::tempdir;
+
+    async fn enqueue(queue: &SimpleQueue, payload: &[u8]) -> String {
+        queue
+            .enqueue(Request::new(EnqueueRequest {
+                payload: payload.to_vec(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .message_id
+    }
+
+    async fn dequeue(queue: &SimpleQueue, number: u32) -> Vec<QueueMessage> {
+        dequeue_leased(queue, number, 0).await
+    }
+
+    async fn dequeue_leased(
+        queue: &SimpleQueue,
+        number: u32,
+        visibility_timeout_ms: u32,
+    ) -> Vec<QueueMessage> {
+        queue
+            .dequeue(Request::new(DequeueRequest {
+                number,
+                visibility_timeout_ms,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .messages
+    }
+
+    async fn ack(queue: &SimpleQueue, message_id: &str) -> bool {
+        queue
+            .ack(Request::new(AckRequest {
+                message_id: message_id.to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .acked
+    }
+
+    #[tokio::test]
+    async fn test_restart_rebuilds_the_queue() {
+        let dir = tempdir().unwrap();
+
+        let mut ids = Vec::new();
+        {
+            let queue =
+                SimpleQueue::new(dir.path(), DEFAULT_SEGMENT_BYTES, Durability::Always).unwrap();
+            for i in 0..5u8 {
+                ids.push(enqueue(&queue, &[i]).await);
+            }
+        }
+
+        let queue =
+            SimpleQueue::new(dir.path(), DEFAULT_SEGMENT_BYTES, Durability::Always).unwrap();
+        let messages = dequeue(&queue, 10).await;
+        let expected: Vec<QueueMessage> = ids
+            .into_iter()
+            .zip(0..5u8)
+            .map(|(id, i)| QueueMessage {
+                id,
+                payload: vec![i],
+            })
+            .collect();
+        assert_eq!(messages, expected);
+    }
+
+    #[tokio::test]
+    async fn test_acked_messages_are_not_redelivered_after_restart() {
+        let dir = tempdir().unwrap();
+
+        let mut ids = Vec::new();
+        {
+            let queue =
+                SimpleQueue::new(dir.path(), DEFAULT_SEGMENT_BYTES, Durability::Always).unwrap();
+            for i in 0..5u8 {
+                ids.push(enqueue(&queue, &[i]).await);
+            }
+            let messages = dequeue(&queue, 3).await;
+            assert!(ack(&queue, &messages[0].id).await);
+            assert!(ack(&queue, &messages[2].id).await);
+        }
+
+        // the one that was leased but not acked comes back, in its place
+        let queue =
+            SimpleQueue::new(dir.path(), DEFAULT_SEGMENT_BYTES, Durability::Always).unwrap();
+        let messages = dequeue(&queue, 10).await;
+        let recovered_ids: Vec<String> = messages.into_iter().map(|m| m.id).collect();
+        assert_eq!(recovered_ids, vec![
+            ids[1].clone(),
+            ids[3].clone(),
+            ids[4].clone()
+        ]);
+        for id in &recovered_ids {
+            assert!(ack(&queue, id).await);
+        }
+        drop(queue);
+
+        let queue =
+            SimpleQueue::new(dir.path(), DEFAULT_SEGMENT_BYTES, Durability::Always).unwrap();
+        assert!(dequeue(&queue, 10).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_wal_is_cleaned_behind_acks() {
+        let dir = tempdir().unwrap();
+        let segments = || std::fs::read_dir(dir.path().join(WAL_DIR)).unwrap().count();
+
+        // small enough that every entry gets a segment of its own
+        let queue = SimpleQueue::new(dir.path(), 1, Durability::Always).unwrap();
+        for i in 0..6u8 {
+            enqueue(&queue, &[i]).await;
+        }
+
+        // leased messages are still needed, acked ones aren't
+        let messages = dequeue(&queue, 3).await;
+        let before = segments();
+        assert_eq!(queue.wal.wal().lock().unwrap().base_index(), 0);
+        for message in &messages {
+            ack(&queue, &message.id).await;
+        }
+        assert_eq!(queue.wal.wal().lock().unwrap().base_index(), 3);
+        // the acks took a segment each, as many as were cleaned
+        assert_eq!(segments(), before);
+        drop(queue);
+
+        let queue = SimpleQueue::new(dir.path(), 1, Durability::Always).unwrap();
+        let messages = dequeue(&queue, 10).await;
+        let payloads: Vec<Vec<u8>> = messages.iter().map(|m| m.payload.clone()).collect();
+        assert_eq!(payloads, vec![vec![3], vec![4], vec![5]]);
+        for message in &messages {
+            ack(&queue, &message.id).await;
+        }
+
+        // with nothing left, all that stays is the segment the last of 6 enqueues, 2 receives and 6 acks went in
+        assert_eq!(queue.wal.wal().lock().unwrap().base_index(), 13);
+    }
+
+    #[tokio::test]
+    async fn test_leases() {
+        let dir = tempdir().unwrap();
+        let queue =
+            SimpleQueue::new(dir.path(), DEFAULT_SEGMENT_BYTES, Durability::Always).unwrap();
+        let first = enqueue(&queue, b"first").await;
+        let second = enqueue(&queue, b"second").await;
+
+        // out of sight while they are leased
+        let messages = dequeue_leased(&queue, 2, 50).await;
+        assert_eq!(messages.len(), 2);
+        assert!(dequeue(&queue, 2).await.is_empty());
+        let size = queue.size(Request::new(SizeRequest {})).await.unwrap();
+        assert_eq!(size.get_ref().size, 0);
+
+        // a nack hands the message back straight away
+        let nacked = queue
+            .nack(Request::new(NackRequest {
+                message_id: first.clone(),
+            }))
+            .await
+            .unwrap();
+        assert!(nacked.get_ref().nacked);
+        let messages = dequeue_leased(&queue, 2, 50).await;
+        assert_eq!(messages[0].id, first);
+
+        let extended = queue
+            .extend_lease(Request::new(ExtendLeaseRequest {
+                message_id: first.clone(),
+                visibility_timeout_ms: 10_000,
+            }))
+            .await
+            .unwrap();
+        assert!(extended.get_ref().extended);
+
+        // the second lease runs out, the first was extended
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert!(!ack(&queue, &second).await);
+        let messages = dequeue(&queue, 2).await;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, second);
+
+        assert!(ack(&queue, &first).await);
+        assert!(!ack(&queue, &first).await);
+    }
+
+    async fn dead_letter_ids(queue: &SimpleQueue) -> Vec<String> {
+        queue
+            .list_dead_letters(Request::new(ListDeadLettersRequest { number: 0 }))
+            .await
+            .unwrap()
+            .into_inner()
+            .messages
+            .into_iter()
+            .map(|m| m.id)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_dead_letters() {
+        let dir = tempdir().unwrap();
+        let open = || {
+            SimpleQueue::new(dir.path(), DEFAULT_SEGMENT_BYTES, Durability::Always)
+                .unwrap()
+                .with_max_receives(2)
+        };
+
+        let (poison, fine) = {
+            let queue = open();
+            let poison = enqueue(&queue, b"poison").await;
+            let fine = enqueue(&queue, b"fine").await;
+
+            for _ in 0..2 {
+                let messages = dequeue_leased(&queue, 1, 1).await;
+                assert_eq!(messages[0].id, poison);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+
+            // the third time it goes to the dead-letter queue and the next message is handed out instead
+            let messages = dequeue(&queue, 1).await;
+            assert_eq!(messages[0].id, fine);
+            let listed = queue
+                .list_dead_letters(Request::new(ListDeadLettersRequest { number: 0 }))
+                .await
+                .unwrap()
+                .into_inner()
+                .messages;
+            assert_eq!(listed.len(), 1);
+            assert_eq!(listed[0].payload, b"poison");
+            assert_eq!(listed[0].receives, 2);
+            (poison, fine)
+        };
+
+        // still dead-lettered after a restart, the one that was only leased is back
+        let queue = open();
+        assert_eq!(dead_letter_ids(&queue).await, vec![poison.clone()]);
+        let redriven = queue
+            .redrive_dead_letters(Request::new(RedriveDeadLettersRequest {
+                message_ids: vec![poison.clone(), "unknown".to_string()],
+            }))
+            .await
+            .unwrap();
+        assert_eq!(redriven.get_ref().redriven, 1);
+        assert!(dead_letter_ids(&queue).await.is_empty());
+        let messages = dequeue_leased(&queue, 2, 1).await;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].id, poison);
+        assert_eq!(messages[1].id, fine);
+        drop(queue);
+
+        // the receives from before the restart still count, fine was received once before the redrive and once after
+        let queue = open();
+        assert!(dead_letter_ids(&queue).await.is_empty());
+        let messages = dequeue_leased(&queue, 2, 1).await;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, poison);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert!(dequeue(&queue, 2).await.is_empty());
+        assert_eq!(dead_letter_ids(&queue).await, vec![poison.clone(), fine]);
+
+        let purged = queue
+            .purge_dead_letters(Request::new(PurgeDeadLettersRequest {
+                message_ids: Vec::new(),
+            }))
+            .await
+            .unwrap();
+        assert_eq!(purged.get_ref().purged, 2);
+        drop(queue);
+
+        let queue = open();
+        assert!(dead_letter_ids(&queue).await.is_empty());
+        assert!(dequeue(&queue, 2).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_uncommitted_messages_are_not_handed_out() {
+        let dir = tempdir().unwrap();
+        let durability = Durability::GroupCommit {
+            window: Duration::from_millis(100),
+            max_bytes: usize::MAX,
+        };
+        let queue =
+            Arc::new(SimpleQueue::new(dir.path(), DEFAULT_SEGMENT_BYTES, durability).unwrap());
+
+        let enqueuing = queue.clone();
+        let enqueued = tokio::spawn(async move { enqueue(&enqueuing, b"message").await });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(queue.queue.lock().unwrap().len(Instant::now()), 1);
+        assert!(dequeue(&queue, 1).await.is_empty());
+
+        let id = enqueued.await.unwrap();
+        let messages = dequeue(&queue, 1).await;
+        assert_eq!(messages[0].id, id);
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_requests_in_the_wal_are_recovered() {
+        let dir = tempdir().unwrap();
+
+        // what the WAL held before the message ids were written to it
+        {
+            let mut wal =
+                SegmentedWal::open(dir.path().join(WAL_DIR), DEFAULT_SEGMENT_BYTES).unwrap();
+            for payload in [b"first", b"other"] {
+                let request = EnqueueRequest {
+                    payload: payload.to_vec(),
+                };
+                wal.write(&request.encode_to_vec()).unwrap();
+            }
+        }
+
+        let queue =
+            SimpleQueue::new(dir.path(), DEFAULT_SEGMENT_BYTES, Durability::Always).unwrap();
+        let id = enqueue(&queue, b"third").await;
+        let messages = dequeue(&queue, 10).await;
+        let payloads: Vec<&[u8]> = messages.iter().map(|m| m.payload.as_slice()).collect();
+        assert_eq!(payloads, vec![&b"first"[..], b"other", b"third"]);
+        assert_eq!(messages[2].id, id);
+        assert_ne!(messages[0].id, messages[1].id);
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_requests_keep_their_ids_across_restarts() {
+        let dir = tempdir().unwrap();
+        let open =
+            || SimpleQueue::new(dir.path(), DEFAULT_SEGMENT_BYTES, Durability::Always).unwrap();
+
+        {
+            let mut wal =
+                SegmentedWal::open(dir.path().join(WAL_DIR), DEFAULT_SEGMENT_BYTES).unwrap();
+            for payload in [b"first", b"other"] {
+                let request = EnqueueRequest {
+                    payload: payload.to_vec(),
+                };
+                wal.write(&request.encode_to_vec()).unwrap();
+            }
+        }
+
+        {
+            let queue = open();
+            let messages = dequeue(&queue, 1).await;
+            assert_eq!(messages[0].payload, b"first");
+            assert!(ack(&queue, &messages[0].id).await);
+        }
+
+        // the ack still matches the message after any number of restarts, the other one comes back with the same id
+        let mut other_ids = Vec::new();
+        for _ in 0..2 {
+            let queue = open();
+            let messages = dequeue(&queue, 10).await;
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].payload, b"other");
+            other_ids.push(messages[0].id.clone());
+        }
+        assert_eq!(other_ids[0], other_ids[1]);
+    }
 
     #[test]
     fn test_decode() {
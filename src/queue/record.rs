@@ -0,0 +1,112 @@
+use prost::Message;
+use rmp_serde::{Deserializer, Serializer};
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+use zeyrho::zeyrho::queue::EnqueueRequest;
+
+// Records are this byte followed by the msgpack encoded record. The WAL used to hold encoded EnqueueRequests, which are
+// either empty or start with the tag of the payload field, 0x0a, so the two can't be mixed up.
+const RECORD_VERSION: u8 = 1;
+
+// what the queue writes to its WAL, replaying them in order gets back to the same queue
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum QueueRecord {
+    Enqueue { id: String, payload: Vec<u8> },
+    // messages that were handed out by a dequeue, from before they had to be acked, they are gone like acked ones
+    Dequeue { ids: Vec<String> },
+    Ack { ids: Vec<String> },
+    // messages that were received too many times and went to the dead-letter queue
+    DeadLetter { ids: Vec<String> },
+    // dead letters that were put back in the queue
+    Redrive { ids: Vec<String> },
+    // dead letters that were thrown away
+    Purge { ids: Vec<String> },
+    // messages that were leased out by a dequeue, which counts towards dead-lettering them
+    Receive { ids: Vec<String> },
+}
+
+impl QueueRecord {
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![RECORD_VERSION];
+        self.serialize(&mut Serializer::new(&mut buf))
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        Ok(buf)
+    }
+
+    // `index` is where the record sits in the WAL, which is what an enqueue from before records existed takes its id from
+    pub fn decode(buf: &[u8], index: usize) -> Result<Self, Error> {
+        match buf.first() {
+            Some(&RECORD_VERSION) => QueueRecord::deserialize(&mut Deserializer::new(&buf[1..]))
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            // An enqueue from before the id was written down. Its id has to be the same every time it is replayed, or the
+            // acks and receives written for it would stop matching after a restart.
+            _ => {
+                let request = EnqueueRequest::decode(buf)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                Ok(QueueRecord::Enqueue {
+                    id: format!("wal-{}", index),
+                    payload: request.payload,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_and_decode() {
+        let records = [
+            QueueRecord::Enqueue {
+                id: "some id".to_string(),
+                payload: b"some payload".to_vec(),
+            },
+            QueueRecord::Dequeue {
+                ids: vec!["some id".to_string(), "other id".to_string()],
+            },
+            QueueRecord::Ack {
+                ids: vec!["some id".to_string()],
+            },
+            QueueRecord::DeadLetter {
+                ids: vec!["some id".to_string()],
+            },
+            QueueRecord::Redrive {
+                ids: vec!["some id".to_string()],
+            },
+            QueueRecord::Purge {
+                ids: vec!["some id".to_string()],
+            },
+            QueueRecord::Receive {
+                ids: vec!["some id".to_string(), "other id".to_string()],
+            },
+        ];
+        for record in records {
+            let buf = record.encode().unwrap();
+            assert_eq!(buf[0], RECORD_VERSION);
+            assert_eq!(QueueRecord::decode(&buf, 0).unwrap(), record);
+        }
+    }
+
+    #[test]
+    fn test_decode_enqueue_request() {
+        for payload in [b"some payload".to_vec(), Vec::new()] {
+            let buf = EnqueueRequest {
+                payload: payload.clone(),
+            }
+            .encode_to_vec();
+
+            match QueueRecord::decode(&buf, 7).unwrap() {
+                QueueRecord::Enqueue {
+                    id,
+                    payload: decoded,
+                } => {
+                    assert_eq!(id, "wal-7");
+                    assert_eq!(decoded, payload);
+                }
+                record => panic!("expected an enqueue, got {:?}", record),
+            }
+        }
+    }
+}
@@ -0,0 +1,537 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::time::Instant;
+use zeyrho::zeyrho::queue::dequeue_response::QueueMessage;
+
+// Every message the queue still has. A message is waiting to be dequeued, leased out to whoever dequeued it, in the
+// dead-letter queue, or taken out while a record about it is written to the WAL, and only an ack or a purge gets rid of
+// it. Leases that run out put the message back with the ones waiting, they are checked whenever the state is looked at
+// so there is nothing to run in the background.
+#[derive(Debug, Default)]
+pub struct QueueState {
+    // In the same order as their enqueues are in the WAL, which means the last few might not be committed yet. Those
+    // aren't handed out until they are.
+    messages: VecDeque<Queued>,
+    // messages that have been dequeued but not acked yet, by the WAL index of their enqueue
+    leases: BTreeMap<usize, Lease>,
+    // the index of every leased message by its id
+    leased_ids: HashMap<String, usize>,
+    // when each lease runs out, soonest first, so expiring them only looks at the ones that have
+    deadlines: BTreeSet<(Instant, usize)>,
+    // messages that were received too many times, by the WAL index of their enqueue
+    dead_letters: BTreeMap<usize, Queued>,
+    // the WAL indices of messages that have been taken out of the rest while a record about them is written
+    pending: BTreeSet<usize>,
+    // how many times a message can be dequeued before it is dead-lettered, 0 means it never is
+    max_receives: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Queued {
+    // where the message's enqueue is in the WAL
+    pub index: usize,
+    pub message: QueueMessage,
+    // how many times it has been dequeued, since it was enqueued or redriven
+    pub receives: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lease {
+    pub queued: Queued,
+    // the message is handed out again from here on
+    pub deadline: Instant,
+}
+
+impl QueueState {
+    // `messages` have to be in WAL order
+    pub fn new(
+        messages: impl IntoIterator<Item = Queued>,
+        dead_letters: impl IntoIterator<Item = Queued>,
+        max_receives: u32,
+    ) -> Self {
+        QueueState {
+            messages: messages.into_iter().collect(),
+            dead_letters: dead_letters
+                .into_iter()
+                .map(|queued| (queued.index, queued))
+                .collect(),
+            max_receives,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_max_receives(&mut self, max_receives: u32) {
+        self.max_receives = max_receives;
+    }
+
+    // how many messages are waiting to be dequeued
+    pub fn len(&self, now: Instant) -> usize {
+        // leases that have run out count as waiting, whether or not they have been moved back yet
+        let expired = self.deadlines.range(..=(now, usize::MAX)).count();
+        self.messages.len() + expired
+    }
+
+    // a message that has just been enqueued, its enqueue comes after everything else in the WAL
+    pub fn push(&mut self, queued: Queued) {
+        self.messages.push_back(queued);
+    }
+
+    // drops the messages from `size` on, which were enqueued but dropped from the WAL by a commit that failed
+    pub fn roll_back(&mut self, size: usize) {
+        // they can't have been handed out yet, so they are all still waiting at the back
+        while self
+            .messages
+            .back()
+            .is_some_and(|queued| queued.index >= size)
+        {
+            self.messages.pop_back();
+        }
+    }
+
+    // Takes out up to `number` of the waiting messages that are committed, first in first out, while their receive is
+    // written to the WAL. They are leased out by finish_dequeue. Messages that have been received max_receives times
+    // already are taken out instead and returned second, they go in the dead-letter queue with finish_dead_letter once
+    // that has been written to the WAL.
+    pub fn start_dequeue(
+        &mut self,
+        number: usize,
+        committed_size: usize,
+        now: Instant,
+    ) -> (Vec<Queued>, Vec<Queued>) {
+        self.expire_leases(now);
+
+        let mut received = Vec::new();
+        let mut dead_letters = Vec::new();
+        while received.len() < number
+            && self
+                .messages
+                .front()
+                .is_some_and(|queued| queued.index < committed_size)
+        {
+            let queued = self.messages.pop_front().unwrap();
+            self.pending.insert(queued.index);
+            if self.max_receives > 0 && queued.receives >= self.max_receives {
+                dead_letters.push(queued);
+            } else {
+                received.push(queued);
+            }
+        }
+
+        (received, dead_letters)
+    }
+
+    // The receive is in the WAL and the messages are leased until `deadline`, or if `committed` is false it couldn't be
+    // written and they are waiting again. Returns what to hand out.
+    pub fn finish_dequeue(
+        &mut self,
+        received: Vec<Queued>,
+        deadline: Instant,
+        committed: bool,
+    ) -> Vec<QueueMessage> {
+        let mut dequeued = Vec::new();
+        for mut queued in received {
+            self.pending.remove(&queued.index);
+            if committed {
+                queued.receives += 1;
+                dequeued.push(queued.message.clone());
+                self.insert_lease(Lease { queued, deadline });
+            } else {
+                self.make_waiting(queued);
+            }
+        }
+
+        dequeued
+    }
+
+    // the dead-lettering is in the WAL, or if `committed` is false it couldn't be written and they are waiting again
+    pub fn finish_dead_letter(&mut self, dead_letters: Vec<Queued>, committed: bool) {
+        for queued in dead_letters {
+            self.pending.remove(&queued.index);
+            if committed {
+                self.dead_letters.insert(queued.index, queued);
+            } else {
+                self.make_waiting(queued);
+            }
+        }
+    }
+
+    // up to `number` of the messages in the dead-letter queue, oldest enqueue first, 0 lists all of them
+    pub fn dead_letters(&self, number: usize) -> Vec<Queued> {
+        let number = if number == 0 { usize::MAX } else { number };
+        self.dead_letters.values().take(number).cloned().collect()
+    }
+
+    // Takes the messages with `ids` out of the dead-letter queue, or all of it if `ids` is empty, while a redrive or a
+    // purge of them is written to the WAL. Ids that aren't in it are skipped.
+    pub fn take_dead_letters(&mut self, ids: &[String]) -> Vec<Queued> {
+        let ids: HashSet<&str> = ids.iter().map(String::as_str).collect();
+        let indices: Vec<usize> = self
+            .dead_letters
+            .values()
+            .filter(|queued| ids.is_empty() || ids.contains(queued.message.id.as_str()))
+            .map(|queued| queued.index)
+            .collect();
+
+        indices
+            .into_iter()
+            .map(|index| {
+                self.pending.insert(index);
+                self.dead_letters.remove(&index).unwrap()
+            })
+            .collect()
+    }
+
+    // the redrive is in the WAL and the messages are waiting again, as if they were never received
+    pub fn finish_redrive(&mut self, redriven: Vec<Queued>, committed: bool) {
+        for mut queued in redriven {
+            self.pending.remove(&queued.index);
+            if committed {
+                queued.receives = 0;
+                self.make_waiting(queued);
+            } else {
+                self.dead_letters.insert(queued.index, queued);
+            }
+        }
+    }
+
+    // the purge is in the WAL and the messages are gone
+    pub fn finish_purge(&mut self, purged: Vec<Queued>, committed: bool) {
+        for queued in purged {
+            self.pending.remove(&queued.index);
+            if !committed {
+                self.dead_letters.insert(queued.index, queued);
+            }
+        }
+    }
+
+    // Takes the lease on `id` while its ack is written to the WAL, None if it isn't leased. The message can't be
+    // cleaned from the WAL until finish_ack.
+    pub fn start_ack(&mut self, id: &str, now: Instant) -> Option<Lease> {
+        self.expire_leases(now);
+
+        let lease = self.remove_lease(id)?;
+        self.pending.insert(lease.queued.index);
+        Some(lease)
+    }
+
+    // the ack is in the WAL, or if `committed` is false it couldn't be written and the lease carries on
+    pub fn finish_ack(&mut self, lease: Lease, committed: bool) {
+        self.pending.remove(&lease.queued.index);
+        if !committed {
+            self.insert_lease(lease);
+        }
+    }
+
+    // puts a leased message back with the waiting ones, returning whether it was leased
+    pub fn nack(&mut self, id: &str, now: Instant) -> bool {
+        self.expire_leases(now);
+
+        match self.remove_lease(id) {
+            Some(lease) => {
+                self.make_waiting(lease.queued);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // moves the end of the lease on `id` to `deadline`, returning whether it was leased
+    pub fn extend_lease(&mut self, id: &str, now: Instant, deadline: Instant) -> bool {
+        self.expire_leases(now);
+
+        match self.remove_lease(id) {
+            Some(lease) => {
+                self.insert_lease(Lease { deadline, ..lease });
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Everything in the WAL before the first message that hasn't been acked or purged for good can be cleaned. Every
+    // other record comes after the enqueue it is about, so they only ever refer to messages that are gone too.
+    pub fn first_needed(&self) -> Option<usize> {
+        let first_waiting = self.messages.front().map(|queued| queued.index);
+        let first_leased = self.leases.keys().next().copied();
+        let first_dead = self.dead_letters.keys().next().copied();
+        let first_pending = self.pending.first().copied();
+
+        first_waiting
+            .into_iter()
+            .chain(first_leased)
+            .chain(first_dead)
+            .chain(first_pending)
+            .min()
+    }
+
+    pub fn expire_leases(&mut self, now: Instant) {
+        while let Some(&(deadline, index)) = self.deadlines.first() {
+            if deadline > now {
+                break;
+            }
+
+            self.deadlines.pop_first();
+            let lease = self.leases.remove(&index).unwrap();
+            self.leased_ids.remove(&lease.queued.message.id);
+            self.make_waiting(lease.queued);
+        }
+    }
+
+    fn insert_lease(&mut self, lease: Lease) {
+        let index = lease.queued.index;
+        self.leased_ids
+            .insert(lease.queued.message.id.clone(), index);
+        self.deadlines.insert((lease.deadline, index));
+        self.leases.insert(index, lease);
+    }
+
+    fn remove_lease(&mut self, id: &str) -> Option<Lease> {
+        let index = self.leased_ids.remove(id)?;
+        let lease = self.leases.remove(&index).unwrap();
+        self.deadlines.remove(&(lease.deadline, index));
+        Some(lease)
+    }
+
+    // messages that come back go in WAL order with the rest, which puts them at or near the front
+    fn make_waiting(&mut self, queued: Queued) {
+        let position = self.messages.partition_point(|q| q.index < queued.index);
+        self.messages.insert(position, queued);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn queued(index: usize) -> Queued {
+        Queued {
+            index,
+            message: QueueMessage {
+                id: format!("id-{}", index),
+                payload: vec![index as u8],
+            },
+            receives: 0,
+        }
+    }
+
+    // a dequeue whose receive made it into the WAL
+    fn dequeue(
+        state: &mut QueueState,
+        number: usize,
+        committed_size: usize,
+        now: Instant,
+        deadline: Instant,
+    ) -> (Vec<QueueMessage>, Vec<Queued>) {
+        let (received, dead_letters) = state.start_dequeue(number, committed_size, now);
+        (state.finish_dequeue(received, deadline, true), dead_letters)
+    }
+
+    fn ids(messages: &[QueueMessage]) -> Vec<&str> {
+        messages.iter().map(|m| m.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_dequeue_leases_committed_messages() {
+        let now = Instant::now();
+        let lease = now + Duration::from_secs(30);
+        let mut state = QueueState::new((0..3).map(queued), [], 0);
+        state.push(queued(3));
+
+        // the last one isn't committed yet
+        let (dequeued, _) = dequeue(&mut state, 10, 3, now, lease);
+        assert_eq!(ids(&dequeued), vec!["id-0", "id-1", "id-2"]);
+        assert_eq!(state.len(now), 1);
+        assert_eq!(state.first_needed(), Some(0));
+
+        let (dequeued, _) = dequeue(&mut state, 10, 4, now, lease);
+        assert_eq!(ids(&dequeued), vec!["id-3"]);
+        assert_eq!(state.len(now), 0);
+    }
+
+    #[test]
+    fn test_roll_back_drops_uncommitted_messages() {
+        let now = Instant::now();
+        let lease = now + Duration::from_secs(30);
+        let mut state = QueueState::new((0..2).map(queued), [], 0);
+        state.push(queued(2));
+        state.push(queued(3));
+
+        state.roll_back(2);
+        assert_eq!(state.len(now), 2);
+
+        // the indices are handed out again to whatever is written next
+        state.push(queued(2));
+        let (dequeued, _) = dequeue(&mut state, 10, 3, now, lease);
+        assert_eq!(ids(&dequeued), vec!["id-0", "id-1", "id-2"]);
+    }
+
+    #[test]
+    fn test_failed_receive_puts_messages_back() {
+        let now = Instant::now();
+        let mut state = QueueState::new((0..2).map(queued), [], 0);
+
+        let (received, _) = state.start_dequeue(1, 2, now);
+        assert_eq!(state.len(now), 1);
+        // it is still needed while its receive is written
+        assert_eq!(state.first_needed(), Some(0));
+        assert!(
+            state
+                .finish_dequeue(received, now + Duration::from_secs(30), false)
+                .is_empty()
+        );
+
+        let (received, _) = state.start_dequeue(2, 2, now);
+        assert_eq!(received, vec![queued(0), queued(1)]);
+    }
+
+    #[test]
+    fn test_ack() {
+        let now = Instant::now();
+        let mut state = QueueState::new((0..2).map(queued), [], 0);
+        dequeue(&mut state, 2, 2, now, now + Duration::from_secs(30));
+
+        let lease = state.start_ack("id-0", now).unwrap();
+        assert_eq!(lease.queued.index, 0);
+        assert_eq!(lease.queued.receives, 1);
+        // it is still needed until the ack is committed
+        assert_eq!(state.first_needed(), Some(0));
+        assert!(state.start_ack("id-0", now).is_none());
+
+        state.finish_ack(lease, true);
+        assert_eq!(state.first_needed(), Some(1));
+
+        // an ack that couldn't be written leaves the lease where it was
+        let lease = state.start_ack("id-1", now).unwrap();
+        state.finish_ack(lease, false);
+        assert!(state.start_ack("id-1", now).is_some());
+
+        assert!(state.start_ack("unknown", now).is_none());
+    }
+
+    #[test]
+    fn test_nack_puts_the_message_back_in_order() {
+        let now = Instant::now();
+        let lease = now + Duration::from_secs(30);
+        let mut state = QueueState::new((0..4).map(queued), [], 0);
+        dequeue(&mut state, 2, 4, now, lease);
+
+        assert!(state.nack("id-1", now));
+        assert!(!state.nack("id-1", now));
+        let (dequeued, _) = dequeue(&mut state, 10, 4, now, lease);
+        assert_eq!(ids(&dequeued), vec!["id-1", "id-2", "id-3"]);
+    }
+
+    #[test]
+    fn test_leases_run_out() {
+        let now = Instant::now();
+        let mut state = QueueState::new((0..2).map(queued), [], 0);
+        dequeue(&mut state, 2, 2, now, now + Duration::from_secs(10));
+        assert_eq!(state.len(now), 0);
+
+        // extending one lease keeps that message out of sight after the other comes back
+        let later = now + Duration::from_secs(5);
+        assert!(state.extend_lease("id-1", later, later + Duration::from_secs(10)));
+
+        let after_first_lease = now + Duration::from_secs(11);
+        assert_eq!(state.len(after_first_lease), 1);
+        assert!(state.start_ack("id-0", after_first_lease).is_none());
+        assert!(!state.extend_lease("id-0", after_first_lease, later));
+        let (dequeued, _) = dequeue(
+            &mut state,
+            10,
+            2,
+            after_first_lease,
+            later + Duration::from_secs(60),
+        );
+        assert_eq!(ids(&dequeued), vec!["id-0"]);
+
+        assert!(state.start_ack("id-1", after_first_lease).is_some());
+    }
+
+    #[test]
+    fn test_leases_run_out_by_deadline() {
+        let now = Instant::now();
+        let mut state = QueueState::new((0..4).map(queued), [], 0);
+        for (number, secs) in [(2, 30), (2, 10)] {
+            dequeue(&mut state, number, 4, now, now + Duration::from_secs(secs));
+        }
+
+        // only the two later dequeues have run out, len counts them before they are moved back
+        let later = now + Duration::from_secs(20);
+        assert_eq!(state.len(later), 2);
+        assert_eq!(state.first_needed(), Some(0));
+        let (dequeued, _) = dequeue(&mut state, 10, 4, later, later + Duration::from_secs(60));
+        assert_eq!(ids(&dequeued), vec!["id-2", "id-3"]);
+        assert_eq!(state.len(later), 0);
+    }
+
+    #[test]
+    fn test_messages_received_too_often_are_dead_lettered() {
+        let now = Instant::now();
+        let mut state = QueueState::new((0..3).map(queued), [], 2);
+
+        for _ in 0..2 {
+            let (dequeued, dead_letters) = dequeue(&mut state, 2, 3, now, now);
+            assert_eq!(ids(&dequeued), vec!["id-0", "id-1"]);
+            assert!(dead_letters.is_empty());
+        }
+
+        // the leases ran out straight away, so both have been received twice now
+        let lease = now + Duration::from_secs(30);
+        let (dequeued, dead_letters) = dequeue(&mut state, 2, 3, now, lease);
+        assert_eq!(ids(&dequeued), vec!["id-2"]);
+        assert_eq!(
+            dead_letters.iter().map(|q| q.index).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+        assert_eq!(state.first_needed(), Some(0));
+
+        // if the dead-lettering couldn't be written they are dequeued again and go next time
+        state.finish_dead_letter(dead_letters, false);
+        let (dequeued, dead_letters) = dequeue(&mut state, 3, 3, now, lease);
+        assert!(dequeued.is_empty());
+        assert_eq!(dead_letters.len(), 2);
+
+        state.finish_dead_letter(dead_letters, true);
+        let listed = state.dead_letters(0);
+        assert_eq!(listed.iter().map(|q| q.index).collect::<Vec<_>>(), vec![
+            0, 1
+        ]);
+        assert_eq!(state.dead_letters(1).len(), 1);
+        assert_eq!(state.first_needed(), Some(0));
+    }
+
+    #[test]
+    fn test_redrive_and_purge() {
+        let now = Instant::now();
+        let lease = now + Duration::from_secs(30);
+        let mut state = QueueState::new(
+            [queued(1)],
+            [0, 2, 3].map(|index| Queued {
+                receives: 5,
+                ..queued(index)
+            }),
+            5,
+        );
+
+        let redriven = state.take_dead_letters(&["id-2".to_string(), "unknown".to_string()]);
+        assert_eq!(redriven.len(), 1);
+        state.finish_redrive(redriven, true);
+        let (dequeued, dead_letters) = dequeue(&mut state, 10, 4, now, lease);
+        assert_eq!(ids(&dequeued), vec!["id-1", "id-2"]);
+        assert!(dead_letters.is_empty());
+
+        // a purge that couldn't be written leaves them where they were
+        let purged = state.take_dead_letters(&[]);
+        assert_eq!(purged.len(), 2);
+        assert!(state.dead_letters(0).is_empty());
+        assert_eq!(state.first_needed(), Some(0));
+        state.finish_purge(purged, false);
+        assert_eq!(state.dead_letters(0).len(), 2);
+
+        let purged = state.take_dead_letters(&[]);
+        state.finish_purge(purged, true);
+        assert!(state.dead_letters(0).is_empty());
+        assert_eq!(state.first_needed(), Some(1));
+    }
+}
@@ -1 +1,3 @@
+pub mod segmented;
+#[allow(clippy::module_inception)]
 pub mod wal;
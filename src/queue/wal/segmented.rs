@@ -0,0 +1,432 @@
+use crate::queue::wal::wal::{Durability, FileWal, Wal};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+// segments roll over once they reach this many bytes
+pub const DEFAULT_SEGMENT_BYTES: usize = 64 * 1024 * 1024;
+
+const SEGMENT_EXTENSION: &str = "wal";
+const METADATA_EXTENSION: &str = "meta";
+
+// A WAL split over a directory of segment files, the way Kafka lays out a partition. Each segment is a FileWal named
+// after the index of its first entry, so sorting the names puts them in order. Only the last segment is written to,
+// once it grows past `segment_bytes`, or has `segment_records` entries if that is set, a new one is started. Cleaning
+// deletes whole segments.
+#[derive(Debug)]
+pub struct SegmentedWal {
+    dir: PathBuf,
+    segment_bytes: usize,
+    segment_records: Option<usize>,
+    durability: Durability,
+    // ordered by base index, the last one is the one being written to
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug)]
+struct Segment {
+    base_index: usize,
+    wal: FileWal,
+}
+
+impl Segment {
+    fn open(dir: &Path, base_index: usize, durability: Durability) -> Result<Self, Error> {
+        let wal_path = segment_path(dir, base_index, SEGMENT_EXTENSION);
+        let metadata_path = segment_path(dir, base_index, METADATA_EXTENSION);
+
+        Ok(Segment {
+            base_index,
+            wal: FileWal::new(path_str(&wal_path)?, path_str(&metadata_path)?)?
+                .with_durability(durability),
+        })
+    }
+
+    // the index one past the last entry in this segment
+    fn end_index(&self) -> usize {
+        self.base_index + self.wal.size()
+    }
+}
+
+impl Wal for SegmentedWal {
+    fn write(&mut self, record: &[u8]) -> Result<(), Error> {
+        // an empty segment takes an entry whatever its size, so a big entry can't roll over forever
+        let active = &self.active().wal;
+        let full = active.offset() >= self.segment_bytes
+            || self
+                .segment_records
+                .is_some_and(|records| active.size() >= records);
+        if active.size() > 0 && full {
+            // anything still buffered has to go out before later entries can go into the next segment
+            self.commit()?;
+            let segment = Segment::open(&self.dir, self.active().end_index(), self.durability)?;
+            self.segments.push(segment);
+        }
+
+        self.segments.last_mut().unwrap().wal.write(record)
+    }
+
+    fn read(&self, index: usize) -> Result<Vec<u8>, Error> {
+        let segment = self.segment_for(index)?;
+        segment.wal.read(index - segment.base_index)
+    }
+
+    fn size(&self) -> usize {
+        self.active().end_index()
+    }
+
+    // Deletes the segments that only hold entries before `index`. Entries before it that share a segment with later
+    // ones can still be read until the rest of their segment is cleaned too.
+    fn clean_until(&mut self, index: usize) -> Result<(), Error> {
+        if index > self.size() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Index out of range, WAL is length {}", self.size()),
+            ));
+        }
+
+        while self.segments.len() > 1 && self.segments[1].base_index <= index {
+            let base_index = self.segments.remove(0).base_index;
+            FileWal::remove(
+                path_str(&segment_path(&self.dir, base_index, SEGMENT_EXTENSION))?,
+                path_str(&segment_path(&self.dir, base_index, METADATA_EXTENSION))?,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl SegmentedWal {
+    pub fn open(dir: impl Into<PathBuf>, segment_bytes: usize) -> Result<Self, Error> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let mut base_indices = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+
+            let base_index = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<usize>().ok())
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("unexpected file in the WAL directory: {}", path.display()),
+                    )
+                })?;
+            base_indices.push(base_index);
+        }
+        base_indices.sort_unstable();
+
+        let durability = Durability::default();
+        let mut segments = base_indices
+            .into_iter()
+            .map(|base_index| Segment::open(&dir, base_index, durability))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if segments.is_empty() {
+            segments.push(Segment::open(&dir, 0, durability)?);
+        }
+
+        // each segment has to pick up exactly where the one before it ends
+        for pair in segments.windows(2) {
+            if pair[0].end_index() != pair[1].base_index {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "WAL segment at {} ends at {} but the next one starts at {}",
+                        pair[0].base_index,
+                        pair[0].end_index(),
+                        pair[1].base_index
+                    ),
+                ));
+            }
+        }
+
+        Ok(SegmentedWal {
+            dir,
+            segment_bytes,
+            segment_records: None,
+            durability,
+            segments,
+        })
+    }
+
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self.segments = self
+            .segments
+            .into_iter()
+            .map(|segment| Segment {
+                base_index: segment.base_index,
+                wal: segment.wal.with_durability(durability),
+            })
+            .collect();
+        self
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    // also rolls over once a segment has `segment_records` entries, whichever comes first
+    pub fn with_segment_records(mut self, segment_records: usize) -> Self {
+        self.segment_records = Some(segment_records);
+        self
+    }
+
+    // only the segment being written to can have anything buffered
+    pub fn commit(&mut self) -> Result<(), Error> {
+        self.segments.last_mut().unwrap().wal.commit()
+    }
+
+    // the index one past the last entry that has been committed
+    pub fn committed_size(&self) -> usize {
+        self.active().base_index + self.active().wal.committed_size()
+    }
+
+    // the index of the first entry that can still be read
+    pub fn base_index(&self) -> usize {
+        let first = &self.segments[0];
+        first.base_index + first.wal.base_index()
+    }
+
+    // reads the entries from `index` onwards in order, across however many segments that takes
+    pub fn iter_from(
+        &self,
+        index: usize,
+    ) -> Result<impl Iterator<Item = Result<Vec<u8>, Error>>, Error> {
+        if index < self.base_index() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Entry {} has been cleaned, WAL starts at {}",
+                    index,
+                    self.base_index()
+                ),
+            ));
+        }
+
+        let mut iters = Vec::new();
+        for segment in &self.segments {
+            if segment.end_index() > index {
+                iters.push(
+                    segment
+                        .wal
+                        .iter_from(index.saturating_sub(segment.base_index))?,
+                );
+            }
+        }
+
+        Ok(iters.into_iter().flatten())
+    }
+
+    fn segment_for(&self, index: usize) -> Result<&Segment, Error> {
+        if index >= self.size() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Index out of range, WAL is length {}", self.size()),
+            ));
+        }
+
+        // the last segment starting at or before the index
+        let position = self
+            .segments
+            .partition_point(|segment| segment.base_index <= index);
+        match position {
+            0 => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Entry {} has been cleaned, WAL starts at {}",
+                    index,
+                    self.base_index()
+                ),
+            )),
+            _ => Ok(&self.segments[position - 1]),
+        }
+    }
+
+    fn active(&self) -> &Segment {
+        self.segments.last().unwrap()
+    }
+}
+
+fn segment_path(dir: &Path, base_index: usize, extension: &str) -> PathBuf {
+    dir.join(format!("{:020}.{}", base_index, extension))
+}
+
+fn path_str(path: &Path) -> Result<&str, Error> {
+    path.to_str().ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("WAL path is not valid utf-8: {}", path.display()),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::wal::wal::ENTRY_HEADER_LEN;
+    use tempfile::tempdir;
+
+    // every entry written in these tests is this long once it is encoded
+    const ENTRY_BYTES: usize = 4 + ENTRY_HEADER_LEN;
+
+    fn entry(i: usize) -> Vec<u8> {
+        (i as u32).to_le_bytes().to_vec()
+    }
+
+    fn segment_files(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| {
+                entry.as_ref().unwrap().path().extension().unwrap() == SEGMENT_EXTENSION
+            })
+            .count()
+    }
+
+    #[test]
+    fn test_rollover() {
+        let dir = tempdir().unwrap();
+        let mut wal = SegmentedWal::open(dir.path(), 3 * ENTRY_BYTES).unwrap();
+
+        for i in 0..10 {
+            wal.write(&entry(i)).unwrap();
+        }
+
+        assert_eq!(wal.size(), 10);
+        assert_eq!(wal.segments.len(), 4);
+        assert_eq!(segment_files(dir.path()), 4);
+        for i in 0..10 {
+            assert_eq!(wal.read(i).unwrap(), entry(i));
+        }
+        assert!(wal.read(10).is_err());
+    }
+
+    #[test]
+    fn test_rollover_by_records() {
+        let dir = tempdir().unwrap();
+        let mut wal = SegmentedWal::open(dir.path(), usize::MAX)
+            .unwrap()
+            .with_segment_records(4);
+
+        for i in 0..10 {
+            wal.write(&entry(i)).unwrap();
+        }
+
+        let base_indices: Vec<usize> = wal.segments.iter().map(|s| s.base_index).collect();
+        assert_eq!(base_indices, vec![0, 4, 8]);
+        assert_eq!(segment_files(dir.path()), 3);
+    }
+
+    #[test]
+    fn test_reopen() {
+        let dir = tempdir().unwrap();
+
+        {
+            let mut wal = SegmentedWal::open(dir.path(), 3 * ENTRY_BYTES).unwrap();
+            for i in 0..7 {
+                wal.write(&entry(i)).unwrap();
+            }
+        }
+
+        let mut wal = SegmentedWal::open(dir.path(), 3 * ENTRY_BYTES).unwrap();
+        assert_eq!(wal.size(), 7);
+        wal.write(&entry(7)).unwrap();
+
+        let entries: Vec<Vec<u8>> = wal.iter_from(0).unwrap().map(Result::unwrap).collect();
+        assert_eq!(entries, (0..8).map(entry).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_iter_from() {
+        let dir = tempdir().unwrap();
+        let mut wal = SegmentedWal::open(dir.path(), 4 * ENTRY_BYTES).unwrap();
+        for i in 0..10 {
+            wal.write(&entry(i)).unwrap();
+        }
+
+        for from in 0..=10 {
+            let entries: Vec<Vec<u8>> = wal.iter_from(from).unwrap().map(Result::unwrap).collect();
+            assert_eq!(entries, (from..10).map(entry).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_clean_until_deletes_whole_segments() {
+        let dir = tempdir().unwrap();
+        let mut wal = SegmentedWal::open(dir.path(), 4 * ENTRY_BYTES).unwrap();
+        for i in 0..10 {
+            wal.write(&entry(i)).unwrap();
+        }
+
+        // 6 is in the middle of the second segment, so only the first one goes
+        wal.clean_until(6).unwrap();
+        assert_eq!(wal.base_index(), 4);
+        assert_eq!(segment_files(dir.path()), 2);
+        assert!(wal.read(3).is_err());
+        assert!(wal.iter_from(3).is_err());
+        assert_eq!(wal.read(4).unwrap(), entry(4));
+
+        // the segment being written to stays, even with everything in it cleaned
+        wal.clean_until(10).unwrap();
+        assert_eq!(wal.base_index(), 8);
+        assert!(wal.clean_until(11).is_err());
+
+        drop(wal);
+        let wal = SegmentedWal::open(dir.path(), 4 * ENTRY_BYTES).unwrap();
+        assert_eq!(wal.base_index(), 8);
+        assert_eq!(wal.size(), 10);
+        assert_eq!(wal.read(9).unwrap(), entry(9));
+    }
+
+    #[test]
+    fn test_group_commit_across_segments() {
+        let dir = tempdir().unwrap();
+        let mut wal = SegmentedWal::open(dir.path(), 2 * ENTRY_BYTES)
+            .unwrap()
+            .with_durability(Durability::GroupCommit {
+                window: std::time::Duration::from_secs(1),
+                max_bytes: 100 * ENTRY_BYTES,
+            });
+
+        // buffered entries count towards the segment size, so the third write rolls over and commits the first two
+        for i in 0..3 {
+            wal.write(&entry(i)).unwrap();
+        }
+        assert_eq!(wal.segments.len(), 2);
+        assert_eq!(wal.committed_size(), 2);
+        assert_eq!(wal.read(2).unwrap(), entry(2));
+
+        wal.write(&entry(3)).unwrap();
+        assert_eq!(wal.committed_size(), 2);
+        wal.commit().unwrap();
+        assert_eq!(wal.committed_size(), 4);
+        drop(wal);
+
+        let wal = SegmentedWal::open(dir.path(), 2 * ENTRY_BYTES).unwrap();
+        let entries: Vec<Vec<u8>> = wal.iter_from(0).unwrap().map(Result::unwrap).collect();
+        assert_eq!(entries, (0..4).map(entry).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_missing_segment() {
+        let dir = tempdir().unwrap();
+
+        {
+            let mut wal = SegmentedWal::open(dir.path(), 2 * ENTRY_BYTES).unwrap();
+            for i in 0..6 {
+                wal.write(&entry(i)).unwrap();
+            }
+        }
+        fs::remove_file(segment_path(dir.path(), 2, SEGMENT_EXTENSION)).unwrap();
+
+        let err = SegmentedWal::open(dir.path(), 2 * ENTRY_BYTES).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -1,12 +1,126 @@
-use std::io::{Error, Read, Seek, SeekFrom, Write};
+use std::fmt::{Display, Formatter};
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+// The files a FileWal keeps, every integer in them is little endian:
+//
+//   wal file:      "ZWAL" | format version: u32 | created at, in ms since the unix epoch: u64 | entries
+//   entry:         entry version: u8 | payload length: u64 | crc32c of the version, length and payload: u32 | payload
+//   metadata file: "ZWMD" | format version: u32 | offset: u64 | size: u64 | base index: u64
+//   index file:    where each entry starts, counting from the end of the wal file header: u64 each
+//
+// Files written before there was a header (format versions 0 and 1, which only the metadata tells apart) are rewritten
+// in the current format when they are opened. Anything else FileWal::new doesn't recognize is refused.
+//
+// The metadata is written after the entries it counts, so a crash can leave entries it doesn't know about or the last
+// entry only partly written. FileWal::new checks the entries from the last one the metadata counts onwards and cuts
+// the file off at the first one that is cut short or fails its checksum.
+const WAL_MAGIC: &[u8; 4] = b"ZWAL";
+const METADATA_MAGIC: &[u8; 4] = b"ZWMD";
+const FORMAT_VERSION: u32 = 2;
+const WAL_HEADER_LEN: usize = WAL_MAGIC.len() + 4 + 8;
+const METADATA_LEN: usize = METADATA_MAGIC.len() + 4 + 8 * 3;
+
+const ENTRY_VERSION: u8 = 2;
+pub(crate) const ENTRY_HEADER_LEN: usize = 1 + 8 + 4;
+
+// the offset index sits next to the wal file with this extension
+const INDEX_EXTENSION: &str = "idx";
+
+// how group commit batches writes when it is picked by name rather than built with its own numbers
+pub const DEFAULT_COMMIT_WINDOW: Duration = Duration::from_millis(5);
+pub const DEFAULT_COMMIT_BYTES: usize = 1024 * 1024;
+
+// When the entries written to a FileWal have to be on disk. A write is only safe from a power failure once it has been
+// committed with a sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    // every write is written out and synced before it returns
+    #[default]
+    Always,
+    // Writes are buffered and go out together, with one sync, when FileWal::commit is called. Whoever is writing calls
+    // it once `window` has passed since the first buffered write, a write that takes the buffer past `max_bytes`
+    // commits straight away.
+    GroupCommit {
+        window: Duration,
+        max_bytes: usize,
+    },
+    // every write is written out straight away, but it's left to the OS when that reaches the disk
+    Os,
+}
+
+impl Display for Durability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Durability::Always => write!(f, "always"),
+            Durability::GroupCommit { window, max_bytes } => write!(
+                f,
+                "group commit every {}ms or {} bytes",
+                window.as_millis(),
+                max_bytes
+            ),
+            Durability::Os => write!(f, "os"),
+        }
+    }
+}
+
+impl FromStr for Durability {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Durability::Always),
+            "group" => Ok(Durability::GroupCommit {
+                window: DEFAULT_COMMIT_WINDOW,
+                max_bytes: DEFAULT_COMMIT_BYTES,
+            }),
+            "os" => Ok(Durability::Os),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown durability {}, expected always, group or os", s),
+            )),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct FileWal {
+    wal_path: PathBuf,
     wal_file: std::fs::File,
+    metadata_path: PathBuf,
     metadata_file: std::fs::File,
+    // the byte position of every entry in the file, so reads can seek straight to one
+    index_file: std::fs::File,
+    positions: Vec<u64>,
+    // written but not in the file yet, they go out together on the next commit
     uncommitted: Vec<WalEntry>,
+    durability: Durability,
+    // how many bytes of entries follow the header
     offset: usize,
+    // the index one past the last entry, indices keep counting from here after a clean_until
     size: usize,
+    // the index of the first entry still in the file, everything before it was dropped by clean_until
+    base_index: usize,
+    // ms since the unix epoch, from the wal file header
+    created_at: u64,
+    // what FileWal::new had to fix up at the end of the file, if anything
+    tail_repair: Option<TailRepair>,
+    // set when a failed commit couldn't be undone, the files can't be trusted again until the wal is reopened
+    poisoned: bool,
+}
+
+// What opening a FileWal found at the end of the wal file that the metadata didn't agree with
+#[derive(Debug, Clone, PartialEq)]
+pub struct TailRepair {
+    // bytes cut off the end of the file, from an entry that was cut short or failed its checksum onwards
+    pub dropped_bytes: u64,
+    // the size the metadata had
+    pub metadata_size: usize,
+    // the size going by the entries that are actually there
+    pub recovered_size: usize,
 }
 
 #[derive(Debug)]
@@ -16,19 +130,111 @@ struct WalEntry {
 
 impl WalEntry {
     fn encode(&self) -> Vec<u8> {
-        let payload_len = self.payload.len();
-        let checksum = checksum_xor(&self.payload);
+        let payload_len = self.payload.len() as u64;
 
-        let mut encoded = Vec::with_capacity(std::mem::size_of::<usize>() * 2 + self.payload.len());
-        encoded.extend_from_slice(&payload_len.to_ne_bytes());
-        encoded.extend_from_slice(&checksum.to_ne_bytes());
+        let mut encoded = Vec::with_capacity(self.len());
+        encoded.push(ENTRY_VERSION);
+        encoded.extend_from_slice(&payload_len.to_le_bytes());
+        encoded.extend_from_slice(&entry_checksum(payload_len, &self.payload).to_le_bytes());
         encoded.extend_from_slice(&self.payload);
 
         encoded
     }
 
     fn len(&self) -> usize {
-        std::mem::size_of::<usize>() * 2 + self.payload.len() // usize bytes for payload length, usize bytes for checksum
+        ENTRY_HEADER_LEN + self.payload.len()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Metadata {
+    format_version: u32,
+    offset: usize,
+    size: usize,
+    base_index: usize,
+}
+
+impl Metadata {
+    fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(METADATA_LEN);
+        encoded.extend_from_slice(METADATA_MAGIC);
+        encoded.extend_from_slice(&self.format_version.to_le_bytes());
+        encoded.extend_from_slice(&(self.offset as u64).to_le_bytes());
+        encoded.extend_from_slice(&(self.size as u64).to_le_bytes());
+        encoded.extend_from_slice(&(self.base_index as u64).to_le_bytes());
+
+        encoded
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, Error> {
+        // a wal that is only just being created
+        if buf.is_empty() {
+            return Ok(Metadata {
+                format_version: FORMAT_VERSION,
+                offset: 0,
+                size: 0,
+                base_index: 0,
+            });
+        }
+
+        if buf.starts_with(METADATA_MAGIC) {
+            let format_version = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+            if format_version != FORMAT_VERSION {
+                return Err(unknown_format_version(format_version));
+            }
+            if buf.len() != METADATA_LEN {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "WAL metadata is {} bytes long, expected {}",
+                        buf.len(),
+                        METADATA_LEN
+                    ),
+                ));
+            }
+
+            let field = |i: usize| {
+                let start = 8 + i * 8;
+                u64::from_le_bytes(buf[start..start + 8].try_into().unwrap()) as usize
+            };
+            return Ok(Metadata {
+                format_version,
+                offset: field(0),
+                size: field(1),
+                base_index: field(2),
+            });
+        }
+
+        // Before the header the metadata was the offset, size and later the base index as native usizes, with the
+        // entry version byte on the end once there was one. Their lengths are the only way to recognize them.
+        let field = |i: usize| {
+            let start = i * std::mem::size_of::<usize>();
+            usize::from_ne_bytes(
+                buf[start..start + std::mem::size_of::<usize>()]
+                    .try_into()
+                    .unwrap(),
+            )
+        };
+        let (format_version, base_index) = match buf.len() / std::mem::size_of::<usize>() {
+            2 if buf.len() % std::mem::size_of::<usize>() == 0 => (0, 0),
+            3 if buf.len() % std::mem::size_of::<usize>() == 0 => (0, field(2)),
+            3 if buf.len() % std::mem::size_of::<usize>() == 1 && buf[buf.len() - 1] == 1 => {
+                (1, field(2))
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Not a WAL metadata file",
+                ));
+            }
+        };
+
+        Ok(Metadata {
+            format_version,
+            offset: field(0),
+            size: field(1),
+            base_index,
+        })
     }
 }
 
@@ -39,189 +245,714 @@ pub trait Wal {
 
     fn size(&self) -> usize;
 
-    fn clean_until(&mut self, offset: usize) -> Result<(), Error>;
+    // drops every entry before `index`, the entries after it keep their indices
+    fn clean_until(&mut self, index: usize) -> Result<(), Error>;
 }
 
 impl Wal for FileWal {
     fn write(&mut self, record: &[u8]) -> Result<(), Error> {
+        self.check_not_poisoned()?;
         let entry = WalEntry {
             payload: record.to_vec(),
         };
 
         let entry_len = entry.len();
         self.uncommitted.push(entry);
+        self.positions.push(self.offset as u64);
 
         // Update offset and size before flushing so metadata is correct
         self.offset += entry_len;
-        println!("current offset: {}", self.offset);
         self.size += 1;
 
-        // TODO: This really doesn't do anything, we're just putting it in a queue to clear it...
-        if self.uncommitted.len() > 0 {
-            self.flush()?;
+        match self.durability {
+            Durability::GroupCommit { max_bytes, .. } if self.uncommitted_bytes() < max_bytes => {
+                Ok(())
+            }
+            _ => self.commit(),
         }
-
-        Ok(())
     }
 
     fn read(&self, index: usize) -> Result<Vec<u8>, Error> {
         if index >= self.size {
             return Err(Error::new(
-                std::io::ErrorKind::InvalidInput,
+                ErrorKind::InvalidInput,
                 format!("Index out of range, WAL is length {}", self.size),
             ));
         }
 
-        let mut file = self.wal_file.try_clone()?;
-        file.seek(SeekFrom::Start(0))?;
-
-        // Skip to the desired entry
-        for i in 0..=index {
-            // Read payload length
-            let mut len_buf = [0u8; std::mem::size_of::<usize>()];
-            file.read_exact(&mut len_buf)?;
-            let payload_len = usize::from_ne_bytes(len_buf);
-
-            // Read checksum
-            let mut checksum_buf = [0u8; std::mem::size_of::<usize>()];
-            file.read_exact(&mut checksum_buf)?;
-            let stored_checksum = usize::from_ne_bytes(checksum_buf);
-
-            // If this is the entry we want, read and return it
-            if i == index {
-                let mut payload = vec![0u8; payload_len];
-                file.read_exact(&mut payload)?;
-
-                // Verify checksum
-                let actual_checksum = checksum_xor(&payload);
-                if actual_checksum != stored_checksum {
-                    return Err(Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        "Checksum mismatch",
-                    ));
-                }
-
-                return Ok(payload);
-            }
-
-            // Skip this entry's payload
-            file.seek(SeekFrom::Current(payload_len as i64))?;
+        // entries waiting for a commit aren't in the file yet
+        let committed_size = self.committed_size();
+        if index >= committed_size {
+            return Ok(self.uncommitted[index - committed_size].payload.clone());
         }
 
-        unreachable!()
+        // the offset index says where the entry starts, so there is nothing to skip over
+        self.iter_from(index)?.read_entry()
     }
 
     fn size(&self) -> usize {
         self.size
     }
 
-    fn clean_until(&mut self, offset: usize) -> Result<(), Error> {
-        todo!()
+    fn clean_until(&mut self, index: usize) -> Result<(), Error> {
+        if index > self.size {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Index out of range, WAL is length {}", self.size),
+            ));
+        }
+        if index <= self.base_index {
+            return Ok(());
+        }
+
+        self.commit()?;
+
+        // The header and the entries that stay are copied into a new file, and the metadata that goes with it into
+        // another, before either replaces the old one. Once the wal file has been renamed the clean has happened, if
+        // we crash before the metadata is renamed too FileWal::new finishes it off.
+        let position = self.position_of(index);
+        let wal_temp_path = temp_path(&self.wal_path);
+        let mut temp_file = std::fs::File::create(&wal_temp_path)?;
+        temp_file.write_all(&encode_header(self.created_at))?;
+        let mut source = self.wal_file.try_clone()?;
+        source.seek(SeekFrom::Start(WAL_HEADER_LEN as u64 + position))?;
+        std::io::copy(&mut source, &mut temp_file)?;
+        temp_file.sync_all()?;
+
+        let metadata = Metadata {
+            format_version: FORMAT_VERSION,
+            offset: self.offset - position as usize,
+            size: self.size,
+            base_index: index,
+        };
+        let metadata_temp_path = temp_path(&self.metadata_path);
+        let mut temp_file = std::fs::File::create(&metadata_temp_path)?;
+        temp_file.write_all(&metadata.encode())?;
+        temp_file.sync_all()?;
+
+        std::fs::rename(&wal_temp_path, &self.wal_path)?;
+        self.wal_file = open_wal_file(&self.wal_path)?;
+        std::fs::rename(&metadata_temp_path, &self.metadata_path)?;
+        self.metadata_file = open_metadata_file(&self.metadata_path)?;
+        sync_parent_dir(&self.wal_path)?;
+
+        // the index is swapped the same way, if we crash before that FileWal::new finds it doesn't match and rebuilds it
+        self.positions = self.positions[index - self.base_index..]
+            .iter()
+            .map(|p| p - position)
+            .collect();
+        self.offset = metadata.offset;
+        self.base_index = index;
+        self.write_index_file()
     }
 }
 
 impl FileWal {
     pub fn new(wal_path: &str, metadata_path: &str) -> Result<Self, Error> {
-        let wal_file = std::fs::OpenOptions::new()
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(wal_path)?;
-        let mut metadata_file = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(metadata_path)?;
-
-        // Read offset and size from metadata file if it exists
-        let mut offset_buf = [0u8; std::mem::size_of::<usize>()];
-        let mut size_buf = [0u8; std::mem::size_of::<usize>()];
-
-        let (offset, size) = match metadata_file.read_exact(&mut offset_buf) {
-            Ok(_) => {
-                // Successfully read offset, now try to read size
-                match metadata_file.read_exact(&mut size_buf) {
-                    Ok(_) => (
-                        usize::from_ne_bytes(offset_buf),
-                        usize::from_ne_bytes(size_buf),
-                    ),
-                    Err(_) => (0, 0), // If size is missing, start fresh
-                }
-            }
-            Err(_) => (0, 0), // If file is empty or doesn't have enough data, start at 0
-        };
+        let wal_path = PathBuf::from(wal_path);
+        let metadata_path = PathBuf::from(metadata_path);
+        finish_interrupted_clean(&wal_path, &metadata_path)?;
+
+        let wal_file = open_wal_file(&wal_path)?;
+        let mut index_file = open_wal_file(&wal_path.with_extension(INDEX_EXTENSION))?;
+        let positions = read_positions(&mut index_file)?;
+        let mut metadata_file = open_metadata_file(&metadata_path)?;
+
+        let mut metadata_buf = Vec::new();
+        metadata_file.read_to_end(&mut metadata_buf)?;
+        let metadata = Metadata::decode(&metadata_buf)?;
 
-        Ok(FileWal {
+        let mut wal = FileWal {
+            wal_path,
             wal_file,
+            metadata_path,
             metadata_file,
+            index_file,
+            positions,
             uncommitted: Vec::new(),
-            offset,
-            size,
-        })
+            durability: Durability::default(),
+            offset: metadata.offset,
+            size: metadata.size,
+            base_index: metadata.base_index,
+            created_at: 0,
+            tail_repair: None,
+            poisoned: false,
+        };
+
+        if metadata.format_version != FORMAT_VERSION {
+            wal.migrate(metadata.format_version)?;
+        } else if wal.file_len()? == 0 {
+            if !metadata_buf.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "WAL file is empty but it has metadata",
+                ));
+            }
+
+            wal.created_at = now_millis();
+            wal.wal_file.write_all(&encode_header(wal.created_at))?;
+            wal.wal_file.sync_data()?;
+            wal.write_metadata()?;
+            sync_parent_dir(&wal.wal_path)?;
+        } else {
+            // without metadata this is a wal that crashed while it was being created, it is worked out from the file
+            wal.created_at = read_header(&wal.wal_file)?;
+        }
+
+        wal.recover_tail()?;
+
+        Ok(wal)
     }
 
-    fn flush(&mut self) -> Result<(), Error> {
-        for entry in &self.uncommitted {
-            self.wal_file.write_all(&entry.encode())?;
+    // deletes the files a FileWal opened with the same paths would use, which must not be open any more
+    pub fn remove(wal_path: &str, metadata_path: &str) -> Result<(), Error> {
+        std::fs::remove_file(wal_path)?;
+        std::fs::remove_file(metadata_path)?;
+
+        // older wals might not have had an index yet
+        match std::fs::remove_file(Path::new(wal_path).with_extension(INDEX_EXTENSION)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
         }
-        self.wal_file.flush()?;
+    }
+
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    // the index one past the last entry that has been committed, the entries from here to size are still buffered
+    pub fn committed_size(&self) -> usize {
+        self.size - self.uncommitted.len()
+    }
+
+    // how many bytes of entries are waiting for the next commit
+    pub fn uncommitted_bytes(&self) -> usize {
+        self.uncommitted.iter().map(WalEntry::len).sum()
+    }
+
+    // Writes out every entry buffered since the last commit in one go. Unless the durability leaves it to the OS the
+    // wal file is synced before this returns, the index and metadata aren't, FileWal::new works them out again from
+    // the wal file if they are behind.
+    //
+    // If it fails the buffered entries are dropped and the files are cut back to the last commit, so none of them turn
+    // up later. When even that fails every write and commit is refused until the wal is reopened.
+    pub fn commit(&mut self) -> Result<(), Error> {
+        self.check_not_poisoned()?;
+        if self.uncommitted.is_empty() {
+            return Ok(());
+        }
+
+        let committed_size = self.committed_size();
+        let committed_offset = self.offset - self.uncommitted_bytes();
+        let result = self.write_uncommitted();
         self.uncommitted.clear();
 
-        // Write both offset and size to metadata file
-        self.metadata_file.set_len(0)?;
-        self.metadata_file.seek(SeekFrom::Start(0))?;
+        if result.is_err() {
+            self.positions.truncate(committed_size - self.base_index);
+            self.offset = committed_offset;
+            self.size = committed_size;
+            if self.roll_back().is_err() {
+                self.poisoned = true;
+            }
+        }
 
-        let offset_to_write = &self.offset.to_ne_bytes();
-        let size_to_write = &self.size.to_ne_bytes();
-        println!("size: {:?}, offset: {:?}", offset_to_write, size_to_write);
+        result
+    }
 
-        self.metadata_file.write_all(&self.offset.to_ne_bytes())?;
-        self.metadata_file.write_all(&self.size.to_ne_bytes())?;
+    fn write_uncommitted(&mut self) -> Result<(), Error> {
+        let mut encoded = Vec::with_capacity(self.uncommitted_bytes());
+        for entry in &self.uncommitted {
+            encoded.extend_from_slice(&entry.encode());
+        }
+        self.wal_file.write_all(&encoded)?;
+        if self.durability != Durability::Os {
+            self.wal_file.sync_data()?;
+        }
+
+        // the uncommitted entries are the last ones in the index
+        let uncommitted_positions =
+            &self.positions[self.positions.len() - self.uncommitted.len()..];
+        self.index_file
+            .write_all(&encode_positions(uncommitted_positions))?;
+
+        self.write_metadata()
+    }
+
+    // puts the files back the way the last commit left them, after the offset, size and positions have been
+    fn roll_back(&mut self) -> Result<(), Error> {
+        self.wal_file
+            .set_len(WAL_HEADER_LEN as u64 + self.offset as u64)?;
+        self.write_index_file()?;
+        self.write_metadata()
+    }
+
+    fn check_not_poisoned(&self) -> Result<(), Error> {
+        if self.poisoned {
+            return Err(Error::other(
+                "WAL failed to roll back a commit, it has to be reopened",
+            ));
+        }
+        Ok(())
+    }
+
+    // the index of the first entry that hasn't been cleaned
+    pub fn base_index(&self) -> usize {
+        self.base_index
+    }
+
+    // how many bytes of entries are in the file
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    // when the wal file was first created, a clean_until keeps this the same
+    pub fn created_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.created_at)
+    }
+
+    // what had to be fixed up at the end of the file when it was opened, None when it matched the metadata
+    pub fn tail_repair(&self) -> Option<&TailRepair> {
+        self.tail_repair.as_ref()
+    }
+
+    fn write_metadata(&mut self) -> Result<(), Error> {
+        // Write the offset, size and base index to metadata file. It is always the same length, so it is written over
+        // rather than truncated first, which would leave an empty file if we crashed in between.
+        self.metadata_file.seek(SeekFrom::Start(0))?;
+
+        let metadata = Metadata {
+            format_version: FORMAT_VERSION,
+            offset: self.offset,
+            size: self.size,
+            base_index: self.base_index,
+        };
+        self.metadata_file.write_all(&metadata.encode())?;
         self.metadata_file.flush()?;
         Ok(())
     }
 
+    // Rewrites a wal file from before the header into the current format. A crash after the new file is in place but
+    // before the metadata is rewritten leaves a file that already has the header, only the metadata is left to do.
+    fn migrate(&mut self, format_version: u32) -> Result<(), Error> {
+        let mut magic = [0u8; WAL_MAGIC.len()];
+        let mut file = self.wal_file.try_clone()?;
+        file.seek(SeekFrom::Start(0))?;
+        let migrated_already = file.read_exact(&mut magic).is_ok() && &magic == WAL_MAGIC;
+
+        if !migrated_already {
+            file.seek(SeekFrom::Start(0))?;
+            let mut reader = BufReader::new(file);
+
+            let mut migrated = encode_header(now_millis());
+            for _ in self.base_index..self.size {
+                let payload = match format_version {
+                    0 => read_xor_entry(&mut reader)?,
+                    _ => read_native_entry(&mut reader)?,
+                };
+                migrated.extend_from_slice(&WalEntry { payload }.encode());
+            }
+
+            let temp_path = temp_path(&self.wal_path);
+            let mut temp_file = std::fs::File::create(&temp_path)?;
+            temp_file.write_all(&migrated)?;
+            temp_file.sync_all()?;
+            std::fs::rename(&temp_path, &self.wal_path)?;
+            self.wal_file = open_wal_file(&self.wal_path)?;
+        }
+
+        // the entries are a different length now, so the old index is no use and recover_tail works it out again
+        self.created_at = read_header(&self.wal_file)?;
+        self.offset = self.file_len()? - WAL_HEADER_LEN;
+        self.positions.clear();
+        self.write_metadata()
+    }
+
+    // Finds where the entries really end. The ones before the last entry the metadata counts are trusted, as long as
+    // the index agrees with the metadata, and every entry from there on has its length and checksum checked. The file
+    // is cut off at the first one that fails, then the metadata and index are rewritten to match what is left.
+    fn recover_tail(&mut self) -> Result<(), Error> {
+        let entries_len = (self.file_len()? - WAL_HEADER_LEN) as u64;
+        let trusted = if self.index_matches() && self.offset as u64 <= entries_len {
+            self.positions.len().saturating_sub(1)
+        } else {
+            0
+        };
+
+        let mut positions = self.positions[..trusted].to_vec();
+        let mut position = if trusted > 0 {
+            self.positions[trusted]
+        } else {
+            0
+        };
+
+        let mut iter = self.iter_file()?;
+        iter.reader.seek_relative(position as i64)?;
+        while position < entries_len && iter.read_entry().is_ok() {
+            positions.push(position);
+            position = iter.reader.stream_position()? - WAL_HEADER_LEN as u64;
+        }
+
+        let size = self.base_index + positions.len();
+        if position != entries_len || position != self.offset as u64 || size != self.size {
+            let repair = TailRepair {
+                dropped_bytes: entries_len - position,
+                metadata_size: self.size,
+                recovered_size: size,
+            };
+            warn!(
+                "WAL {} didn't match its metadata, dropped {} bytes from the end and went from {} entries to {}",
+                self.wal_path.display(),
+                repair.dropped_bytes,
+                repair.metadata_size,
+                repair.recovered_size
+            );
+
+            self.wal_file.set_len(WAL_HEADER_LEN as u64 + position)?;
+            self.offset = position as usize;
+            self.size = size;
+            self.tail_repair = Some(repair);
+            self.write_metadata()?;
+        }
+
+        // files from before the offset index, a crash part way through writing it, or entries that were dropped
+        if positions != self.positions {
+            self.positions = positions;
+            self.write_index_file()?;
+        }
+
+        Ok(())
+    }
+
+    // reads the entries from `index` onwards in order, without going back to the start of the file for each one
+    pub fn iter_from(&self, index: usize) -> Result<WalIter, Error> {
+        self.check_not_cleaned(index)?;
+
+        // the committed entries are read from the file, then any that are still buffered
+        let index = index.min(self.size);
+        let committed_size = self.committed_size();
+        let from_file = index.min(committed_size);
+        let mut iter = self.iter_file()?;
+        iter.reader
+            .seek_relative(self.position_of(from_file) as i64)?;
+        iter.remaining = committed_size - from_file;
+        iter.buffered = self.uncommitted[index.max(committed_size) - committed_size..]
+            .iter()
+            .map(|entry| entry.payload.clone())
+            .collect::<Vec<_>>()
+            .into_iter();
+
+        Ok(iter)
+    }
+
+    // an iterator over everything still in the file
+    fn iter_file(&self) -> Result<WalIter, Error> {
+        let mut file = self.wal_file.try_clone()?;
+        file.seek(SeekFrom::Start(WAL_HEADER_LEN as u64))?;
+
+        Ok(WalIter {
+            reader: BufReader::new(file),
+            remaining: self.committed_size() - self.base_index,
+            buffered: Vec::new().into_iter(),
+        })
+    }
+
+    fn file_len(&self) -> Result<usize, Error> {
+        Ok(self.wal_file.metadata()?.len() as usize)
+    }
+
+    // where the entry at `index` starts after the header, the end of the file for `size`
+    fn position_of(&self, index: usize) -> u64 {
+        match self.positions.get(index - self.base_index) {
+            Some(position) => *position,
+            None => self.offset as u64,
+        }
+    }
+
+    fn index_matches(&self) -> bool {
+        self.positions.len() == self.size - self.base_index
+            && self.positions.first().is_none_or(|first| *first == 0)
+            && self
+                .positions
+                .last()
+                .is_none_or(|last| *last < self.offset as u64)
+    }
+
+    // replaces the whole index file with `positions`
+    fn write_index_file(&mut self) -> Result<(), Error> {
+        let index_path = self.wal_path.with_extension(INDEX_EXTENSION);
+        let temp_path = temp_path(&index_path);
+
+        let mut temp_file = std::fs::File::create(&temp_path)?;
+        temp_file.write_all(&encode_positions(&self.positions))?;
+        temp_file.sync_all()?;
+        std::fs::rename(&temp_path, &index_path)?;
+
+        self.index_file = open_wal_file(&index_path)?;
+        Ok(())
+    }
+
+    fn check_not_cleaned(&self, index: usize) -> Result<(), Error> {
+        if index < self.base_index {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Entry {} has been cleaned, WAL starts at {}",
+                    index, self.base_index
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[cfg(test)]
     fn as_vec(&mut self) -> Result<Vec<WalEntry>, Error> {
-        let mut vec = Vec::with_capacity(self.size);
+        self.iter_file()?
+            .map(|payload| payload.map(|payload| WalEntry { payload }))
+            .collect()
+    }
+}
 
-        self.wal_file.seek(SeekFrom::Start(0))?;
+pub struct WalIter {
+    reader: BufReader<std::fs::File>,
+    // how many entries are left to read from the file
+    remaining: usize,
+    // entries that haven't been committed yet, which come after the ones in the file
+    buffered: std::vec::IntoIter<Vec<u8>>,
+}
 
-        let mut whole_file = Vec::new();
-        self.wal_file.read_to_end(&mut whole_file)?;
-        self.wal_file.seek(SeekFrom::Start(0))?;
+impl WalIter {
+    fn read_header(&mut self) -> Result<(u64, u32), Error> {
+        let mut version_buf = [0u8; 1];
+        self.reader.read_exact(&mut version_buf)?;
+        if version_buf[0] != ENTRY_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown WAL entry version {}", version_buf[0]),
+            ));
+        }
 
-        for _ in 0..self.size {
-            let mut len_buf = [0u8; std::mem::size_of::<usize>()];
+        let mut len_buf = [0u8; 8];
+        self.reader.read_exact(&mut len_buf)?;
 
-            self.wal_file.read_exact(&mut len_buf)?;
-            let payload_len = usize::from_ne_bytes(len_buf);
+        let mut checksum_buf = [0u8; 4];
+        self.reader.read_exact(&mut checksum_buf)?;
 
-            let mut checksum_buf = [0u8; std::mem::size_of::<usize>()];
-            self.wal_file.read_exact(&mut checksum_buf)?;
-            let checksum = usize::from_ne_bytes(checksum_buf);
+        Ok((
+            u64::from_le_bytes(len_buf),
+            u32::from_le_bytes(checksum_buf),
+        ))
+    }
 
-            let mut payload_buf = vec![0u8; payload_len];
-            self.wal_file.read_exact(&mut payload_buf)?;
+    fn read_entry(&mut self) -> Result<Vec<u8>, Error> {
+        let (payload_len, stored_checksum) = self.read_header()?;
+        let payload = read_payload(&mut self.reader, payload_len)?;
 
-            let actual_checksum = checksum_xor(&payload_buf);
+        // the length is covered by the checksum too, so a bad one is caught here rather than misreading what follows
+        if entry_checksum(payload_len, &payload) != stored_checksum {
+            return Err(Error::new(ErrorKind::InvalidData, "Checksum mismatch"));
+        }
 
-            if actual_checksum != checksum {
-                return Err(Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Checksum mismatch",
-                ));
-            }
+        Ok(payload)
+    }
+}
 
-            vec.push(WalEntry {
-                payload: payload_buf,
-            });
+impl Iterator for WalIter {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return self.buffered.next().map(Ok);
         }
 
-        let result = Ok(vec);
-        result
+        self.remaining -= 1;
+        let entry = self.read_entry();
+        // there is no telling where the next entry starts after a bad one
+        if entry.is_err() {
+            self.remaining = 0;
+            self.buffered = Vec::new().into_iter();
+        }
+
+        Some(entry)
     }
 }
 
+fn open_wal_file(wal_path: &Path) -> Result<std::fs::File, Error> {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(wal_path)
+}
+
+fn open_metadata_file(metadata_path: &Path) -> Result<std::fs::File, Error> {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(metadata_path)
+}
+
+// a new or renamed file only survives a crash once the directory it is in has been synced too
+fn sync_parent_dir(path: &Path) -> Result<(), Error> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => std::fs::File::open(parent)?.sync_all(),
+        _ => std::fs::File::open(".")?.sync_all(),
+    }
+}
+
+// where a file is written before it is renamed over `path`
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp_path = path.to_path_buf().into_os_string();
+    temp_path.push(".tmp");
+    PathBuf::from(temp_path)
+}
+
+// clean_until writes the cleaned wal file and its metadata next to the old ones, then renames the wal file and then the
+// metadata into place
+fn finish_interrupted_clean(wal_path: &Path, metadata_path: &Path) -> Result<(), Error> {
+    let wal_temp_path = temp_path(wal_path);
+    let metadata_temp_path = temp_path(metadata_path);
+
+    if wal_temp_path.exists() {
+        // it never got as far as replacing the wal file, the old files still go together
+        std::fs::remove_file(&wal_temp_path)?;
+        if metadata_temp_path.exists() {
+            std::fs::remove_file(&metadata_temp_path)?;
+        }
+    } else if metadata_temp_path.exists() {
+        // the wal file is the cleaned one already, so its metadata has to be too
+        std::fs::rename(&metadata_temp_path, metadata_path)?;
+    }
+
+    Ok(())
+}
+
+fn encode_header(created_at: u64) -> Vec<u8> {
+    let mut header = Vec::with_capacity(WAL_HEADER_LEN);
+    header.extend_from_slice(WAL_MAGIC);
+    header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header.extend_from_slice(&created_at.to_le_bytes());
+
+    header
+}
+
+// checks the wal file header, returning when the file was created
+fn read_header(wal_file: &std::fs::File) -> Result<u64, Error> {
+    let mut file = wal_file.try_clone()?;
+    file.seek(SeekFrom::Start(0))?;
+
+    let mut header = [0u8; WAL_HEADER_LEN];
+    if file.read_exact(&mut header).is_err() || &header[..4] != WAL_MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "Not a WAL file"));
+    }
+
+    let format_version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    if format_version != FORMAT_VERSION {
+        return Err(unknown_format_version(format_version));
+    }
+
+    Ok(u64::from_le_bytes(header[8..16].try_into().unwrap()))
+}
+
+fn unknown_format_version(format_version: u32) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Unknown WAL format version {}", format_version),
+    )
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn read_positions(index_file: &mut std::fs::File) -> Result<Vec<u64>, Error> {
+    let mut buf = Vec::new();
+    index_file.seek(SeekFrom::Start(0))?;
+    index_file.read_to_end(&mut buf)?;
+
+    // a position that was only partly written is left off, which makes the index not match and get rebuilt
+    Ok(buf
+        .chunks_exact(std::mem::size_of::<u64>())
+        .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+        .collect())
+}
+
+fn encode_positions(positions: &[u64]) -> Vec<u8> {
+    positions.iter().flat_map(|p| p.to_le_bytes()).collect()
+}
+
+// The payload is read in pieces rather than into a buffer of `payload_len` up front, a corrupted length could be
+// anything.
+fn read_payload(reader: &mut impl Read, payload_len: u64) -> Result<Vec<u8>, Error> {
+    let mut payload = Vec::new();
+    reader.take(payload_len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != payload_len {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "WAL entry is cut short",
+        ));
+    }
+
+    Ok(payload)
+}
+
+fn entry_checksum(payload_len: u64, payload: &[u8]) -> u32 {
+    let mut header = vec![ENTRY_VERSION];
+    header.extend_from_slice(&payload_len.to_le_bytes());
+    crc32c::crc32c_append(crc32c::crc32c(&header), payload)
+}
+
+// An entry from format version 1: a version byte of 1, the payload length as a native usize, a crc32c of those and the
+// payload, then the payload.
+fn read_native_entry(reader: &mut impl Read) -> Result<Vec<u8>, Error> {
+    let mut header = [0u8; 1 + std::mem::size_of::<usize>()];
+    reader.read_exact(&mut header)?;
+    if header[0] != 1 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Unknown WAL entry version {}", header[0]),
+        ));
+    }
+
+    let mut checksum_buf = [0u8; 4];
+    reader.read_exact(&mut checksum_buf)?;
+
+    let payload_len = usize::from_ne_bytes(header[1..].try_into().unwrap());
+    let payload = read_payload(reader, payload_len as u64)?;
+    if crc32c::crc32c_append(crc32c::crc32c(&header), &payload) != u32::from_le_bytes(checksum_buf)
+    {
+        return Err(Error::new(ErrorKind::InvalidData, "Checksum mismatch"));
+    }
+
+    Ok(payload)
+}
+
+// an entry from format version 0: the payload length, an xor of the payload's bytes and then the payload
+fn read_xor_entry(reader: &mut impl Read) -> Result<Vec<u8>, Error> {
+    let mut len_buf = [0u8; std::mem::size_of::<usize>()];
+    reader.read_exact(&mut len_buf)?;
+
+    let mut checksum_buf = [0u8; std::mem::size_of::<usize>()];
+    reader.read_exact(&mut checksum_buf)?;
+
+    let payload = read_payload(reader, usize::from_ne_bytes(len_buf) as u64)?;
+    if checksum_xor(&payload) != usize::from_ne_bytes(checksum_buf) {
+        return Err(Error::new(ErrorKind::InvalidData, "Checksum mismatch"));
+    }
+
+    Ok(payload)
+}
+
 fn checksum_xor(bytes: &[u8]) -> usize {
     bytes.iter().fold(0u8, |acc, &b| acc ^ b) as usize
 }
@@ -230,22 +961,28 @@ fn checksum_xor(bytes: &[u8]) -> usize {
 mod tests {
     use super::*;
     use bytes::Bytes;
-    use tempfile::tempfile;
+    use tempfile::{TempDir, tempdir};
+
+    fn temp_wal() -> (TempDir, FileWal) {
+        let dir = tempdir().unwrap();
+        let wal = open_wal(&dir);
+        (dir, wal)
+    }
+
+    fn open_wal(dir: &TempDir) -> FileWal {
+        let wal_path = dir.path().join("test.wal");
+        let metadata_path = dir.path().join("test.meta");
+        FileWal::new(wal_path.to_str().unwrap(), metadata_path.to_str().unwrap()).unwrap()
+    }
 
     #[test]
     fn test_simple_write() {
-        let mut wal = FileWal {
-            wal_file: tempfile().unwrap(),
-            metadata_file: tempfile().unwrap(),
-            uncommitted: Vec::new(),
-            offset: 0,
-            size: 0,
-        };
+        let (_dir, mut wal) = temp_wal();
 
         let data = "some data goes here 100";
         wal.write(data.as_bytes()).unwrap();
         assert_eq!(wal.size(), 1);
-        assert_eq!(wal.offset, data.len() + std::mem::size_of::<usize>() * 2);
+        assert_eq!(wal.offset, data.len() + ENTRY_HEADER_LEN);
         assert_eq!(wal.read(0).unwrap(), b"some data goes here 100");
     }
 
@@ -253,13 +990,7 @@ mod tests {
     fn test_read_at_offset() {
         let data1 = "first entry";
         let data2 = "second entry";
-        let mut wal = FileWal {
-            wal_file: tempfile().unwrap(),
-            metadata_file: tempfile().unwrap(),
-            uncommitted: Vec::new(),
-            offset: 0,
-            size: 0,
-        };
+        let (_dir, mut wal) = temp_wal();
 
         wal.write(data1.as_bytes()).unwrap();
         wal.write(data2.as_bytes()).unwrap();
@@ -270,20 +1001,14 @@ mod tests {
 
     #[test]
     fn test_as_vec() {
-        let mut wal = FileWal {
-            wal_file: tempfile().unwrap(),
-            metadata_file: tempfile().unwrap(),
-            uncommitted: Vec::new(),
-            offset: 0,
-            size: 0,
-        };
+        let (_dir, mut wal) = temp_wal();
 
         let data1 = "first entry";
         let data2 = "second entry";
 
         wal.write(data1.as_bytes()).unwrap();
         wal.write(data2.as_bytes()).unwrap();
-        wal.flush().unwrap();
+        wal.commit().unwrap();
 
         let entries = wal.as_vec().unwrap();
         assert_eq!(entries.len(), 2);
@@ -291,6 +1016,25 @@ mod tests {
         assert_eq!(entries[1].payload, Bytes::from(data2));
     }
 
+    #[test]
+    fn test_iter_from() {
+        let (_dir, mut wal) = temp_wal();
+
+        let data = ["first entry", "second entry", "third entry"];
+        for entry in data {
+            wal.write(entry.as_bytes()).unwrap();
+        }
+
+        for start in 0..=data.len() {
+            let entries: Vec<Vec<u8>> = wal.iter_from(start).unwrap().map(Result::unwrap).collect();
+            let expected: Vec<Vec<u8>> = data[start..]
+                .iter()
+                .map(|d| d.as_bytes().to_vec())
+                .collect();
+            assert_eq!(entries, expected);
+        }
+    }
+
     #[test]
     fn test_metadata_file_persists_state() {
         use std::io::Read;
@@ -300,11 +1044,20 @@ mod tests {
         let metadata_file = NamedTempFile::new().unwrap();
 
         let mut wal = FileWal {
+            wal_path: wal_file.path().to_path_buf(),
             wal_file: wal_file.reopen().unwrap(),
+            metadata_path: metadata_file.path().to_path_buf(),
             metadata_file: metadata_file.reopen().unwrap(),
+            index_file: tempfile::tempfile().unwrap(),
+            positions: Vec::new(),
             uncommitted: Vec::new(),
+            durability: Durability::Always,
             offset: 0,
             size: 0,
+            base_index: 0,
+            created_at: 0,
+            tail_repair: None,
+            poisoned: false,
         };
 
         let data1 = "first entry";
@@ -313,21 +1066,25 @@ mod tests {
         wal.write(data1.as_bytes()).unwrap();
         wal.write(data2.as_bytes()).unwrap();
 
-        // Expected offset: (data1.len() + 16) + (data2.len() + 16)
-        let expected_offset = (data1.len() + std::mem::size_of::<usize>() * 2)
-            + (data2.len() + std::mem::size_of::<usize>() * 2);
+        // Expected offset: (data1.len() + ENTRY_HEADER_LEN) + (data2.len() + ENTRY_HEADER_LEN)
+        let expected_offset = (data1.len() + ENTRY_HEADER_LEN) + (data2.len() + ENTRY_HEADER_LEN);
         let expected_size = 2;
 
-        // Read metadata file and verify it contains correct offset and size
+        // Read metadata file and verify it contains correct offset and size, after the magic and format version
         let mut meta_file = metadata_file.reopen().unwrap();
-        let mut offset_buf = [0u8; std::mem::size_of::<usize>()];
-        let mut size_buf = [0u8; std::mem::size_of::<usize>()];
+        let mut header_buf = [0u8; 8];
+        let mut offset_buf = [0u8; 8];
+        let mut size_buf = [0u8; 8];
 
+        meta_file.read_exact(&mut header_buf).unwrap();
         meta_file.read_exact(&mut offset_buf).unwrap();
         meta_file.read_exact(&mut size_buf).unwrap();
 
-        let stored_offset = usize::from_ne_bytes(offset_buf);
-        let stored_size = usize::from_ne_bytes(size_buf);
+        let stored_offset = u64::from_le_bytes(offset_buf) as usize;
+        let stored_size = u64::from_le_bytes(size_buf) as usize;
+
+        assert_eq!(&header_buf[..4], METADATA_MAGIC);
+        assert_eq!(header_buf[4..], FORMAT_VERSION.to_le_bytes());
 
         assert_eq!(stored_offset, expected_offset);
         assert_eq!(stored_size, expected_size);
@@ -337,8 +1094,6 @@ mod tests {
 
     #[test]
     fn test_wal_recovery() {
-        use tempfile::tempdir;
-
         let dir = tempdir().unwrap();
         let wal_path = dir.path().join("test.wal");
         let metadata_path = dir.path().join("test.meta");
@@ -352,19 +1107,19 @@ mod tests {
 
         // Create initial WAL and write data
         {
-            let mut wal = FileWal::new(&wal_path_str, &metadata_path_str).unwrap();
+            let mut wal = FileWal::new(wal_path_str, metadata_path_str).unwrap();
             wal.write(data1.as_bytes()).unwrap();
             wal.write(data2.as_bytes()).unwrap();
             wal.write(data3.as_bytes()).unwrap();
         } // WAL dropped here, files should persist
 
         // Create new WAL instance with same files
-        let wal = FileWal::new(&wal_path_str, &metadata_path_str).unwrap();
+        let wal = FileWal::new(wal_path_str, metadata_path_str).unwrap();
 
         // Verify offset and size were restored
-        let expected_offset = (data1.len() + std::mem::size_of::<usize>() * 2)
-            + (data2.len() + std::mem::size_of::<usize>() * 2)
-            + (data3.len() + std::mem::size_of::<usize>() * 2);
+        let expected_offset = (data1.len() + ENTRY_HEADER_LEN)
+            + (data2.len() + ENTRY_HEADER_LEN)
+            + (data3.len() + ENTRY_HEADER_LEN);
         assert_eq!(wal.offset, expected_offset);
         assert_eq!(wal.size, 3);
 
@@ -373,4 +1128,573 @@ mod tests {
         assert_eq!(wal.read(1).unwrap(), data2.as_bytes());
         assert_eq!(wal.read(2).unwrap(), data3.as_bytes());
     }
+
+    #[test]
+    fn test_clean_until() {
+        let (dir, mut wal) = temp_wal();
+        for i in 0..10u8 {
+            wal.write(&[i; 3]).unwrap();
+        }
+
+        wal.clean_until(4).unwrap();
+        assert_eq!(wal.base_index(), 4);
+        assert_eq!(wal.size(), 10);
+        assert_eq!(wal.offset, 6 * (3 + ENTRY_HEADER_LEN));
+        assert!(wal.read(3).is_err());
+        assert_eq!(wal.read(4).unwrap(), vec![4; 3]);
+        assert_eq!(wal.read(9).unwrap(), vec![9; 3]);
+
+        // cleaning up to somewhere already cleaned does nothing
+        wal.clean_until(2).unwrap();
+        assert_eq!(wal.base_index(), 4);
+        assert!(wal.clean_until(11).is_err());
+
+        // writes after cleaning carry on from the same index
+        wal.write(&[10; 3]).unwrap();
+        assert_eq!(wal.read(10).unwrap(), vec![10; 3]);
+        let entries: Vec<Vec<u8>> = wal.iter_from(8).unwrap().map(Result::unwrap).collect();
+        assert_eq!(entries, vec![vec![8; 3], vec![9; 3], vec![10; 3]]);
+        assert!(wal.iter_from(3).is_err());
+        drop(wal);
+
+        let mut wal = open_wal(&dir);
+        assert_eq!(wal.base_index(), 4);
+        assert_eq!(wal.size(), 11);
+        assert_eq!(wal.read(4).unwrap(), vec![4; 3]);
+
+        // cleaning everything leaves an empty file that still knows where it is up to
+        wal.clean_until(11).unwrap();
+        assert_eq!(wal.offset, 0);
+        wal.write(&[11; 3]).unwrap();
+        assert_eq!(wal.read(11).unwrap(), vec![11; 3]);
+    }
+
+    #[test]
+    fn test_interrupted_clean_until() {
+        let (dir, mut wal) = temp_wal();
+        for i in 0..5u8 {
+            wal.write(&[i; 3]).unwrap();
+        }
+        drop(wal);
+
+        let entry_len = 3 + ENTRY_HEADER_LEN;
+        let wal_path = dir.path().join("test.wal");
+        let metadata_path = dir.path().join("test.meta");
+        let wal_bytes = std::fs::read(&wal_path).unwrap();
+        let cleaned = [
+            &wal_bytes[..WAL_HEADER_LEN],
+            &wal_bytes[WAL_HEADER_LEN + 2 * entry_len..],
+        ]
+        .concat();
+        let cleaned_metadata = Metadata {
+            format_version: FORMAT_VERSION,
+            offset: 3 * entry_len,
+            size: 5,
+            base_index: 2,
+        }
+        .encode();
+
+        // both new files were written but neither was renamed, so the clean didn't happen
+        std::fs::write(temp_path(&wal_path), &cleaned).unwrap();
+        std::fs::write(temp_path(&metadata_path), &cleaned_metadata).unwrap();
+        let wal = open_wal(&dir);
+        assert_eq!(wal.base_index(), 0);
+        assert_eq!(wal.size(), 5);
+        assert_eq!(wal.read(0).unwrap(), vec![0; 3]);
+        assert!(!temp_path(&wal_path).exists());
+        assert!(!temp_path(&metadata_path).exists());
+        drop(wal);
+
+        // the wal file has been swapped for the cleaned one, but the metadata is still from before
+        std::fs::write(&wal_path, &cleaned).unwrap();
+        std::fs::write(temp_path(&metadata_path), &cleaned_metadata).unwrap();
+        let wal = open_wal(&dir);
+        assert_eq!(wal.base_index(), 2);
+        assert_eq!(wal.size(), 5);
+        assert_eq!(wal.offset, 3 * entry_len);
+        assert_eq!(wal.tail_repair(), None);
+        assert_eq!(wal.read(2).unwrap(), vec![2; 3]);
+        assert_eq!(wal.read(4).unwrap(), vec![4; 3]);
+    }
+
+    #[test]
+    fn test_torn_last_entry_is_dropped() {
+        let (dir, mut wal) = temp_wal();
+        for i in 0..3u8 {
+            wal.write(&[i; 3]).unwrap();
+        }
+        drop(wal);
+
+        // the last entry only got partly written before a crash
+        let entry_len = 3 + ENTRY_HEADER_LEN;
+        let wal_path = dir.path().join("test.wal");
+        let wal_bytes = std::fs::read(&wal_path).unwrap();
+        std::fs::write(&wal_path, &wal_bytes[..wal_bytes.len() - 2]).unwrap();
+
+        let mut wal = open_wal(&dir);
+        assert_eq!(
+            wal.tail_repair(),
+            Some(&TailRepair {
+                dropped_bytes: (entry_len - 2) as u64,
+                metadata_size: 3,
+                recovered_size: 2,
+            })
+        );
+        assert_eq!(wal.size(), 2);
+        assert_eq!(wal.offset, 2 * entry_len);
+        assert_eq!(
+            std::fs::metadata(&wal_path).unwrap().len(),
+            (WAL_HEADER_LEN + 2 * entry_len) as u64
+        );
+
+        // the next write takes the place of the one that was lost
+        wal.write(&[9; 3]).unwrap();
+        drop(wal);
+        let wal = open_wal(&dir);
+        assert_eq!(wal.tail_repair(), None);
+        assert_eq!(wal.read(1).unwrap(), vec![1; 3]);
+        assert_eq!(wal.read(2).unwrap(), vec![9; 3]);
+    }
+
+    #[test]
+    fn test_corrupted_last_entry_is_dropped() {
+        let (dir, mut wal) = temp_wal();
+        for i in 0..3u8 {
+            wal.write(&[i; 3]).unwrap();
+        }
+        drop(wal);
+
+        let wal_path = dir.path().join("test.wal");
+        let mut wal_bytes = std::fs::read(&wal_path).unwrap();
+        let last = wal_bytes.len() - 1;
+        wal_bytes[last] ^= 1;
+        std::fs::write(&wal_path, &wal_bytes).unwrap();
+
+        let wal = open_wal(&dir);
+        assert_eq!(wal.size(), 2);
+        assert_eq!(
+            wal.tail_repair().unwrap().dropped_bytes,
+            (3 + ENTRY_HEADER_LEN) as u64
+        );
+        assert!(wal.read(2).is_err());
+        assert_eq!(wal.read(1).unwrap(), vec![1; 3]);
+    }
+
+    #[test]
+    fn test_entries_after_the_metadata_are_kept() {
+        let (dir, mut wal) = temp_wal();
+        wal.write(&[0; 3]).unwrap();
+        let metadata_path = dir.path().join("test.meta");
+        let index_path = dir.path().join("test.idx");
+        let metadata = std::fs::read(&metadata_path).unwrap();
+        let index = std::fs::read(&index_path).unwrap();
+
+        // the entries reached the file, but we crashed before the index and metadata caught up
+        wal.write(&[1; 3]).unwrap();
+        wal.write(&[2; 3]).unwrap();
+        drop(wal);
+        std::fs::write(&metadata_path, &metadata).unwrap();
+        std::fs::write(&index_path, &index).unwrap();
+
+        let wal = open_wal(&dir);
+        assert_eq!(
+            wal.tail_repair(),
+            Some(&TailRepair {
+                dropped_bytes: 0,
+                metadata_size: 1,
+                recovered_size: 3,
+            })
+        );
+        assert_eq!(wal.size(), 3);
+        assert_eq!(wal.offset, 3 * (3 + ENTRY_HEADER_LEN));
+        assert_eq!(wal.positions.len(), 3);
+        assert_eq!(wal.read(2).unwrap(), vec![2; 3]);
+        drop(wal);
+
+        let metadata = Metadata::decode(&std::fs::read(&metadata_path).unwrap()).unwrap();
+        assert_eq!(metadata.size, 3);
+    }
+
+    #[test]
+    fn test_missing_metadata_is_rebuilt() {
+        let (dir, mut wal) = temp_wal();
+        wal.write(b"first entry").unwrap();
+        wal.write(b"second entry").unwrap();
+        drop(wal);
+
+        // a crash after writing the header of a new wal, before there was any metadata
+        std::fs::write(dir.path().join("test.meta"), b"").unwrap();
+        let wal = open_wal(&dir);
+        assert_eq!(wal.size(), 2);
+        assert_eq!(wal.tail_repair().unwrap().metadata_size, 0);
+        assert_eq!(wal.read(1).unwrap(), b"second entry");
+    }
+
+    #[test]
+    fn test_offset_index() {
+        let (dir, mut wal) = temp_wal();
+        let entry_len = 3 + ENTRY_HEADER_LEN;
+        for i in 0..5u8 {
+            wal.write(&[i; 3]).unwrap();
+        }
+
+        let positions: Vec<u64> = (0..5).map(|i| (i * entry_len) as u64).collect();
+        assert_eq!(wal.positions, positions);
+        drop(wal);
+
+        // the index is read back rather than worked out again
+        let index_path = dir.path().join("test.idx");
+        assert_eq!(
+            std::fs::read(&index_path).unwrap(),
+            encode_positions(&positions)
+        );
+        let wal = open_wal(&dir);
+        assert_eq!(wal.positions, positions);
+        assert_eq!(wal.read(3).unwrap(), vec![3; 3]);
+
+        // after cleaning the positions are relative to the new start of the file
+        let mut wal = wal;
+        wal.clean_until(2).unwrap();
+        assert_eq!(wal.positions, positions[..3].to_vec());
+        assert_eq!(wal.read(2).unwrap(), vec![2; 3]);
+        assert_eq!(wal.read(4).unwrap(), vec![4; 3]);
+    }
+
+    #[test]
+    fn test_offset_index_is_rebuilt() {
+        let (dir, mut wal) = temp_wal();
+        for i in 0..5u8 {
+            wal.write(&[i; 3]).unwrap();
+        }
+        let positions = wal.positions.clone();
+        drop(wal);
+
+        // an index that is missing or half written gets worked out from the wal file
+        let index_path = dir.path().join("test.idx");
+        std::fs::remove_file(&index_path).unwrap();
+        let wal = open_wal(&dir);
+        assert_eq!(wal.positions, positions);
+        drop(wal);
+
+        let index = std::fs::read(&index_path).unwrap();
+        std::fs::write(&index_path, &index[..index.len() - 3]).unwrap();
+        let wal = open_wal(&dir);
+        assert_eq!(wal.positions, positions);
+        for i in 0..5u8 {
+            assert_eq!(wal.read(i as usize).unwrap(), vec![i; 3]);
+        }
+    }
+
+    #[test]
+    fn test_corrupted_entries_fail_the_checksum() {
+        let (dir, mut wal) = temp_wal();
+        wal.write(b"first entry").unwrap();
+        wal.write(b"second entry").unwrap();
+        drop(wal);
+
+        let wal_path = dir.path().join("test.wal");
+        let original = std::fs::read(&wal_path).unwrap();
+
+        // two flips in the same bit position cancel out in an xor, but not in a CRC
+        let payload = WAL_HEADER_LEN + ENTRY_HEADER_LEN;
+        let mut corrupted = original.clone();
+        corrupted[payload] ^= 0b100;
+        corrupted[payload + 1] ^= 0b100;
+        std::fs::write(&wal_path, &corrupted).unwrap();
+        let wal = open_wal(&dir);
+        assert_eq!(
+            wal.read(0).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+        assert_eq!(wal.read(1).unwrap(), b"second entry");
+        drop(wal);
+
+        // so do swapped bytes
+        let mut corrupted = original.clone();
+        corrupted.swap(payload, payload + 1);
+        std::fs::write(&wal_path, &corrupted).unwrap();
+        assert!(open_wal(&dir).read(0).is_err());
+
+        // and the length in the header
+        let mut corrupted = original.clone();
+        corrupted[WAL_HEADER_LEN + 1] ^= 1;
+        std::fs::write(&wal_path, &corrupted).unwrap();
+        assert!(open_wal(&dir).read(0).is_err());
+    }
+
+    #[test]
+    fn test_unknown_entry_version() {
+        let (dir, mut wal) = temp_wal();
+        wal.write(b"first entry").unwrap();
+        wal.write(b"second entry").unwrap();
+        drop(wal);
+
+        let wal_path = dir.path().join("test.wal");
+        let mut bytes = std::fs::read(&wal_path).unwrap();
+        bytes[WAL_HEADER_LEN] = ENTRY_VERSION + 1;
+        std::fs::write(&wal_path, &bytes).unwrap();
+
+        let err = open_wal(&dir).read(0).unwrap_err();
+        assert!(err.to_string().contains("Unknown WAL entry version"));
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let (dir, wal) = temp_wal();
+        let entry_len = 3 + ENTRY_HEADER_LEN;
+        let mut wal = wal.with_durability(Durability::GroupCommit {
+            window: Duration::from_secs(1),
+            max_bytes: 4 * entry_len,
+        });
+        let wal_len = || {
+            std::fs::metadata(dir.path().join("test.wal"))
+                .unwrap()
+                .len() as usize
+        };
+
+        // writes are buffered until they are committed, but can be read in the meantime
+        for i in 0..3u8 {
+            wal.write(&[i; 3]).unwrap();
+        }
+        assert_eq!(wal.size(), 3);
+        assert_eq!(wal.committed_size(), 0);
+        assert_eq!(wal.uncommitted_bytes(), 3 * entry_len);
+        assert_eq!(wal_len(), WAL_HEADER_LEN);
+        assert_eq!(wal.read(1).unwrap(), vec![1; 3]);
+        let entries: Vec<Vec<u8>> = wal.iter_from(1).unwrap().map(Result::unwrap).collect();
+        assert_eq!(entries, vec![vec![1; 3], vec![2; 3]]);
+
+        wal.commit().unwrap();
+        assert_eq!(wal.committed_size(), 3);
+        assert_eq!(wal_len(), WAL_HEADER_LEN + 3 * entry_len);
+
+        // reads span what is in the file and what is still buffered
+        wal.write(&[3; 3]).unwrap();
+        let entries: Vec<Vec<u8>> = wal.iter_from(2).unwrap().map(Result::unwrap).collect();
+        assert_eq!(entries, vec![vec![2; 3], vec![3; 3]]);
+
+        // enough buffered bytes commit without waiting for the window
+        for i in 4..7u8 {
+            wal.write(&[i; 3]).unwrap();
+        }
+        assert_eq!(wal.committed_size(), 7);
+
+        // anything not committed is lost when the wal goes away
+        wal.write(&[7; 3]).unwrap();
+        drop(wal);
+        let wal = open_wal(&dir);
+        assert_eq!(wal.size(), 7);
+        assert_eq!(wal.read(6).unwrap(), vec![6; 3]);
+    }
+
+    #[test]
+    fn test_failed_commit_is_rolled_back() {
+        let (dir, wal) = temp_wal();
+        let mut wal = wal.with_durability(Durability::GroupCommit {
+            window: Duration::from_secs(1),
+            max_bytes: DEFAULT_COMMIT_BYTES,
+        });
+        let read_only = |name: &str| std::fs::File::open(dir.path().join(name)).unwrap();
+        let read_all = |wal: &FileWal| -> Vec<Vec<u8>> {
+            wal.iter_from(0).unwrap().map(Result::unwrap).collect()
+        };
+
+        wal.write(b"first").unwrap();
+        wal.commit().unwrap();
+        let offset = wal.offset();
+
+        // the entries make it into the wal file, the index write after it fails
+        wal.write(b"second").unwrap();
+        wal.write(b"third").unwrap();
+        wal.index_file = read_only("test.idx");
+        assert!(wal.commit().is_err());
+        assert_eq!(wal.size(), 1);
+        assert_eq!(wal.committed_size(), 1);
+        assert_eq!(wal.offset(), offset);
+        assert!(wal.read(1).is_err());
+
+        // none of the failed entries are written out again by the next commit
+        wal.write(b"fourth").unwrap();
+        wal.commit().unwrap();
+        assert_eq!(read_all(&wal), vec![b"first".to_vec(), b"fourth".to_vec()]);
+        drop(wal);
+        let mut wal = open_wal(&dir);
+        assert!(wal.tail_repair().is_none());
+        assert_eq!(read_all(&wal), vec![b"first".to_vec(), b"fourth".to_vec()]);
+
+        // a wal file that can't be cut back leaves the wal refusing writes until it is reopened
+        wal.wal_file = read_only("test.wal");
+        assert!(wal.write(b"fifth").is_err());
+        assert!(wal.write(b"sixth").is_err());
+        assert!(wal.commit().is_err());
+        drop(wal);
+        let wal = open_wal(&dir);
+        assert_eq!(read_all(&wal), vec![b"first".to_vec(), b"fourth".to_vec()]);
+    }
+
+    #[test]
+    fn test_durability_always_and_os_write_straight_away() {
+        for durability in [Durability::Always, Durability::Os] {
+            let (dir, wal) = temp_wal();
+            let mut wal = wal.with_durability(durability);
+            wal.write(b"first entry").unwrap();
+            assert_eq!(wal.committed_size(), 1);
+            assert_eq!(
+                std::fs::metadata(dir.path().join("test.wal"))
+                    .unwrap()
+                    .len() as usize,
+                WAL_HEADER_LEN + wal.offset
+            );
+        }
+    }
+
+    #[test]
+    fn test_durability_from_str() {
+        assert_eq!("always".parse::<Durability>().unwrap(), Durability::Always);
+        assert_eq!("os".parse::<Durability>().unwrap(), Durability::Os);
+        assert_eq!(
+            "group".parse::<Durability>().unwrap(),
+            Durability::GroupCommit {
+                window: DEFAULT_COMMIT_WINDOW,
+                max_bytes: DEFAULT_COMMIT_BYTES,
+            }
+        );
+        assert!("sometimes".parse::<Durability>().is_err());
+    }
+
+    const DATA: [&str; 3] = ["first entry", "second entry", "third entry"];
+
+    // opens the wal in `dir` twice, so the second time it is already in the current format
+    fn assert_migrated(dir: &TempDir) {
+        let data = DATA;
+        for _ in 0..2 {
+            let wal = open_wal(dir);
+            assert_eq!(wal.size(), 3);
+            assert_eq!(
+                wal.offset,
+                data.iter()
+                    .map(|d| d.len() + ENTRY_HEADER_LEN)
+                    .sum::<usize>()
+            );
+            for (i, entry) in data.iter().enumerate() {
+                assert_eq!(wal.read(i).unwrap(), entry.as_bytes());
+            }
+        }
+
+        let metadata = std::fs::read(dir.path().join("test.meta")).unwrap();
+        assert_eq!(
+            Metadata::decode(&metadata).unwrap().format_version,
+            FORMAT_VERSION
+        );
+        let wal_bytes = std::fs::read(dir.path().join("test.wal")).unwrap();
+        assert!(wal_bytes.starts_with(WAL_MAGIC));
+    }
+
+    #[test]
+    fn test_xor_entries_are_migrated() {
+        let dir = tempdir().unwrap();
+
+        // format version 0, from before the version byte, with the metadata that went with it
+        let mut wal_bytes = Vec::new();
+        for entry in DATA {
+            wal_bytes.extend_from_slice(&entry.len().to_ne_bytes());
+            wal_bytes.extend_from_slice(&checksum_xor(entry.as_bytes()).to_ne_bytes());
+            wal_bytes.extend_from_slice(entry.as_bytes());
+        }
+        let mut metadata = wal_bytes.len().to_ne_bytes().to_vec();
+        metadata.extend_from_slice(&DATA.len().to_ne_bytes());
+        std::fs::write(dir.path().join("test.wal"), &wal_bytes).unwrap();
+        std::fs::write(dir.path().join("test.meta"), &metadata).unwrap();
+
+        assert_migrated(&dir);
+    }
+
+    #[test]
+    fn test_native_entries_are_migrated() {
+        let dir = tempdir().unwrap();
+
+        // format version 1, with a version byte and crc32c but native usizes and no header
+        let mut wal_bytes = Vec::new();
+        for entry in DATA {
+            let mut header = vec![1u8];
+            header.extend_from_slice(&entry.len().to_ne_bytes());
+            let checksum = crc32c::crc32c_append(crc32c::crc32c(&header), entry.as_bytes());
+
+            wal_bytes.extend_from_slice(&header);
+            wal_bytes.extend_from_slice(&checksum.to_le_bytes());
+            wal_bytes.extend_from_slice(entry.as_bytes());
+        }
+        let mut metadata = wal_bytes.len().to_ne_bytes().to_vec();
+        metadata.extend_from_slice(&DATA.len().to_ne_bytes());
+        metadata.extend_from_slice(&0usize.to_ne_bytes());
+        metadata.push(1);
+        std::fs::write(dir.path().join("test.wal"), &wal_bytes).unwrap();
+        std::fs::write(dir.path().join("test.meta"), &metadata).unwrap();
+
+        assert_migrated(&dir);
+    }
+
+    #[test]
+    fn test_header() {
+        let (dir, mut wal) = temp_wal();
+        let created_at = wal.created_at();
+        assert!(created_at <= SystemTime::now());
+        assert!(created_at > SystemTime::now() - Duration::from_secs(60));
+
+        // the creation time is kept through a reopen and a clean
+        wal.write(b"first entry").unwrap();
+        wal.write(b"second entry").unwrap();
+        wal.clean_until(1).unwrap();
+        drop(wal);
+
+        let wal = open_wal(&dir);
+        assert_eq!(wal.created_at(), created_at);
+        assert_eq!(wal.read(1).unwrap(), b"second entry");
+
+        let wal_bytes = std::fs::read(dir.path().join("test.wal")).unwrap();
+        assert_eq!(&wal_bytes[..4], WAL_MAGIC);
+        assert_eq!(wal_bytes[4..8], FORMAT_VERSION.to_le_bytes());
+    }
+
+    #[test]
+    fn test_unrecognized_files_are_refused() {
+        let dir = tempdir().unwrap();
+        let wal_path = dir.path().join("test.wal");
+        let metadata_path = dir.path().join("test.meta");
+
+        let (_good_dir, mut wal) = temp_wal();
+        wal.write(b"first entry").unwrap();
+        let good_wal = std::fs::read(wal.wal_path.clone()).unwrap();
+        let good_metadata = Metadata {
+            format_version: FORMAT_VERSION,
+            offset: wal.offset,
+            size: 1,
+            base_index: 0,
+        }
+        .encode();
+
+        let refused = |wal_bytes: &[u8], metadata: &[u8]| {
+            std::fs::write(&wal_path, wal_bytes).unwrap();
+            std::fs::write(&metadata_path, metadata).unwrap();
+            let err = FileWal::new(wal_path.to_str().unwrap(), metadata_path.to_str().unwrap())
+                .unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        };
+
+        // metadata that isn't any format there has been
+        refused(&good_wal, b"not metadata");
+        refused(&good_wal, &good_metadata[..20]);
+        let mut future_metadata = good_metadata.clone();
+        future_metadata[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        refused(&good_wal, &future_metadata);
+
+        // a wal file without the header, or from a newer version
+        refused(b"not a wal file at all", &good_metadata);
+        let mut future_wal = good_wal.clone();
+        future_wal[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        refused(&future_wal, &good_metadata);
+
+        // a wal file that has lost its entries
+        refused(b"", &good_metadata);
+    }
 }
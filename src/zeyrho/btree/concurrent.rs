@@ -0,0 +1,1323 @@
+use crate::zeyrho::btree::Order;
+use parking_lot::{ArcRwLockReadGuard, ArcRwLockWriteGuard, RawRwLock, RwLock};
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::ops::{Bound, Deref, DerefMut, RangeBounds, RangeFull};
+use std::sync::Arc;
+
+/*
+A B-link tree (Lehman & Yao) so that it can be shared between tokio tasks. Every node knows the range of keys it covers
+and has a link to its right sibling on the same level. A split moves the upper half of a node into a new right sibling
+and links it in before the parent knows about it, so anybody who lands on the left half with a key that moved just
+follows the link to the right. That means readers and writers only ever hold the latch of a single node at a time
+instead of crabbing down the tree.
+
+A delete that empties out a leaf unlinks it: the right sibling takes over its keys, the left sibling links past it and
+the parent stops pointing at it. The leaf keeps its link to the right and is left with an empty range, so anybody who
+was already on their way to it moves right as if it had been split. Link nodes are never merged, so a leaf that is the
+only child of its parent stays pointed at by it, it is out of the leaf chain all the same.
+ */
+
+type NodeRef<K, V> = Arc<RwLock<BLinkNode<K, V>>>;
+
+#[derive(Debug)]
+struct BLinkNode<K: Ord + Debug, V: Debug> {
+    // keys in this node are >= low_key and < high_key, None being unbounded
+    low_key: Option<Arc<K>>,
+    high_key: Option<Arc<K>>,
+    // the right sibling is an owning reference, there are no prev links so this can't form a cycle
+    next: Option<NodeRef<K, V>>,
+    kind: BLinkKind<K, V>,
+}
+
+#[derive(Debug)]
+enum BLinkKind<K: Ord + Debug, V: Debug> {
+    Leaf {
+        key_vals: Vec<(Arc<K>, V)>,
+    },
+    Link {
+        // leaves are level 0, so the parents of leaves are level 1
+        level: usize,
+        separators: Vec<Arc<K>>,
+        children: Vec<NodeRef<K, V>>,
+    },
+}
+
+impl<K: Ord + Debug, V: Debug> BLinkNode<K, V> {
+    fn new_leaf() -> NodeRef<K, V> {
+        Arc::new(RwLock::new(BLinkNode {
+            low_key: None,
+            high_key: None,
+            next: None,
+            kind: BLinkKind::Leaf {
+                key_vals: Vec::new(),
+            },
+        }))
+    }
+
+    fn level(&self) -> usize {
+        match &self.kind {
+            BLinkKind::Leaf { .. } => 0,
+            BLinkKind::Link { level, .. } => *level,
+        }
+    }
+
+    // the right sibling when the keys up to `bound` can't be in this node anymore because a split moved them over
+    fn right_sibling_for<Q>(&self, bound: Bound<&Q>) -> Option<NodeRef<K, V>>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let high_key = <K as std::borrow::Borrow<Q>>::borrow(self.high_key.as_ref()?);
+        let moved_right = match bound {
+            Bound::Unbounded => true,
+            Bound::Included(key) => high_key <= key,
+            Bound::Excluded(key) => high_key < key,
+        };
+
+        if moved_right { self.next.clone() } else { None }
+    }
+
+    // one step towards the node on `level` that holds the keys right up to `bound`, None once we are there
+    fn step_towards<Q>(&self, bound: Bound<&Q>, level: usize) -> Option<NodeRef<K, V>>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        if let Some(right) = self.right_sibling_for(bound) {
+            return Some(right);
+        }
+
+        match &self.kind {
+            BLinkKind::Link {
+                level: node_level,
+                separators,
+                children,
+            } if *node_level > level => {
+                let child_index = match bound {
+                    Bound::Unbounded => children.len() - 1,
+                    Bound::Included(key) => separators
+                        .partition_point(|s| <K as std::borrow::Borrow<Q>>::borrow(s) <= key),
+                    Bound::Excluded(key) => separators
+                        .partition_point(|s| <K as std::borrow::Borrow<Q>>::borrow(s) < key),
+                };
+                Some(children[child_index].clone())
+            }
+            _ => None,
+        }
+    }
+
+    // moves the upper half of this node into a new right sibling and returns the separator between the two
+    fn split(&mut self) -> (Arc<K>, NodeRef<K, V>) {
+        let (separator, kind) = match &mut self.kind {
+            BLinkKind::Leaf { key_vals } => {
+                let right = key_vals.split_off(key_vals.len() / 2);
+                (right[0].0.clone(), BLinkKind::Leaf { key_vals: right })
+            }
+            BLinkKind::Link {
+                level,
+                separators,
+                children,
+            } => {
+                let mid = separators.len() / 2;
+                let right_children = children.split_off(mid + 1);
+                let right_separators = separators.split_off(mid + 1);
+                let separator = separators.pop().unwrap();
+                (separator, BLinkKind::Link {
+                    level: *level,
+                    separators: right_separators,
+                    children: right_children,
+                })
+            }
+        };
+
+        let right = Arc::new(RwLock::new(BLinkNode {
+            low_key: Some(separator.clone()),
+            high_key: self.high_key.take(),
+            next: self.next.take(),
+            kind,
+        }));
+
+        self.high_key = Some(separator.clone());
+        self.next = Some(right.clone());
+
+        (separator, right)
+    }
+
+    fn key_vals_mut(&mut self) -> &mut Vec<(Arc<K>, V)> {
+        match &mut self.kind {
+            BLinkKind::Leaf { key_vals } => key_vals,
+            BLinkKind::Link { .. } => unreachable!("expected a leaf node but found a link node"),
+        }
+    }
+
+    fn key_vals(&self) -> &Vec<(Arc<K>, V)> {
+        match &self.kind {
+            BLinkKind::Leaf { key_vals } => key_vals,
+            BLinkKind::Link { .. } => unreachable!("expected a leaf node but found a link node"),
+        }
+    }
+}
+
+fn search_leaf<K, V, Q>(key_vals: &[(Arc<K>, V)], key: &Q) -> Result<usize, usize>
+where
+    K: std::borrow::Borrow<Q>,
+    Q: Ord + ?Sized,
+{
+    key_vals.binary_search_by(|(k, _)| <K as std::borrow::Borrow<Q>>::borrow(k).cmp(key))
+}
+
+// A latch on a node that also holds on to the node through its Arc, so it can be kept after the lookup that found it
+type ReadLatch<K, V> = ArcRwLockReadGuard<RawRwLock, BLinkNode<K, V>>;
+type WriteLatch<K, V> = ArcRwLockWriteGuard<RawRwLock, BLinkNode<K, V>>;
+
+// Thread safe counterpart to `BPlusTree` with the same operations, all of them taking `&self`. Values handed out by
+// `get`, `get_mut` and the entries keep their leaf latched until they are dropped, so using the tree from the same thread
+// while holding one can deadlock the way a `BPlusTree` Ref makes the RefCell panic.
+#[derive(Debug)]
+pub struct ConcurrentBPlusTree<K: Ord + Debug, V: Debug> {
+    root: RwLock<NodeRef<K, V>>,
+    order: Order,
+}
+
+impl<K: Ord + Debug, V: Debug> Default for ConcurrentBPlusTree<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Debug, V: Debug> ConcurrentBPlusTree<K, V> {
+    pub fn new() -> Self {
+        Self::with_order(Order::default())
+    }
+
+    pub(super) fn with_order(order: Order) -> Self {
+        ConcurrentBPlusTree {
+            root: RwLock::new(BLinkNode::new_leaf()),
+            order,
+        }
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        match self.entry(key) {
+            ConcurrentEntry::Occupied(mut entry) => Some(entry.insert(value)),
+            ConcurrentEntry::Vacant(entry) => {
+                entry.insert(value);
+                None
+            }
+        }
+    }
+
+    pub fn entry(&self, key: K) -> ConcurrentEntry<'_, K, V> {
+        let latch = self.latch_write(Bound::Included(&key), 0);
+
+        match search_leaf(latch.key_vals(), &key) {
+            Ok(index) => ConcurrentEntry::Occupied(ConcurrentOccupiedEntry {
+                key: latch.key_vals()[index].0.clone(),
+                tree: self,
+                latch: Some(latch),
+                index,
+                split: None,
+            }),
+            Err(index) => ConcurrentEntry::Vacant(ConcurrentVacantEntry {
+                key,
+                tree: self,
+                latch,
+                index,
+            }),
+        }
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<ConcurrentRef<'_, K, V>>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let latch = self.latch_read(Bound::Included(key), 0);
+        let index = search_leaf(latch.key_vals(), key).ok()?;
+        Some(ConcurrentRef {
+            latch,
+            index,
+            _tree: PhantomData,
+        })
+    }
+
+    pub fn get_mut<Q>(&self, key: &Q) -> Option<ConcurrentRefMut<'_, K, V>>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let latch = self.latch_write(Bound::Included(key), 0);
+        let index = search_leaf(latch.key_vals(), key).ok()?;
+        Some(ConcurrentRefMut {
+            latch,
+            index,
+            _tree: PhantomData,
+        })
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let latch = self.latch_read(Bound::Included(key), 0);
+        search_leaf(latch.key_vals(), key).is_ok()
+    }
+
+    pub fn delete<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut latch = self.latch_write(Bound::Included(key), 0);
+        let pos = search_leaf(latch.key_vals(), key).ok()?;
+        let (_, value) = latch.key_vals_mut().remove(pos);
+
+        if latch.key_vals().is_empty() {
+            let leaf = WriteLatch::rwlock(&latch).clone();
+            drop(latch);
+            self.unlink_leaf(leaf);
+        }
+
+        Some(value)
+    }
+
+    pub fn iter(&self) -> ConcurrentRange<'_, K, V, K, RangeFull> {
+        self.range(..)
+    }
+
+    pub fn keys(&self) -> ConcurrentKeys<'_, K, V> {
+        ConcurrentKeys {
+            scan: LeafScan::new(self, ..),
+        }
+    }
+
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = V> + '_
+    where
+        V: Clone,
+    {
+        self.iter().map(|(_, v)| v)
+    }
+
+    pub fn range<Q, R>(&self, range: R) -> ConcurrentRange<'_, K, V, Q, R>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        ConcurrentRange {
+            scan: LeafScan::new(self, range),
+        }
+    }
+
+    fn root(&self) -> NodeRef<K, V> {
+        self.root.read().clone()
+    }
+
+    // the node on `level` that holds the keys right up to `bound`. Nothing stays locked, so by the time the caller
+    // latches the node it may have to move right again
+    fn find_node<Q>(&self, bound: Bound<&Q>, level: usize) -> NodeRef<K, V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut node = self.root();
+        loop {
+            let next = node.read().step_towards(bound, level);
+            match next {
+                None => return node,
+                Some(next) => node = next,
+            }
+        }
+    }
+
+    fn leftmost_leaf(&self) -> NodeRef<K, V> {
+        let mut node = self.root();
+        loop {
+            let child = match &node.read().kind {
+                BLinkKind::Leaf { .. } => None,
+                BLinkKind::Link { children, .. } => Some(children[0].clone()),
+            };
+            match child {
+                None => return node,
+                Some(child) => node = child,
+            }
+        }
+    }
+
+    // latches the node on `level` that holds the keys right up to `bound`, moving right past any splits since the
+    // descent
+    fn latch_read<Q>(&self, bound: Bound<&Q>, level: usize) -> ReadLatch<K, V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut node = self.find_node(bound, level);
+        loop {
+            let latch = node.read_arc();
+            match latch.right_sibling_for(bound) {
+                Some(right) => node = right,
+                None => return latch,
+            }
+        }
+    }
+
+    fn latch_write<Q>(&self, bound: Bound<&Q>, level: usize) -> WriteLatch<K, V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.latch_write_from(self.find_node(bound, level), bound)
+    }
+
+    fn latch_write_from<Q>(&self, mut node: NodeRef<K, V>, bound: Bound<&Q>) -> WriteLatch<K, V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        loop {
+            let latch = node.write_arc();
+            match latch.right_sibling_for(bound) {
+                Some(right) => node = right,
+                None => return latch,
+            }
+        }
+    }
+
+    // links a node created by a split on `level - 1` into its parent, splitting further up when the parent overflows
+    fn insert_separator(&self, level: usize, separator: Arc<K>, new_child: NodeRef<K, V>) {
+        if self.root().read().level() < level {
+            let mut root = self.root.write();
+            // somebody else may have grown the tree while we were waiting for the lock
+            if root.read().level() < level {
+                let old_root = root.clone();
+                *root = Arc::new(RwLock::new(BLinkNode {
+                    low_key: None,
+                    high_key: None,
+                    next: None,
+                    kind: BLinkKind::Link {
+                        level,
+                        separators: vec![separator],
+                        children: vec![old_root, new_child],
+                    },
+                }));
+                return;
+            }
+        }
+
+        let mut parent = self.latch_write(Bound::Included(separator.as_ref()), level);
+        let overflowing = match &mut parent.kind {
+            BLinkKind::Link {
+                separators,
+                children,
+                ..
+            } => {
+                let pos = separators.partition_point(|s| s <= &separator);
+                separators.insert(pos, separator.clone());
+                children.insert(pos + 1, new_child);
+                separators.len() > self.order.separators_max_size()
+            }
+            BLinkKind::Leaf { .. } => unreachable!("found a leaf above level 0"),
+        };
+
+        if overflowing {
+            let (separator, new_right) = parent.split();
+            drop(parent);
+            self.insert_separator(level + 1, separator, new_right);
+        }
+    }
+
+    // Unlinks a leaf that a delete emptied out, which the caller must not have latched anymore. The latches are taken
+    // left to right along the leaves and the parent last, and nothing that latches a link node ever waits on a leaf,
+    // so this can't deadlock with anything else. If the leaf was refilled, or its neighbours changed in the meantime,
+    // it is left where it is.
+    fn unlink_leaf(&self, leaf: NodeRef<K, V>) {
+        let (low_key, right) = {
+            let guard = leaf.read();
+            match (&guard.low_key, &guard.high_key, &guard.next) {
+                // the leftmost and rightmost leaves stay, there is no sibling to hand their keys to
+                (Some(low_key), Some(_), Some(next)) if guard.key_vals().is_empty() => {
+                    (low_key.clone(), next.clone())
+                }
+                _ => return,
+            }
+        };
+        let left = self.find_node(Bound::Excluded(low_key.as_ref()), 0);
+        let parent = self.find_node(Bound::Included(low_key.as_ref()), 1);
+
+        let mut left = left.write_arc();
+        if !left
+            .next
+            .as_ref()
+            .is_some_and(|next| Arc::ptr_eq(next, &leaf))
+        {
+            return;
+        }
+        let mut leaf = leaf.write_arc();
+        if !leaf.key_vals().is_empty()
+            || !leaf
+                .next
+                .as_ref()
+                .is_some_and(|next| Arc::ptr_eq(next, &right))
+        {
+            return;
+        }
+        let mut right = right.write_arc();
+
+        left.next = Some(WriteLatch::rwlock(&right).clone());
+        right.low_key = leaf.low_key.clone();
+        leaf.high_key = leaf.low_key.clone();
+
+        // The parent can keep pointing at the leaf, anybody it sends there moves right, but if it has another child
+        // the keys can go to the leaf is dropped. Sending them right needs the right sibling to be the next child, a
+        // split the parent hasn't heard of yet would sit in between. Sending them left always works, the left sibling
+        // links to the right one now.
+        let mut parent = self.latch_write_from(parent, Bound::Included(low_key.as_ref()));
+        if let BLinkKind::Link {
+            level: 1,
+            separators,
+            children,
+        } = &mut parent.kind
+        {
+            if let Some(i) = children
+                .iter()
+                .position(|child| Arc::ptr_eq(child, WriteLatch::rwlock(&leaf)))
+            {
+                if children
+                    .get(i + 1)
+                    .is_some_and(|next| Arc::ptr_eq(next, WriteLatch::rwlock(&right)))
+                {
+                    separators.remove(i);
+                    children.remove(i);
+                } else if i > 0 {
+                    separators.remove(i - 1);
+                    children.remove(i);
+                }
+            }
+        }
+    }
+}
+
+// A value in a `ConcurrentBPlusTree`, its leaf stays read latched until this is dropped.
+pub struct ConcurrentRef<'a, K: Ord + Debug, V: Debug> {
+    latch: ReadLatch<K, V>,
+    index: usize,
+    _tree: PhantomData<&'a ConcurrentBPlusTree<K, V>>,
+}
+
+impl<K: Ord + Debug, V: Debug> Deref for ConcurrentRef<'_, K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.latch.key_vals()[self.index].1
+    }
+}
+
+// A value in a `ConcurrentBPlusTree`, its leaf stays write latched until this is dropped.
+pub struct ConcurrentRefMut<'a, K: Ord + Debug, V: Debug> {
+    latch: WriteLatch<K, V>,
+    index: usize,
+    _tree: PhantomData<&'a ConcurrentBPlusTree<K, V>>,
+}
+
+impl<K: Ord + Debug, V: Debug> Deref for ConcurrentRefMut<'_, K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.latch.key_vals()[self.index].1
+    }
+}
+
+impl<K: Ord + Debug, V: Debug> DerefMut for ConcurrentRefMut<'_, K, V> {
+    fn deref_mut(&mut self) -> &mut V {
+        &mut self.latch.key_vals_mut()[self.index].1
+    }
+}
+
+// Modeled on `Entry` for `BPlusTree`. The leaf the key belongs in stays write latched for as long as the entry is
+// around, so nothing else gets at the key between looking it up and acting on it.
+pub enum ConcurrentEntry<'a, K: Ord + Debug, V: Debug> {
+    Vacant(ConcurrentVacantEntry<'a, K, V>),
+    Occupied(ConcurrentOccupiedEntry<'a, K, V>),
+}
+
+pub struct ConcurrentVacantEntry<'a, K: Ord + Debug, V: Debug> {
+    key: K,
+    tree: &'a ConcurrentBPlusTree<K, V>,
+    latch: WriteLatch<K, V>,
+    index: usize,
+}
+
+pub struct ConcurrentOccupiedEntry<'a, K: Ord + Debug, V: Debug> {
+    key: Arc<K>,
+    tree: &'a ConcurrentBPlusTree<K, V>,
+    // only None while the entry is being dropped
+    latch: Option<WriteLatch<K, V>>,
+    index: usize,
+    // a split of the leaf that the parent is told about once the latch is released, since a parent is never latched
+    // while holding a leaf
+    split: Option<(Arc<K>, NodeRef<K, V>)>,
+}
+
+impl<'a, K: Ord + Debug, V: Debug> ConcurrentEntry<'a, K, V> {
+    pub fn key(&self) -> &K {
+        match self {
+            ConcurrentEntry::Vacant(entry) => entry.key(),
+            ConcurrentEntry::Occupied(entry) => entry.key(),
+        }
+    }
+
+    pub fn or_insert(self, default: V) -> ConcurrentOccupiedEntry<'a, K, V> {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> ConcurrentOccupiedEntry<'a, K, V> {
+        match self {
+            ConcurrentEntry::Vacant(entry) => entry.insert(default()),
+            ConcurrentEntry::Occupied(entry) => entry,
+        }
+    }
+
+    pub fn or_default(self) -> ConcurrentOccupiedEntry<'a, K, V>
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        match self {
+            ConcurrentEntry::Vacant(entry) => ConcurrentEntry::Vacant(entry),
+            ConcurrentEntry::Occupied(mut entry) => {
+                f(entry.get_mut());
+                ConcurrentEntry::Occupied(entry)
+            }
+        }
+    }
+}
+
+impl<'a, K: Ord + Debug, V: Debug> ConcurrentVacantEntry<'a, K, V> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    pub fn insert(self, value: V) -> ConcurrentOccupiedEntry<'a, K, V> {
+        let ConcurrentVacantEntry {
+            key,
+            tree,
+            mut latch,
+            index,
+        } = self;
+        let key = Arc::new(key);
+
+        let key_vals = latch.key_vals_mut();
+        key_vals.insert(index, (key.clone(), value));
+        let len = key_vals.len();
+        if len <= tree.order.max_kvs_in_leaf() {
+            return ConcurrentOccupiedEntry {
+                key,
+                tree,
+                latch: Some(latch),
+                index,
+                split: None,
+            };
+        }
+
+        // the split keeps the first half in this leaf and moves the rest into the new right leaf, which can only be
+        // reached through this one until the parent hears about it
+        let mid = len / 2;
+        let (separator, new_right) = latch.split();
+        let (latch, index) = if index < mid {
+            (latch, index)
+        } else {
+            (new_right.write_arc(), index - mid)
+        };
+
+        ConcurrentOccupiedEntry {
+            key,
+            tree,
+            latch: Some(latch),
+            index,
+            split: Some((separator, new_right)),
+        }
+    }
+}
+
+impl<K: Ord + Debug, V: Debug> ConcurrentOccupiedEntry<'_, K, V> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn get(&self) -> &V {
+        &self.latch().key_vals()[self.index].1
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        let index = self.index;
+        &mut self.latch.as_mut().unwrap().key_vals_mut()[index].1
+    }
+
+    pub fn insert(&mut self, value: V) -> V {
+        std::mem::replace(self.get_mut(), value)
+    }
+
+    pub fn remove(mut self) -> V {
+        let mut latch = self.latch.take().unwrap();
+        let (_, value) = latch.key_vals_mut().remove(self.index);
+        let emptied = latch
+            .key_vals()
+            .is_empty()
+            .then(|| WriteLatch::rwlock(&latch).clone());
+        drop(latch);
+
+        self.release();
+        if let Some(leaf) = emptied {
+            self.tree.unlink_leaf(leaf);
+        }
+
+        value
+    }
+
+    fn latch(&self) -> &WriteLatch<K, V> {
+        self.latch.as_ref().unwrap()
+    }
+
+    fn release(&mut self) {
+        self.latch = None;
+        if let Some((separator, new_right)) = self.split.take() {
+            self.tree.insert_separator(1, separator, new_right);
+        }
+    }
+}
+
+impl<K: Ord + Debug, V: Debug> Drop for ConcurrentOccupiedEntry<'_, K, V> {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+// where the back of a scan reads its next leaf from
+enum BackCursor<K> {
+    End,
+    Below(Arc<K>),
+    Done,
+}
+
+// Iterates a leaf at a time, copying out the entries of the leaf while it is latched. Since nothing stays latched
+// between leaves the iterator sees every key that was in the range for the whole iteration, but may or may not see
+// writes that happen while it runs. There are no prev links between leaves, so going backwards descends again
+// from the root for every leaf using the low key of the last one. `ConcurrentRange` and `ConcurrentKeys` only differ
+// in what they copy out of each value.
+struct LeafScan<'a, K: Ord + Debug, V: Debug, Q: ?Sized, R, T> {
+    tree: &'a ConcurrentBPlusTree<K, V>,
+    range: R,
+    // the last keys handed out at either end, which is also how the two ends notice they met
+    front_key: Option<Arc<K>>,
+    back_key: Option<Arc<K>>,
+    front: VecDeque<(Arc<K>, T)>,
+    front_leaf: Option<NodeRef<K, V>>,
+    back: VecDeque<(Arc<K>, T)>,
+    back_cursor: BackCursor<K>,
+    _bound: PhantomData<fn(&Q)>,
+}
+
+impl<'a, K, V, Q, R, T> LeafScan<'a, K, V, Q, R, T>
+where
+    K: Ord + Debug + std::borrow::Borrow<Q>,
+    V: Debug,
+    Q: Ord + ?Sized,
+    R: RangeBounds<Q>,
+{
+    fn new(tree: &'a ConcurrentBPlusTree<K, V>, range: R) -> Self {
+        match (range.start_bound(), range.end_bound()) {
+            (Bound::Excluded(start), Bound::Excluded(end)) if start == end => {
+                panic!("range start and end are equal and excluded in ConcurrentBPlusTree")
+            }
+            (
+                Bound::Included(start) | Bound::Excluded(start),
+                Bound::Included(end) | Bound::Excluded(end),
+            ) if start > end => {
+                panic!("range start is greater than range end in ConcurrentBPlusTree")
+            }
+            _ => {}
+        }
+
+        let front_leaf = match range.start_bound() {
+            Bound::Unbounded => tree.leftmost_leaf(),
+            Bound::Included(key) | Bound::Excluded(key) => tree.find_node(Bound::Included(key), 0),
+        };
+
+        LeafScan {
+            tree,
+            range,
+            front_key: None,
+            back_key: None,
+            front: VecDeque::new(),
+            front_leaf: Some(front_leaf),
+            back: VecDeque::new(),
+            back_cursor: BackCursor::End,
+            _bound: PhantomData,
+        }
+    }
+
+    fn within(&self, key: &K) -> bool {
+        self.range
+            .contains(<K as std::borrow::Borrow<Q>>::borrow(key))
+            && self
+                .front_key
+                .as_ref()
+                .is_none_or(|front| key > front.as_ref())
+            && self
+                .back_key
+                .as_ref()
+                .is_none_or(|back| key < back.as_ref())
+    }
+
+    // whether the leaves from `high_key` on could still have keys in what is left of the range
+    fn before_end(&self, high_key: &K) -> bool {
+        let before_end = match self.range.end_bound() {
+            Bound::Unbounded => true,
+            Bound::Included(end) => <K as std::borrow::Borrow<Q>>::borrow(high_key) <= end,
+            Bound::Excluded(end) => <K as std::borrow::Borrow<Q>>::borrow(high_key) < end,
+        };
+
+        before_end
+            && self
+                .back_key
+                .as_ref()
+                .is_none_or(|back| high_key < back.as_ref())
+    }
+
+    // and whether the leaves below `low_key` could
+    fn after_start(&self, low_key: &K) -> bool {
+        let after_start = match self.range.start_bound() {
+            Bound::Unbounded => true,
+            Bound::Included(start) | Bound::Excluded(start) => {
+                <K as std::borrow::Borrow<Q>>::borrow(low_key) > start
+            }
+        };
+
+        after_start
+            && self
+                .front_key
+                .as_ref()
+                .is_none_or(|front| low_key > front.as_ref())
+    }
+
+    fn fill_front(&mut self, take: &impl Fn(&V) -> T) {
+        let Some(mut leaf) = self.front_leaf.take() else {
+            return;
+        };
+
+        loop {
+            let guard = leaf.read();
+            // a split may have moved the keys we were after into the right sibling
+            let right = match (&self.front_key, self.range.start_bound()) {
+                (Some(key), _) => guard.right_sibling_for::<K>(Bound::Included(key)),
+                (None, Bound::Included(key) | Bound::Excluded(key)) => {
+                    guard.right_sibling_for(Bound::Included(key))
+                }
+                (None, Bound::Unbounded) => None,
+            };
+            if let Some(right) = right {
+                drop(guard);
+                leaf = right;
+                continue;
+            }
+
+            let items: Vec<(Arc<K>, T)> = guard
+                .key_vals()
+                .iter()
+                .filter(|(k, _)| self.within(k))
+                .map(|(k, v)| (k.clone(), take(v)))
+                .collect();
+            self.front.extend(items);
+
+            if guard
+                .high_key
+                .as_ref()
+                .is_some_and(|high_key| self.before_end(high_key))
+            {
+                self.front_leaf = guard.next.clone();
+            }
+            return;
+        }
+    }
+
+    fn fill_back(&mut self, take: &impl Fn(&V) -> T) {
+        let (items, cursor) = match std::mem::replace(&mut self.back_cursor, BackCursor::Done) {
+            BackCursor::Done => return,
+            BackCursor::End => self.read_back(self.range.end_bound(), take),
+            BackCursor::Below(key) => self.read_back::<K>(Bound::Excluded(&key), take),
+        };
+
+        self.back.extend(items);
+        self.back_cursor = cursor;
+    }
+
+    // the entries of the leaf that holds the keys right up to `bound`, and where to carry on from after it
+    fn read_back<B>(
+        &self,
+        bound: Bound<&B>,
+        take: &impl Fn(&V) -> T,
+    ) -> (Vec<(Arc<K>, T)>, BackCursor<K>)
+    where
+        K: std::borrow::Borrow<B>,
+        B: Ord + ?Sized,
+    {
+        let mut leaf = self.tree.find_node(bound, 0);
+
+        loop {
+            let guard = leaf.read();
+            if let Some(right) = guard.right_sibling_for(bound) {
+                drop(guard);
+                leaf = right;
+                continue;
+            }
+
+            let items = guard
+                .key_vals()
+                .iter()
+                .filter(|(k, _)| self.within(k))
+                .map(|(k, v)| (k.clone(), take(v)))
+                .collect();
+            let cursor = match &guard.low_key {
+                Some(low_key) if self.after_start(low_key) => BackCursor::Below(low_key.clone()),
+                _ => BackCursor::Done,
+            };
+            return (items, cursor);
+        }
+    }
+
+    fn next(&mut self, take: impl Fn(&V) -> T) -> Option<(Arc<K>, T)> {
+        loop {
+            if let Some((k, v)) = self.front.pop_front() {
+                // the back of the iterator may have already handed this out
+                if !self.within(&k) {
+                    self.front.clear();
+                    self.front_leaf = None;
+                    return None;
+                }
+                self.front_key = Some(k.clone());
+                return Some((k, v));
+            }
+
+            self.front_leaf.as_ref()?;
+            self.fill_front(&take);
+        }
+    }
+
+    fn next_back(&mut self, take: impl Fn(&V) -> T) -> Option<(Arc<K>, T)> {
+        loop {
+            if let Some((k, v)) = self.back.pop_back() {
+                if !self.within(&k) {
+                    self.back.clear();
+                    self.back_cursor = BackCursor::Done;
+                    return None;
+                }
+                self.back_key = Some(k.clone());
+                return Some((k, v));
+            }
+
+            if let BackCursor::Done = self.back_cursor {
+                return None;
+            }
+            self.fill_back(&take);
+        }
+    }
+}
+
+pub struct ConcurrentRange<'a, K: Ord + Debug, V: Debug, Q: ?Sized, R> {
+    scan: LeafScan<'a, K, V, Q, R, V>,
+}
+
+impl<K, V, Q, R> Iterator for ConcurrentRange<'_, K, V, Q, R>
+where
+    K: Ord + Debug + std::borrow::Borrow<Q>,
+    V: Debug + Clone,
+    Q: Ord + ?Sized,
+    R: RangeBounds<Q>,
+{
+    type Item = (Arc<K>, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.scan.next(V::clone)
+    }
+}
+
+impl<K, V, Q, R> DoubleEndedIterator for ConcurrentRange<'_, K, V, Q, R>
+where
+    K: Ord + Debug + std::borrow::Borrow<Q>,
+    V: Debug + Clone,
+    Q: Ord + ?Sized,
+    R: RangeBounds<Q>,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.scan.next_back(V::clone)
+    }
+}
+
+// the same scan as `ConcurrentRange` over the whole tree that only hands out the keys, so values are never cloned
+pub struct ConcurrentKeys<'a, K: Ord + Debug, V: Debug> {
+    scan: LeafScan<'a, K, V, K, RangeFull, ()>,
+}
+
+impl<K: Ord + Debug, V: Debug> Iterator for ConcurrentKeys<'_, K, V> {
+    type Item = Arc<K>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.scan.next(|_| ()).map(|(k, _)| k)
+    }
+}
+
+impl<K: Ord + Debug, V: Debug> DoubleEndedIterator for ConcurrentKeys<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.scan.next_back(|_| ()).map(|(k, _)| k)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zeyrho::btree::TEST_ORDERS;
+    use crate::zeyrho::btree::tree::BPlusTree;
+    use std::collections::BTreeMap;
+    use std::ops::Bound::{Excluded, Included, Unbounded};
+    use std::thread;
+
+    fn create_tree(order: Order) -> ConcurrentBPlusTree<i32, String> {
+        BPlusTree::builder()
+            .degree(order.degree)
+            .leaf_capacity(order.leaf_capacity)
+            .build_concurrent()
+    }
+
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+
+    #[test]
+    fn test_is_send_and_sync() {
+        assert_send_sync::<ConcurrentBPlusTree<String, Vec<u8>>>();
+    }
+
+    #[test]
+    fn test_matches_btree_map() {
+        for order in TEST_ORDERS {
+            let tree = create_tree(order);
+            let mut expected = BTreeMap::new();
+
+            for i in (0..1000).map(|i| (i * 389) % 1000) {
+                assert_eq!(
+                    tree.insert(i, i.to_string()),
+                    expected.insert(i, i.to_string())
+                );
+            }
+            for i in (0..1000).filter(|i| i % 3 == 0) {
+                assert_eq!(
+                    tree.insert(i, format!("new {}", i)),
+                    expected.insert(i, format!("new {}", i))
+                );
+            }
+            for i in (0..1000).filter(|i| i % 7 == 0) {
+                assert_eq!(tree.delete(&i), expected.remove(&i));
+            }
+
+            for i in -5..1005 {
+                assert_eq!(tree.get(&i).as_deref(), expected.get(&i));
+                assert_eq!(tree.contains_key(&i), expected.contains_key(&i));
+            }
+
+            tree.get_mut(&1).unwrap().push('!');
+            assert!(tree.get_mut(&7).is_none());
+            assert_eq!(tree.get(&1).as_deref().map(String::as_str), Some("1!"));
+        }
+    }
+
+    #[test]
+    fn test_range() {
+        for order in TEST_ORDERS {
+            let tree = create_tree(order);
+            let mut expected = BTreeMap::new();
+            for i in (0..300).map(|i| i * 2) {
+                tree.insert(i, i.to_string());
+                expected.insert(i, i.to_string());
+            }
+            // empties out a run of leaves, which get unlinked
+            for i in (100..300).filter(|i| i % 2 == 0) {
+                tree.delete(&i);
+                expected.remove(&i);
+            }
+
+            let bounds = vec![
+                (Unbounded, Unbounded),
+                (Included(10), Excluded(20)),
+                (Included(11), Included(21)),
+                (Excluded(10), Included(20)),
+                (Included(10), Included(10)),
+                (Included(90), Included(310)),
+                (Included(150), Excluded(250)),
+                (Unbounded, Excluded(7)),
+                (Excluded(500), Unbounded),
+                (Included(-5), Included(1000)),
+                (Included(700), Unbounded),
+            ];
+
+            for bound in bounds {
+                let collected: Vec<i32> = tree.range(bound).map(|(k, _)| *k).collect();
+                let oracle: Vec<i32> = expected.range(bound).map(|(k, _)| *k).collect();
+                assert_eq!(collected, oracle, "forward {:?}", bound);
+
+                let collected: Vec<i32> = tree.range(bound).rev().map(|(k, _)| *k).collect();
+                let oracle: Vec<i32> = expected.range(bound).rev().map(|(k, _)| *k).collect();
+                assert_eq!(collected, oracle, "reverse {:?}", bound);
+            }
+
+            let values: Vec<String> = tree.values().collect();
+            assert_eq!(values, expected.values().cloned().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_range_from_both_ends() {
+        let tree = create_tree(TEST_ORDERS[0]);
+        let mut expected = BTreeMap::new();
+        for i in 0..50 {
+            tree.insert(i, i.to_string());
+            expected.insert(i, i.to_string());
+        }
+
+        for split in 0..=50 {
+            let mut range = tree.range(5..45);
+            let mut oracle = expected.range(5..45);
+
+            for i in 0..split {
+                if i % 2 == 0 {
+                    assert_eq!(
+                        range.next().map(|(k, _)| *k),
+                        oracle.next().map(|(k, _)| *k)
+                    );
+                } else {
+                    assert_eq!(
+                        range.next_back().map(|(k, _)| *k),
+                        oracle.next_back().map(|(k, _)| *k)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_concurrent_writers() {
+        for order in TEST_ORDERS {
+            let tree = create_tree(order);
+
+            thread::scope(|scope| {
+                for t in 0..8 {
+                    let tree = &tree;
+                    scope.spawn(move || {
+                        for i in (0..500).map(|i| i * 8 + t) {
+                            tree.insert(i, i.to_string());
+                        }
+                    });
+                }
+            });
+
+            let collected: Vec<i32> = tree.keys().map(|k| *k).collect();
+            assert_eq!(collected, (0..4000).collect::<Vec<_>>());
+            for i in 0..4000 {
+                assert_eq!(tree.get(&i).as_deref(), Some(&i.to_string()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_concurrent_readers_and_writers() {
+        let tree = create_tree(TEST_ORDERS[0]);
+        for i in (0..2000).filter(|i| i % 2 == 0) {
+            tree.insert(i, i.to_string());
+        }
+
+        thread::scope(|scope| {
+            for t in 0..4 {
+                let tree = &tree;
+                scope.spawn(move || {
+                    for i in (0..1000)
+                        .map(|i| i * 2 + 1)
+                        .filter(|i| i % 4 == t % 2 * 2 + 1)
+                    {
+                        tree.insert(i, i.to_string());
+                    }
+                });
+            }
+            for _ in 0..4 {
+                let tree = &tree;
+                scope.spawn(move || {
+                    for _ in 0..20 {
+                        // the even keys were there before the writers started, so every reader has to see them
+                        for i in (0..2000).filter(|i| i % 2 == 0) {
+                            assert_eq!(tree.get(&i).as_deref(), Some(&i.to_string()));
+                        }
+                        let keys: Vec<i32> = tree.keys().map(|k| *k).collect();
+                        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+                    }
+                });
+            }
+        });
+
+        let collected: Vec<i32> = tree.keys().map(|k| *k).collect();
+        assert_eq!(collected, (0..2000).collect::<Vec<_>>());
+    }
+
+    fn leaf_count(tree: &ConcurrentBPlusTree<i32, String>) -> usize {
+        let mut count = 1;
+        let mut leaf = tree.leftmost_leaf();
+        loop {
+            let next = leaf.read().next.clone();
+            match next {
+                None => return count,
+                Some(next) => {
+                    count += 1;
+                    leaf = next;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_entry() {
+        for order in TEST_ORDERS {
+            let tree = create_tree(order);
+            let mut expected = BTreeMap::new();
+
+            for i in (0..500).map(|i| (i * 37) % 500) {
+                let entry = tree.entry(i);
+                assert_eq!(*entry.key(), i);
+                entry.or_insert(i.to_string());
+                expected.insert(i, i.to_string());
+            }
+            for i in (0..500).filter(|i| i % 3 == 0) {
+                tree.entry(i).and_modify(|v| v.push('!')).or_default();
+                expected.entry(i).and_modify(|v| v.push('!')).or_default();
+            }
+            for i in (0..500).filter(|i| i % 5 == 0) {
+                if let ConcurrentEntry::Occupied(entry) = tree.entry(i) {
+                    assert_eq!(Some(entry.remove()), expected.remove(&i));
+                }
+            }
+
+            match tree.entry(1000) {
+                ConcurrentEntry::Vacant(entry) => {
+                    let mut entry = entry.insert("new".to_string());
+                    assert_eq!(entry.insert("newer".to_string()), "new");
+                    assert_eq!(entry.get(), "newer");
+                }
+                ConcurrentEntry::Occupied(_) => panic!("1000 was never inserted"),
+            }
+            expected.insert(1000, "newer".to_string());
+
+            let collected: Vec<(i32, String)> = tree.iter().map(|(k, v)| (*k, v)).collect();
+            assert_eq!(collected, expected.into_iter().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_range_with_borrowed_keys() {
+        let tree: ConcurrentBPlusTree<String, i32> = BPlusTree::builder()
+            .degree(TEST_ORDERS[0].degree)
+            .leaf_capacity(TEST_ORDERS[0].leaf_capacity)
+            .build_concurrent();
+        for i in 0..100 {
+            tree.insert(format!("{:03}", i), i);
+        }
+
+        let collected: Vec<i32> = tree
+            .range::<str, _>((Included("010"), Excluded("020")))
+            .map(|(_, v)| v)
+            .collect();
+        assert_eq!(collected, (10..20).collect::<Vec<_>>());
+        assert_eq!(tree.get("042").as_deref(), Some(&42));
+    }
+
+    #[test]
+    fn test_deletes_unlink_empty_leaves() {
+        for order in TEST_ORDERS {
+            let tree = create_tree(order);
+            let mut expected = BTreeMap::new();
+            for i in 0..1000 {
+                tree.insert(i, i.to_string());
+                expected.insert(i, i.to_string());
+            }
+            let before = leaf_count(&tree);
+
+            for i in (0..1000).filter(|i| i % 100 != 0) {
+                assert_eq!(tree.delete(&i), expected.remove(&i));
+            }
+            // every leaf that still has a key, plus the rightmost leaf which is never unlinked
+            assert!(leaf_count(&tree) <= expected.len() + 1);
+            assert!(leaf_count(&tree) < before);
+
+            for i in (0..1000).filter(|i| i % 3 == 0) {
+                tree.insert(i, i.to_string());
+                expected.insert(i, i.to_string());
+            }
+            for i in -5..1005 {
+                assert_eq!(tree.get(&i).as_deref(), expected.get(&i));
+            }
+            let collected: Vec<i32> = tree.keys().map(|k| *k).collect();
+            assert_eq!(collected, expected.keys().cloned().collect::<Vec<_>>());
+            let collected: Vec<i32> = tree.keys().rev().map(|k| *k).collect();
+            assert_eq!(
+                collected,
+                expected.keys().rev().cloned().collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn test_concurrent_deletes_and_readers() {
+        let tree = create_tree(TEST_ORDERS[0]);
+        for i in 0..4000 {
+            tree.insert(i, i.to_string());
+        }
+
+        thread::scope(|scope| {
+            for t in 0..4 {
+                let tree = &tree;
+                scope.spawn(move || {
+                    for i in (0..4000).filter(|i| i % 8 == t) {
+                        assert_eq!(tree.delete(&i), Some(i.to_string()));
+                    }
+                });
+            }
+            for _ in 0..4 {
+                let tree = &tree;
+                scope.spawn(move || {
+                    for _ in 0..20 {
+                        // the keys that are never deleted have to be found however many leaves go away around them
+                        for i in (0..4000).filter(|i| i % 8 >= 4) {
+                            assert_eq!(tree.get(&i).as_deref(), Some(&i.to_string()));
+                        }
+                        let keys: Vec<i32> = tree.keys().map(|k| *k).collect();
+                        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+                        assert!(
+                            (0..4000)
+                                .filter(|i| i % 8 >= 4)
+                                .all(|i| keys.binary_search(&i).is_ok())
+                        );
+                    }
+                });
+            }
+        });
+
+        let collected: Vec<i32> = tree.keys().map(|k| *k).collect();
+        assert_eq!(
+            collected,
+            (0..4000).filter(|i| i % 8 >= 4).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use crate::zeyrho::btree::node::Node;
+use crate::zeyrho::btree::tree::BPlusTree;
+use std::cell::{Ref, RefCell, RefMut};
+use std::fmt::Debug;
+use std::rc::Rc;
+
+// Modeled on std::collections::btree_map::Entry. The descent to the leaf happens once in `BPlusTree::entry`, the
+// entries then keep that leaf (and for vacant entries the link nodes above it) so no further lookups are needed.
+pub enum Entry<'a, K: Ord + Debug, V: Debug> {
+    Vacant(VacantEntry<'a, K, V>),
+    Occupied(OccupiedEntry<'a, K, V>),
+}
+
+pub struct VacantEntry<'a, K: Ord + Debug, V: Debug> {
+    pub(super) key: K,
+    pub(super) tree: &'a mut BPlusTree<K, V>,
+    // the link nodes we passed through on the way down, the root first. Splits bubble back up through these
+    pub(super) path: Vec<Rc<RefCell<Node<K, V>>>>,
+    // None when the tree is empty
+    pub(super) leaf: Option<Rc<RefCell<Node<K, V>>>>,
+    pub(super) index: usize,
+}
+
+pub struct OccupiedEntry<'a, K: Ord + Debug, V: Debug> {
+    pub(super) key: Rc<K>,
+    pub(super) tree: &'a mut BPlusTree<K, V>,
+    pub(super) leaf: Rc<RefCell<Node<K, V>>>,
+    pub(super) index: usize,
+}
+
+impl<'a, K: Ord + Debug, V: Debug> Entry<'a, K, V> {
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Vacant(entry) => entry.key(),
+            Entry::Occupied(entry) => entry.key(),
+        }
+    }
+
+    pub fn or_insert(self, default: V) -> OccupiedEntry<'a, K, V> {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> OccupiedEntry<'a, K, V> {
+        match self {
+            Entry::Vacant(entry) => entry.insert(default()),
+            Entry::Occupied(entry) => entry,
+        }
+    }
+
+    pub fn or_default(self) -> OccupiedEntry<'a, K, V>
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        match self {
+            Entry::Vacant(entry) => Entry::Vacant(entry),
+            Entry::Occupied(mut entry) => {
+                f(&mut entry.get_mut());
+                Entry::Occupied(entry)
+            }
+        }
+    }
+}
+
+impl<'a, K: Ord + Debug, V: Debug> VacantEntry<'a, K, V> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    // std hands back a `&'a mut V` here, but our values sit behind the leaf's RefCell so we return the entry instead
+    pub fn insert(self, value: V) -> OccupiedEntry<'a, K, V> {
+        let key = Rc::new(self.key);
+        let tree = self.tree;
+        let order = tree.order;
+
+        let Some(leaf) = self.leaf else {
+            let leaf = Node::new_leaf_with_kv(key.clone(), value);
+            tree.root = Some(leaf.clone());
+            return OccupiedEntry {
+                key,
+                tree,
+                leaf,
+                index: 0,
+            };
+        };
+
+        let (mut split, entry_leaf, entry_index) = {
+            let mut leaf_ref = leaf.borrow_mut();
+            let len = leaf_ref.insert_into_leaf(self.index, key.clone(), value);
+
+            if len <= order.max_kvs_in_leaf() {
+                (None, leaf.clone(), self.index)
+            } else {
+                // the split keeps the first half in this leaf and moves the rest into the new right leaf
+                let mid = len / 2;
+                let (separator, new_right) = leaf_ref.split_borrowed_leaf_node(&leaf);
+                let (entry_leaf, entry_index) = if self.index < mid {
+                    (leaf.clone(), self.index)
+                } else {
+                    (new_right.clone(), self.index - mid)
+                };
+
+                (Some((separator, new_right)), entry_leaf, entry_index)
+            }
+        };
+
+        let mut path = self.path;
+        while let Some((separator, new_node)) = split.take() {
+            match path.pop() {
+                Some(parent) => {
+                    let mut parent_ref = parent.borrow_mut();
+                    let overflowing = match &mut *parent_ref {
+                        Node::Link {
+                            separators,
+                            children,
+                        } => {
+                            Node::insert_separator_and_child_into_link(
+                                separators, children, separator, new_node,
+                            );
+                            separators.len() > order.separators_max_size()
+                        }
+                        Node::Leaf { .. } => unreachable!("entry path contains a leaf node"),
+                    };
+
+                    if overflowing {
+                        split = Some(parent_ref.split_borrowed_link_node());
+                    }
+                }
+                None => {
+                    // we split the root, so the tree grows by one level
+                    let new_root = Rc::new(RefCell::new(Node::Link {
+                        separators: vec![separator],
+                        children: vec![tree.root.take().unwrap(), new_node],
+                    }));
+
+                    tree.root = Some(new_root);
+                }
+            }
+        }
+
+        OccupiedEntry {
+            key,
+            tree,
+            leaf: entry_leaf,
+            index: entry_index,
+        }
+    }
+}
+
+impl<K: Ord + Debug, V: Debug> OccupiedEntry<'_, K, V> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn get(&self) -> Ref<'_, V> {
+        Ref::map(self.leaf.borrow(), |node| match node {
+            Node::Leaf { key_vals, .. } => &key_vals[self.index].1,
+            Node::Link { .. } => unreachable!("occupied entry is pointing at a link node"),
+        })
+    }
+
+    pub fn get_mut(&mut self) -> RefMut<'_, V> {
+        RefMut::map(self.leaf.borrow_mut(), |node| match node {
+            Node::Leaf { key_vals, .. } => &mut key_vals[self.index].1,
+            Node::Link { .. } => unreachable!("occupied entry is pointing at a link node"),
+        })
+    }
+
+    pub fn insert(&mut self, value: V) -> V {
+        std::mem::replace(&mut *self.get_mut(), value)
+    }
+
+    pub fn remove(self) -> V {
+        let OccupiedEntry {
+            key, tree, leaf, ..
+        } = self;
+        // let go of the leaf first, it may get merged away by the delete
+        drop(leaf);
+
+        tree.delete(key.as_ref())
+            .expect("occupied entry key is missing from the tree")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::zeyrho::btree::TEST_ORDERS;
+    use crate::zeyrho::btree::entry::Entry;
+    use crate::zeyrho::btree::tree::BPlusTree;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_insert_replaces_existing_value() {
+        let mut tree = BPlusTree::new();
+        for i in 0..20 {
+            assert_eq!(tree.insert(i, i.to_string()), None);
+        }
+        for i in 0..20 {
+            assert_eq!(tree.insert(i, format!("new {}", i)), Some(i.to_string()));
+        }
+
+        let collected: Vec<(i32, String)> = tree.iter().map(|(k, v)| (*k, v)).collect();
+        let expected: Vec<(i32, String)> = (0..20).map(|i| (i, format!("new {}", i))).collect();
+        assert_eq!(collected, expected);
+    }
+
+    #[test]
+    fn test_entry_counts() {
+        let mut tree: BPlusTree<String, usize> = BPlusTree::new();
+        let mut expected = BTreeMap::new();
+
+        let words = "the quick brown fox jumps over the lazy dog the end";
+        for word in words.split(' ') {
+            *tree.entry(word.to_string()).or_default().get_mut() += 1;
+            *expected.entry(word.to_string()).or_default() += 1;
+        }
+
+        let collected: Vec<(String, usize)> =
+            tree.iter().map(|(k, v)| (k.as_ref().clone(), v)).collect();
+        assert_eq!(collected, expected.into_iter().collect::<Vec<_>>());
+        assert_eq!(tree.get("the").map(|v| *v), Some(3));
+    }
+
+    #[test]
+    fn test_entry_variants() {
+        let mut tree = BPlusTree::new();
+        tree.insert(1, 10);
+
+        assert!(matches!(tree.entry(1), Entry::Occupied(_)));
+        assert!(matches!(tree.entry(2), Entry::Vacant(_)));
+        assert_eq!(tree.entry(2).key(), &2);
+
+        tree.entry(1).and_modify(|v| *v += 1).or_insert(0);
+        tree.entry(2).and_modify(|v| *v += 1).or_insert(20);
+        assert_eq!(tree.get(&1).map(|v| *v), Some(11));
+        assert_eq!(tree.get(&2).map(|v| *v), Some(20));
+
+        if let Entry::Occupied(mut entry) = tree.entry(2) {
+            assert_eq!(*entry.get(), 20);
+            assert_eq!(entry.insert(21), 20);
+            assert_eq!(entry.remove(), 21);
+        } else {
+            panic!("entry for 2 should be occupied");
+        }
+        assert!(tree.get(&2).is_none());
+    }
+
+    #[test]
+    fn test_vacant_insert_points_at_value_after_split() {
+        for order in TEST_ORDERS {
+            let mut tree = BPlusTree::builder()
+                .degree(order.degree)
+                .leaf_capacity(order.leaf_capacity)
+                .build();
+            let mut expected = BTreeMap::new();
+
+            // inserting in a scattered order splits leaves on both sides of the new key
+            for i in (0..500).map(|i| (i * 73) % 500) {
+                let entry = tree.entry(i).or_insert(i * 2);
+                assert_eq!(entry.key(), &i);
+                assert_eq!(*entry.get(), i * 2);
+                expected.insert(i, i * 2);
+            }
+
+            let collected: Vec<(i32, i32)> = tree.iter().map(|(k, v)| (*k, v)).collect();
+            assert_eq!(collected, expected.into_iter().collect::<Vec<_>>());
+        }
+    }
+}
@@ -0,0 +1,349 @@
+use crate::zeyrho::btree::node::Node;
+use crate::zeyrho::btree::tree::BPlusTree;
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::rc::{Rc, Weak};
+
+// a position between two entries of a leaf, `index` is the entry that comes right after the position
+pub(super) struct LeafCursor<K: Ord + Debug, V: Debug> {
+    leaf: Rc<RefCell<Node<K, V>>>,
+    index: usize,
+}
+
+// where a walk starts from at the front and the back, None when the tree is empty
+pub(super) type RangeEnds<K, V> = (Option<LeafCursor<K, V>>, Option<LeafCursor<K, V>>);
+
+impl<K: Ord + Debug, V: Debug> LeafCursor<K, V> {
+    pub(super) fn new(leaf: Rc<RefCell<Node<K, V>>>, index: usize) -> Self {
+        LeafCursor { leaf, index }
+    }
+
+    // the end of one leaf and the start of the next are the same position, the front cursor always sits on the latter
+    fn skip_forward(&mut self) {
+        loop {
+            let next = match &*self.leaf.borrow() {
+                Node::Leaf { key_vals, next, .. } if self.index >= key_vals.len() => {
+                    next.as_ref().and_then(Weak::upgrade)
+                }
+                _ => None,
+            };
+
+            match next {
+                None => return,
+                Some(next) => {
+                    self.leaf = next;
+                    self.index = 0;
+                }
+            }
+        }
+    }
+
+    // and the back cursor always sits on the former
+    fn skip_backward(&mut self) {
+        loop {
+            let prev = match &*self.leaf.borrow() {
+                Node::Leaf { prev, .. } if self.index == 0 => prev.as_ref().and_then(Weak::upgrade),
+                _ => None,
+            };
+
+            match prev {
+                None => return,
+                Some(prev) => {
+                    self.index = prev.borrow().leaf_len();
+                    self.leaf = prev;
+                }
+            }
+        }
+    }
+}
+
+// The two ends of a walk along the leaf chain, which is all `Range` and `Keys` differ in is what they take out of each
+// entry they pass.
+struct LeafWalk<K: Ord + Debug, V: Debug> {
+    front: Option<LeafCursor<K, V>>,
+    back: Option<LeafCursor<K, V>>,
+}
+
+impl<K: Ord + Debug, V: Debug> LeafWalk<K, V> {
+    fn new(front: Option<LeafCursor<K, V>>, back: Option<LeafCursor<K, V>>) -> Self {
+        let mut walk = LeafWalk { front, back };
+
+        if let Some(front) = walk.front.as_mut() {
+            front.skip_forward();
+        }
+        if let Some(back) = walk.back.as_mut() {
+            back.skip_backward();
+        }
+
+        walk
+    }
+
+    fn is_exhausted(&self) -> bool {
+        let (Some(front), Some(back)) = (&self.front, &self.back) else {
+            return true;
+        };
+
+        if Rc::ptr_eq(&front.leaf, &back.leaf) {
+            return front.index >= back.index;
+        }
+
+        // the cursors can also meet across a leaf boundary, the back at the end of a leaf and the front at the start
+        // of the one after it
+        match &*back.leaf.borrow() {
+            Node::Leaf { key_vals, next, .. } => {
+                front.index == 0
+                    && back.index == key_vals.len()
+                    && next
+                        .as_ref()
+                        .and_then(Weak::upgrade)
+                        .is_some_and(|next| Rc::ptr_eq(&next, &front.leaf))
+            }
+            Node::Link { .. } => unreachable!("range cursor is pointing at a link node"),
+        }
+    }
+
+    fn next<T>(&mut self, take: impl FnOnce(&Node<K, V>, usize) -> T) -> Option<T> {
+        if self.is_exhausted() {
+            return None;
+        }
+
+        let front = self.front.as_mut()?;
+        let item = take(&front.leaf.borrow(), front.index);
+        front.index += 1;
+        front.skip_forward();
+
+        Some(item)
+    }
+
+    fn next_back<T>(&mut self, take: impl FnOnce(&Node<K, V>, usize) -> T) -> Option<T> {
+        if self.is_exhausted() {
+            return None;
+        }
+
+        let back = self.back.as_mut()?;
+        back.index -= 1;
+        let item = take(&back.leaf.borrow(), back.index);
+        back.skip_backward();
+
+        Some(item)
+    }
+}
+
+// Walks the leaf chain between two cursors. Values live behind each leaf's RefCell so items are cloned out of the leaf
+// rather than borrowed, the keys are already reference counted so handing out another Rc is cheap.
+pub struct Range<'a, K: Ord + Debug, V: Debug> {
+    walk: LeafWalk<K, V>,
+    _tree: PhantomData<&'a BPlusTree<K, V>>,
+}
+
+impl<K: Ord + Debug, V: Debug> Range<'_, K, V> {
+    pub(super) fn new(front: Option<LeafCursor<K, V>>, back: Option<LeafCursor<K, V>>) -> Self {
+        Range {
+            walk: LeafWalk::new(front, back),
+            _tree: PhantomData,
+        }
+    }
+}
+
+impl<K: Ord + Debug, V: Debug + Clone> Iterator for Range<'_, K, V> {
+    type Item = (Rc<K>, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.walk.next(Node::leaf_entry)
+    }
+}
+
+impl<K: Ord + Debug, V: Debug + Clone> DoubleEndedIterator for Range<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.walk.next_back(Node::leaf_entry)
+    }
+}
+
+// the same walk as `Range` that only hands out the keys, so the values never need to be cloned
+pub struct Keys<'a, K: Ord + Debug, V: Debug> {
+    walk: LeafWalk<K, V>,
+    _tree: PhantomData<&'a BPlusTree<K, V>>,
+}
+
+impl<K: Ord + Debug, V: Debug> Keys<'_, K, V> {
+    pub(super) fn new(front: Option<LeafCursor<K, V>>, back: Option<LeafCursor<K, V>>) -> Self {
+        Keys {
+            walk: LeafWalk::new(front, back),
+            _tree: PhantomData,
+        }
+    }
+}
+
+impl<K: Ord + Debug, V: Debug> Iterator for Keys<'_, K, V> {
+    type Item = Rc<K>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.walk.next(Node::leaf_key)
+    }
+}
+
+impl<K: Ord + Debug, V: Debug> DoubleEndedIterator for Keys<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.walk.next_back(Node::leaf_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::zeyrho::btree::TEST_ORDERS;
+    use crate::zeyrho::btree::tree::BPlusTree;
+    use std::collections::BTreeMap;
+    use std::ops::Bound::{Excluded, Included, Unbounded};
+    use std::ops::{Bound, Deref};
+
+    // builds the same keys into a tree of every test order, along with a BTreeMap to compare against
+    fn create_trees(
+        keys: impl Iterator<Item = i32> + Clone,
+    ) -> impl Iterator<Item = (BPlusTree<i32, String>, BTreeMap<i32, String>)> {
+        TEST_ORDERS.into_iter().map(move |order| {
+            let mut tree = BPlusTree::builder()
+                .degree(order.degree)
+                .leaf_capacity(order.leaf_capacity)
+                .build();
+            let mut expected = BTreeMap::new();
+            for i in keys.clone() {
+                tree.insert(i, i.to_string());
+                expected.insert(i, i.to_string());
+            }
+            (tree, expected)
+        })
+    }
+
+    #[test]
+    fn test_iter() {
+        for (tree, expected) in create_trees((0..100).rev()) {
+            let collected: Vec<(i32, String)> = tree.iter().map(|(k, v)| (*k, v)).collect();
+            assert_eq!(collected, expected.clone().into_iter().collect::<Vec<_>>());
+
+            let reversed: Vec<i32> = tree.keys().rev().map(|k| *k).collect();
+            assert_eq!(reversed, expected.keys().rev().copied().collect::<Vec<_>>());
+
+            let values: Vec<String> = tree.values().collect();
+            assert_eq!(values, expected.values().cloned().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_keys_without_cloning_values() {
+        #[derive(Debug)]
+        struct NotClone(i32);
+
+        let mut tree = BPlusTree::builder().degree(3).leaf_capacity(2).build();
+        for i in 0..50 {
+            tree.insert(i, NotClone(i));
+        }
+
+        let keys: Vec<i32> = tree.keys().map(|k| *k).collect();
+        assert_eq!(keys, (0..50).collect::<Vec<_>>());
+        let reversed: Vec<i32> = tree.keys().rev().map(|k| *k).collect();
+        assert_eq!(reversed, (0..50).rev().collect::<Vec<_>>());
+        assert_eq!(tree.get(&7).unwrap().0, 7);
+    }
+
+    #[test]
+    fn test_empty_tree() {
+        for (tree, _) in create_trees(0..0) {
+            assert_eq!(tree.iter().next(), None);
+            assert_eq!(tree.iter().next_back(), None);
+            assert_eq!(tree.keys().next(), None);
+            assert_eq!(tree.range(1..5).next(), None);
+        }
+    }
+
+    #[test]
+    fn test_range_bounds() {
+        for (tree, expected) in create_trees((0..60).map(|i| i * 2)) {
+            let bounds: Vec<(Bound<i32>, Bound<i32>)> = vec![
+                (Included(10), Excluded(20)),
+                (Included(11), Included(21)),
+                (Excluded(10), Included(20)),
+                (Included(10), Included(10)),
+                (Included(10), Excluded(10)),
+                (Unbounded, Excluded(7)),
+                (Excluded(100), Unbounded),
+                (Included(-5), Included(500)),
+                (Included(300), Unbounded),
+                (Unbounded, Excluded(0)),
+            ];
+
+            for bound in bounds {
+                let collected: Vec<i32> = tree.range(bound).map(|(k, _)| *k).collect();
+                let oracle: Vec<i32> = expected.range(bound).map(|(k, _)| *k).collect();
+                assert_eq!(collected, oracle, "forward {:?}", bound);
+
+                let collected: Vec<i32> = tree.range(bound).rev().map(|(k, _)| *k).collect();
+                let oracle: Vec<i32> = expected.range(bound).rev().map(|(k, _)| *k).collect();
+                assert_eq!(collected, oracle, "reverse {:?}", bound);
+            }
+        }
+    }
+
+    #[test]
+    fn test_range_from_both_ends() {
+        for (tree, expected) in create_trees(0..50) {
+            for split in 0..=50 {
+                let mut range = tree.range(5..45);
+                let mut oracle = expected.range(5..45);
+
+                let mut collected = Vec::new();
+                let mut expected_keys = Vec::new();
+                for i in 0..split {
+                    if i % 2 == 0 {
+                        collected.push(range.next().map(|(k, _)| *k));
+                        expected_keys.push(oracle.next().map(|(k, _)| *k));
+                    } else {
+                        collected.push(range.next_back().map(|(k, _)| *k));
+                        expected_keys.push(oracle.next_back().map(|(k, _)| *k));
+                    }
+                }
+                assert_eq!(collected, expected_keys);
+            }
+        }
+    }
+
+    #[test]
+    fn test_range_after_deletes() {
+        for (mut tree, mut expected) in create_trees(0..100) {
+            for i in (0..100).filter(|i| i % 3 != 0) {
+                tree.delete(&i);
+                expected.remove(&i);
+            }
+
+            let collected: Vec<i32> = tree.range(10..=90).map(|(k, _)| *k).collect();
+            let oracle: Vec<i32> = expected.range(10..=90).map(|(k, _)| *k).collect();
+            assert_eq!(collected, oracle);
+
+            let collected: Vec<i32> = tree.range(10..=90).rev().map(|(k, _)| *k).collect();
+            let oracle: Vec<i32> = expected.range(10..=90).rev().map(|(k, _)| *k).collect();
+            assert_eq!(collected, oracle);
+        }
+    }
+
+    #[test]
+    fn test_range_borrowed_key() {
+        let mut tree: BPlusTree<String, i32> = BPlusTree::new();
+        for key in ["apple", "banana", "cherry", "date"] {
+            tree.insert(key.to_string(), key.len() as i32);
+        }
+
+        let collected: Vec<String> = tree
+            .range::<str, _>((Included("b"), Excluded("d")))
+            .map(|(k, _)| k.deref().clone())
+            .collect();
+        assert_eq!(collected, vec!["banana", "cherry"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "range start is greater than range end")]
+    #[allow(clippy::reversed_empty_ranges)]
+    fn test_range_start_after_end() {
+        let (tree, _) = create_trees(0..10).next().unwrap();
+        let _ = tree.range(5..3);
+    }
+}
@@ -1,7 +1,64 @@
+pub mod concurrent;
+pub mod entry;
+pub mod iter;
 mod node;
 pub mod tree;
 
-const DEGREE: usize = 3;
-const SEPARATORS_MAX_SIZE: usize = DEGREE - 1;
-const CHILDREN_MAX_SIZE: usize = DEGREE - 1;
-const MAX_KVS_IN_LEAF: usize = DEGREE - 1;
+const DEFAULT_DEGREE: usize = 64;
+const DEFAULT_LEAF_CAPACITY: usize = 64;
+
+// orders the tests are run against, the first one is the original degree 3 tree
+#[cfg(test)]
+const TEST_ORDERS: [Order; 5] = [
+    Order::new(3, 2),
+    Order::new(4, 3),
+    Order::new(5, 8),
+    Order::new(16, 4),
+    Order::new(64, 64),
+];
+
+// `degree` is the most children a link node can have, `leaf_capacity` the most key values a leaf can hold. Both split
+// once they go over, and every node other than the root has to stay at least half full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Order {
+    degree: usize,
+    leaf_capacity: usize,
+}
+
+impl Order {
+    const fn new(degree: usize, leaf_capacity: usize) -> Self {
+        // a degree 2 link would split into a node with no separators, and a leaf needs to split into two non-empty halves
+        assert!(degree >= 3, "BPlusTree degree must be at least 3");
+        assert!(
+            leaf_capacity >= 2,
+            "BPlusTree leaf capacity must be at least 2"
+        );
+
+        Order {
+            degree,
+            leaf_capacity,
+        }
+    }
+
+    fn separators_max_size(&self) -> usize {
+        self.degree - 1
+    }
+
+    fn separators_min_size(&self) -> usize {
+        self.degree.div_ceil(2) - 1
+    }
+
+    fn max_kvs_in_leaf(&self) -> usize {
+        self.leaf_capacity
+    }
+
+    fn min_kvs_in_leaf(&self) -> usize {
+        self.leaf_capacity.div_ceil(2)
+    }
+}
+
+impl Default for Order {
+    fn default() -> Self {
+        Order::new(DEFAULT_DEGREE, DEFAULT_LEAF_CAPACITY)
+    }
+}
@@ -1,7 +1,6 @@
-use crate::zeyrho::btree::{DEGREE, MAX_KVS_IN_LEAF, SEPARATORS_MAX_SIZE};
+use crate::zeyrho::btree::Order;
 use std::cell::RefCell;
 use std::fmt::{Debug, Display, Formatter};
-use std::ops::Deref;
 use std::rc::{Rc, Weak};
 
 #[derive(Debug, Clone)]
@@ -13,8 +12,8 @@ pub enum Node<K: Ord + Debug, V: Debug> {
     },
     Link {
         // TODO: Should these be Vec<Option<>>? It makes it a lot easier to know if we need to insert something new.
-        separators: Vec<Rc<K>>, // a link has DEGREE - 1 separators
-        children: Vec<Rc<RefCell<Node<K, V>>>>, // and DEGREE children
+        separators: Vec<Rc<K>>, // a link has up to degree - 1 separators
+        children: Vec<Rc<RefCell<Node<K, V>>>>, // and up to degree children
     },
 }
 
@@ -107,14 +106,6 @@ impl<K: Debug + Ord, V: Debug> Display for Node<K, V> {
 }
 
 impl<K: Ord + Debug, V: Debug> Node<K, V> {
-    pub(super) fn new_leaf() -> Self {
-        Node::Leaf {
-            key_vals: Vec::new(),
-            next: None,
-            prev: None,
-        }
-    }
-
     pub(super) fn new_link() -> Rc<RefCell<Self>> {
         Rc::new(RefCell::new(Node::Link {
             separators: Vec::new(),
@@ -123,16 +114,68 @@ impl<K: Ord + Debug, V: Debug> Node<K, V> {
     }
 
     pub(super) fn new_leaf_with_kv(key: Rc<K>, value: V) -> Rc<RefCell<Self>> {
-        let mut vec = Vec::new();
-        vec.push((key, value));
-
         Rc::new(RefCell::new(Node::Leaf {
-            key_vals: vec,
+            key_vals: vec![(key, value)],
             next: None,
             prev: None,
         }))
     }
 
+    // index of the child whose subtree can contain `key`, keys equal to a separator live in the child to its right
+    pub(super) fn child_index<Q>(separators: &[Rc<K>], key: &Q) -> usize
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        separators.partition_point(|k| <K as std::borrow::Borrow<Q>>::borrow(k) <= key)
+    }
+
+    pub(super) fn search_leaf<Q>(key_vals: &[(Rc<K>, V)], key: &Q) -> Result<usize, usize>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        key_vals.binary_search_by(|(k, _)| <K as std::borrow::Borrow<Q>>::borrow(k).cmp(key))
+    }
+
+    pub(super) fn leaf_len(&self) -> usize {
+        match self {
+            Node::Leaf { key_vals, .. } => key_vals.len(),
+            Node::Link { .. } => panic!("trying to get leaf length of link node"),
+        }
+    }
+
+    pub(super) fn leaf_entry(&self, index: usize) -> (Rc<K>, V)
+    where
+        V: Clone,
+    {
+        match self {
+            Node::Leaf { key_vals, .. } => {
+                let (k, v) = &key_vals[index];
+                (k.clone(), v.clone())
+            }
+            Node::Link { .. } => panic!("trying to get leaf entry of link node"),
+        }
+    }
+
+    pub(super) fn leaf_key(&self, index: usize) -> Rc<K> {
+        match self {
+            Node::Leaf { key_vals, .. } => key_vals[index].0.clone(),
+            Node::Link { .. } => panic!("trying to get leaf key of link node"),
+        }
+    }
+
+    // inserts the key value at `index` and returns the new length of the leaf, the caller splits it if it got too big
+    pub(super) fn insert_into_leaf(&mut self, index: usize, key: Rc<K>, value: V) -> usize {
+        match self {
+            Node::Leaf { key_vals, .. } => {
+                key_vals.insert(index, (key, value));
+                key_vals.len()
+            }
+            Node::Link { .. } => panic!("trying to insert key value into link node"),
+        }
+    }
+
     pub(super) fn insert_separator_and_child_into_link(
         separators: &mut Vec<Rc<K>>,
         children: &mut Vec<Rc<RefCell<Node<K, V>>>>,
@@ -187,18 +230,6 @@ impl<K: Ord + Debug, V: Debug> Node<K, V> {
         }
     }
 
-    // splitting a link node with separators 1, 2, 3, should result in a new link node with a single separator of 2 and child link nodes of 1, 3
-    pub(super) fn split_link_node(
-        self_rc: &Rc<RefCell<Self>>,
-    ) -> (Rc<RefCell<Self>>, Rc<K>, Rc<RefCell<Self>>) {
-        let (sep, new_right) = (*self_rc.borrow_mut()).split_borrowed_link_node();
-
-        if let Node::Link { children, .. } = new_right.borrow().deref() {
-            return (children[0].clone(), sep, new_right.clone());
-        }
-        panic!("should not fail")
-    }
-
     // returns the new separator and the new right node. Self will become the left node
     pub(super) fn split_borrowed_leaf_node(
         &mut self,
@@ -212,10 +243,23 @@ impl<K: Ord + Debug, V: Debug> Node<K, V> {
 
             let new_right_node = Rc::new(RefCell::new(Node::Leaf {
                 key_vals: new_keys_padded,
-                next: next.take().map(|maybe_weak| maybe_weak.clone()),
+                next: next.take(),
                 prev: Some(Rc::downgrade(rc_self)),
             }));
 
+            // the leaf that used to follow us now needs to point back at the new right node
+            if let Node::Leaf {
+                next: Some(old_next),
+                ..
+            } = &*new_right_node.borrow()
+            {
+                if let Some(old_next) = old_next.upgrade() {
+                    if let Node::Leaf { prev, .. } = &mut *old_next.borrow_mut() {
+                        *prev = Some(Rc::downgrade(&new_right_node));
+                    }
+                }
+            }
+
             *next = Some(Rc::downgrade(&new_right_node));
 
             (split_point, new_right_node)
@@ -224,6 +268,7 @@ impl<K: Ord + Debug, V: Debug> Node<K, V> {
         }
     }
 
+    #[cfg(test)]
     pub(super) fn split_leaf_node(
         link_to_self: &Rc<RefCell<Self>>,
     ) -> (Rc<RefCell<Self>>, Rc<K>, Rc<RefCell<Self>>) {
@@ -231,80 +276,190 @@ impl<K: Ord + Debug, V: Debug> Node<K, V> {
         (link_to_self.clone(), split, right)
     }
 
-    // the left Option is the new separator and the right is the new right node. We don't need to do anything with the left node b/c the parent is already pointing to it
-    pub(super) fn insert_internal(
-        node: &Rc<RefCell<Node<K, V>>>,
-        inserted_key: Rc<K>,
-        inserted_value: V,
-    ) -> Option<(Rc<K>, Rc<RefCell<Node<K, V>>>)> {
-        let mut node_ref = node.borrow_mut();
-        match &mut *node_ref {
-            Node::Leaf { key_vals, .. } => {
-                let pos = key_vals
-                    .iter()
-                    .position(|(k, _)| k.as_ref() > inserted_key.as_ref())
-                    .unwrap_or(key_vals.len());
+    fn is_underflowing(&self, order: &Order) -> bool {
+        match self {
+            Node::Leaf { key_vals, .. } => key_vals.len() < order.min_kvs_in_leaf(),
+            Node::Link { separators, .. } => separators.len() < order.separators_min_size(),
+        }
+    }
 
-                key_vals.insert(pos, (inserted_key, inserted_value));
+    fn can_lend(&self, order: &Order) -> bool {
+        match self {
+            Node::Leaf { key_vals, .. } => key_vals.len() > order.min_kvs_in_leaf(),
+            Node::Link { separators, .. } => separators.len() > order.separators_min_size(),
+        }
+    }
 
-                if key_vals.len() <= MAX_KVS_IN_LEAF {
-                    return None;
-                }
+    // called by a link node after deleting from the child at `index`. If that child dropped below the minimum size we
+    // first try to borrow an entry from a sibling and only merge with one when neither sibling has anything to spare
+    pub(super) fn rebalance_child(
+        separators: &mut Vec<Rc<K>>,
+        children: &mut Vec<Rc<RefCell<Node<K, V>>>>,
+        index: usize,
+        order: &Order,
+    ) {
+        if !children[index].borrow().is_underflowing(order) {
+            return;
+        }
 
-                let (split, new_right) = (*node_ref).split_borrowed_leaf_node(node);
+        let has_left = index > 0;
+        let has_right = index + 1 < children.len();
+
+        if has_left && children[index - 1].borrow().can_lend(order) {
+            Node::borrow_from_left(separators, children, index);
+        } else if has_right && children[index + 1].borrow().can_lend(order) {
+            Node::borrow_from_right(separators, children, index);
+        } else if has_left {
+            Node::merge_children(separators, children, index - 1);
+        } else if has_right {
+            Node::merge_children(separators, children, index);
+        }
+    }
 
-                Some((split, new_right))
-            }
-            Node::Link {
-                separators,
-                children,
-            } => {
-                let mut child_to_update = separators
-                    .iter()
-                    .position(|k| k.as_ref() > inserted_key.as_ref());
-
-                // if we're inserting the biggest and the child location is empty then create new leaf and return current link
-                if child_to_update.is_none() {
-                    if separators.len() == SEPARATORS_MAX_SIZE {
-                        // here we must insert into the right most subtree
-                        if children.get(DEGREE - 1).is_none() {
-                            // no child is here, we need to make a new one
-                            let new_leaf = Node::new_leaf_with_kv(inserted_key, inserted_value);
-                            children.push(new_leaf);
-                            return None;
-                        }
-                    }
-                    child_to_update = Some(children.len() - 1);
-                }
+    fn borrow_from_left(
+        separators: &mut [Rc<K>],
+        children: &[Rc<RefCell<Node<K, V>>>],
+        index: usize,
+    ) {
+        let mut left_ref = children[index - 1].borrow_mut();
+        let mut child_ref = children[index].borrow_mut();
 
-                let child = children[child_to_update.unwrap()].clone();
-
-                if let Some((new_separator, new_node)) =
-                    Node::insert_internal(&child, inserted_key, inserted_value)
-                // self.insert_internal(&child, inserted_key, inserted_value)
-                {
-                    Node::insert_separator_and_child_into_link(
-                        separators,
-                        children,
-                        new_separator,
-                        new_node,
-                    );
-                    if separators.len() <= SEPARATORS_MAX_SIZE {
-                        return None;
-                    }
+        match (&mut *left_ref, &mut *child_ref) {
+            (
+                Node::Leaf {
+                    key_vals: left_kvs, ..
+                },
+                Node::Leaf {
+                    key_vals: child_kvs,
+                    ..
+                },
+            ) => {
+                let borrowed = left_kvs.pop().unwrap();
+                separators[index - 1] = borrowed.0.clone();
+                child_kvs.insert(0, borrowed);
+            }
+            (
+                Node::Link {
+                    separators: left_separators,
+                    children: left_children,
+                },
+                Node::Link {
+                    separators: child_separators,
+                    children: child_children,
+                },
+            ) => {
+                // the parent separator rotates down into the child and the left sibling's last separator replaces it
+                let borrowed_separator = left_separators.pop().unwrap();
+                let parent_separator =
+                    std::mem::replace(&mut separators[index - 1], borrowed_separator);
+                child_separators.insert(0, parent_separator);
+                child_children.insert(0, left_children.pop().unwrap());
+            }
+            (_, _) => panic!("siblings are at different depths"),
+        }
+    }
 
-                    let (new_sep, new_right) = (*node_ref).split_borrowed_link_node();
+    fn borrow_from_right(
+        separators: &mut [Rc<K>],
+        children: &[Rc<RefCell<Node<K, V>>>],
+        index: usize,
+    ) {
+        let mut child_ref = children[index].borrow_mut();
+        let mut right_ref = children[index + 1].borrow_mut();
+
+        match (&mut *child_ref, &mut *right_ref) {
+            (
+                Node::Leaf {
+                    key_vals: child_kvs,
+                    ..
+                },
+                Node::Leaf {
+                    key_vals: right_kvs,
+                    ..
+                },
+            ) => {
+                child_kvs.push(right_kvs.remove(0));
+                separators[index] = right_kvs[0].0.clone();
+            }
+            (
+                Node::Link {
+                    separators: child_separators,
+                    children: child_children,
+                },
+                Node::Link {
+                    separators: right_separators,
+                    children: right_children,
+                },
+            ) => {
+                let borrowed_separator = right_separators.remove(0);
+                let parent_separator =
+                    std::mem::replace(&mut separators[index], borrowed_separator);
+                child_separators.push(parent_separator);
+                child_children.push(right_children.remove(0));
+            }
+            (_, _) => panic!("siblings are at different depths"),
+        }
+    }
 
-                    return Some((new_sep, new_right));
+    // merges the child at `left_index + 1` into the child at `left_index` and removes the separator between them
+    fn merge_children(
+        separators: &mut Vec<Rc<K>>,
+        children: &mut Vec<Rc<RefCell<Node<K, V>>>>,
+        left_index: usize,
+    ) {
+        let separator = separators.remove(left_index);
+        let right = children.remove(left_index + 1);
+        let left = &children[left_index];
+
+        let mut left_ref = left.borrow_mut();
+        let mut right_ref = right.borrow_mut();
+
+        match (&mut *left_ref, &mut *right_ref) {
+            (
+                Node::Leaf {
+                    key_vals: left_kvs,
+                    next: left_next,
+                    ..
+                },
+                Node::Leaf {
+                    key_vals: right_kvs,
+                    next: right_next,
+                    prev: right_prev,
+                },
+            ) => {
+                left_kvs.append(right_kvs);
+
+                // we unlink the right leaf here instead of relying on its Drop, which would try to borrow the left
+                // leaf while we are still holding it
+                *right_prev = None;
+                *left_next = right_next.take();
+                if let Some(new_next) = left_next.as_ref().and_then(Weak::upgrade) {
+                    if let Node::Leaf { prev, .. } = &mut *new_next.borrow_mut() {
+                        *prev = Some(Rc::downgrade(left));
+                    }
                 }
-
-                None
             }
+            (
+                Node::Link {
+                    separators: left_separators,
+                    children: left_children,
+                },
+                Node::Link {
+                    separators: right_separators,
+                    children: right_children,
+                },
+            ) => {
+                left_separators.push(separator);
+                left_separators.append(right_separators);
+                left_children.append(right_children);
+            }
+            (_, _) => panic!("siblings are at different depths"),
         }
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_borrow, clippy::assertions_on_constants)]
 mod tests {
     use super::*;
     use std::ops::Deref;
@@ -351,8 +506,8 @@ mod tests {
             return;
         }
         for rc_node_index in 1..leaves.len() {
-            let mut first = &leaves[rc_node_index - 1];
-            let mut second = &leaves[rc_node_index];
+            let first = &leaves[rc_node_index - 1];
+            let second = &leaves[rc_node_index];
             let mut first_ref = first.borrow_mut();
             let mut second_ref = second.borrow_mut();
 
@@ -1,6 +1,12 @@
+use crate::zeyrho::btree::concurrent::ConcurrentBPlusTree;
+use crate::zeyrho::btree::entry::{Entry, OccupiedEntry, VacantEntry};
+use crate::zeyrho::btree::iter::{Keys, LeafCursor, Range, RangeEnds};
 use crate::zeyrho::btree::node::Node;
-use std::cell::RefCell;
+use crate::zeyrho::btree::{DEFAULT_DEGREE, DEFAULT_LEAF_CAPACITY, Order};
+use std::cell::{Ref, RefCell, RefMut};
 use std::fmt::{Debug, Display, Formatter};
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
 use std::rc::Rc;
 /*
 TODO:
@@ -10,7 +16,47 @@ TODO:
 
 #[derive(Debug)]
 pub struct BPlusTree<K: Ord + Debug, V: Debug> {
-    pub root: Option<Rc<RefCell<Node<K, V>>>>,
+    // kept private so nothing outside the btree module can reach a node, `leaf_cell` depends on that
+    pub(super) root: Option<Rc<RefCell<Node<K, V>>>>,
+    pub(super) order: Order,
+}
+
+// the degree bounds how many children a link node has, the leaf capacity how many key values a leaf holds. Leaves
+// carry the actual values so it can make sense to size them differently from the links
+pub struct BPlusTreeBuilder<K: Ord + Debug, V: Debug> {
+    degree: usize,
+    leaf_capacity: usize,
+    _tree: PhantomData<BPlusTree<K, V>>,
+}
+
+impl<K: Ord + Debug, V: Debug> Default for BPlusTreeBuilder<K, V> {
+    fn default() -> Self {
+        BPlusTreeBuilder {
+            degree: DEFAULT_DEGREE,
+            leaf_capacity: DEFAULT_LEAF_CAPACITY,
+            _tree: PhantomData,
+        }
+    }
+}
+
+impl<K: Ord + Debug, V: Debug> BPlusTreeBuilder<K, V> {
+    pub fn degree(mut self, degree: usize) -> Self {
+        self.degree = degree;
+        self
+    }
+
+    pub fn leaf_capacity(mut self, leaf_capacity: usize) -> Self {
+        self.leaf_capacity = leaf_capacity;
+        self
+    }
+
+    pub fn build(self) -> BPlusTree<K, V> {
+        BPlusTree::with_order(Order::new(self.degree, self.leaf_capacity))
+    }
+
+    pub fn build_concurrent(self) -> ConcurrentBPlusTree<K, V> {
+        ConcurrentBPlusTree::with_order(Order::new(self.degree, self.leaf_capacity))
+    }
 }
 
 impl<K: Debug + Ord, V: Debug> Display for BPlusTree<K, V> {
@@ -32,75 +78,354 @@ impl<K: Ord + Debug, V: Debug> Default for BPlusTree<K, V> {
 
 impl<K: Ord + Debug, V: Debug> BPlusTree<K, V> {
     pub fn new() -> Self {
-        BPlusTree { root: None }
+        Self::with_order(Order::default())
+    }
+
+    pub fn builder() -> BPlusTreeBuilder<K, V> {
+        BPlusTreeBuilder::default()
     }
 
-    pub fn insert(&mut self, key: K, value: V) {
-        if self.root.is_none() {
-            self.root = Some(Node::new_leaf_with_kv(Rc::new(key), value));
-            return;
+    fn with_order(order: Order) -> Self {
+        BPlusTree { root: None, order }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match self.entry(key) {
+            Entry::Occupied(mut entry) => Some(entry.insert(value)),
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+                None
+            }
         }
+    }
 
-        if let Some((new_separator, new_node)) =
-            Node::insert_internal(&self.root.as_ref().unwrap().clone(), Rc::new(key), value)
-        {
-            let new_root = Rc::new(RefCell::new(Node::Link {
-                separators: vec![new_separator],
-                children: vec![self.root.take().unwrap(), new_node],
-            }));
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V> {
+        let Some(mut node) = self.root.clone() else {
+            return Entry::Vacant(VacantEntry {
+                key,
+                tree: self,
+                path: Vec::new(),
+                leaf: None,
+                index: 0,
+            });
+        };
 
-            self.root = Some(new_root)
+        let mut path = Vec::new();
+        loop {
+            let child = match &*node.borrow() {
+                Node::Leaf { .. } => None,
+                Node::Link {
+                    separators,
+                    children,
+                } => Some(children[Node::<K, V>::child_index(separators, &key)].clone()),
+            };
+
+            match child {
+                None => break,
+                Some(child) => path.push(std::mem::replace(&mut node, child)),
+            }
         }
+
+        let search = match &*node.borrow() {
+            Node::Leaf { key_vals, .. } => {
+                Node::search_leaf(key_vals, &key).map(|pos| (key_vals[pos].0.clone(), pos))
+            }
+            Node::Link { .. } => unreachable!("entry descent stopped at a link node"),
+        };
+
+        match search {
+            Ok((existing_key, index)) => Entry::Occupied(OccupiedEntry {
+                key: existing_key,
+                tree: self,
+                leaf: node,
+                index,
+            }),
+            Err(index) => Entry::Vacant(VacantEntry {
+                key,
+                tree: self,
+                path,
+                leaf: Some(node),
+                index,
+            }),
+        }
+    }
+
+    // the value stays borrowed from its leaf like with `OccupiedEntry::get`, so the tree can't be changed until the
+    // Ref is dropped
+    pub fn get<Q>(&self, key: &Q) -> Option<Ref<'_, V>>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let leaf = self.leaf_cell(key)?;
+        Ref::filter_map(leaf.borrow(), |node| match node {
+            Node::Leaf { key_vals, .. } => Node::search_leaf(key_vals, key)
+                .ok()
+                .map(|pos| &key_vals[pos].1),
+            Node::Link { .. } => unreachable!("find_leaf returned a link node"),
+        })
+        .ok()
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<RefMut<'_, V>>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let leaf = self.leaf_cell(key)?;
+        RefMut::filter_map(leaf.borrow_mut(), |node| match node {
+            Node::Leaf { key_vals, .. } => Node::search_leaf(key_vals, key)
+                .ok()
+                .map(|pos| &mut key_vals[pos].1),
+            Node::Link { .. } => unreachable!("find_leaf returned a link node"),
+        })
+        .ok()
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.find_leaf(key)
+            .is_some_and(|leaf| match &*leaf.borrow() {
+                Node::Leaf { key_vals, .. } => Node::search_leaf(key_vals, key).is_ok(),
+                Node::Link { .. } => unreachable!("find_leaf returned a link node"),
+            })
+    }
+
+    pub fn iter(&self) -> Range<'_, K, V> {
+        self.range::<K, _>(..)
     }
 
-    pub fn delete(&mut self, key: K) -> Option<()> {
-        todo!()
+    pub fn keys(&self) -> Keys<'_, K, V> {
+        let (front, back) = self.range_cursors::<K, _>(..);
+        Keys::new(front, back)
+    }
+
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = V> + '_
+    where
+        V: Clone,
+    {
+        self.iter().map(|(_, v)| v)
+    }
+
+    pub fn range<Q, R>(&self, range: R) -> Range<'_, K, V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        let (front, back) = self.range_cursors(range);
+        Range::new(front, back)
+    }
+
+    // finds the leaves at each end of the range once, after that the iterators only follow the leaf chain
+    fn range_cursors<Q, R>(&self, range: R) -> RangeEnds<K, V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        match (range.start_bound(), range.end_bound()) {
+            (Bound::Excluded(start), Bound::Excluded(end)) if start == end => {
+                panic!("range start and end are equal and excluded in BPlusTree")
+            }
+            (
+                Bound::Included(start) | Bound::Excluded(start),
+                Bound::Included(end) | Bound::Excluded(end),
+            ) if start > end => {
+                panic!("range start is greater than range end in BPlusTree")
+            }
+            _ => {}
+        }
+
+        let front = match range.start_bound() {
+            Bound::Unbounded => self.edge_leaf(true).map(|leaf| LeafCursor::new(leaf, 0)),
+            Bound::Included(key) => self.leaf_cursor(key, false),
+            Bound::Excluded(key) => self.leaf_cursor(key, true),
+        };
+        let back = match range.end_bound() {
+            Bound::Unbounded => self.edge_leaf(false).map(|leaf| {
+                let len = leaf.borrow().leaf_len();
+                LeafCursor::new(leaf, len)
+            }),
+            Bound::Included(key) => self.leaf_cursor(key, true),
+            Bound::Excluded(key) => self.leaf_cursor(key, false),
+        };
+
+        (front, back)
     }
 
-    fn delete_internal(&mut self, node: &Rc<RefCell<Node<K, V>>>, deleted_key: K) -> Option<()> {
-        todo!()
+    pub fn delete<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let root = self.root.as_ref()?.clone();
+        let removed = Self::delete_internal(&root, key, &self.order)?;
+
+        // the root is allowed to underflow, but once it is empty (or a link with a single child) the tree shrinks
+        let new_root = match &*root.borrow() {
+            Node::Leaf { key_vals, .. } if key_vals.is_empty() => None,
+            Node::Link { children, .. } if children.len() == 1 => Some(children[0].clone()),
+            _ => return Some(removed),
+        };
+        self.root = new_root;
+
+        Some(removed)
+    }
+
+    fn delete_internal<Q>(
+        node: &Rc<RefCell<Node<K, V>>>,
+        deleted_key: &Q,
+        order: &Order,
+    ) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut node_ref = node.borrow_mut();
+        match &mut *node_ref {
+            Node::Leaf { key_vals, .. } => {
+                let pos = Node::search_leaf(key_vals, deleted_key).ok()?;
+
+                Some(key_vals.remove(pos).1)
+            }
+            Node::Link {
+                separators,
+                children,
+            } => {
+                let child_index = Node::<K, V>::child_index(separators, deleted_key);
+
+                let removed = Self::delete_internal(&children[child_index], deleted_key, order)?;
+                Node::rebalance_child(separators, children, child_index, order);
+
+                Some(removed)
+            }
+        }
+    }
+
+    // The leaf that would hold `key`, borrowed for as long as the tree is rather than through an Rc of our own, which
+    // is what lets `get` and `get_mut` hand out a Ref of the value.
+    fn leaf_cell<Q>(&self, key: &Q) -> Option<&RefCell<Node<K, V>>>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let leaf = self.find_leaf(key)?;
+        // SAFETY: the leaf is also owned by its parent (or the root). `root` and the nodes are private to the btree
+        // module and the only code in it that replaces or unlinks a node (insert, entry and delete) takes `&mut self`,
+        // so the allocation outlives the `&self` borrow the reference is tied to.
+        Some(unsafe { &*Rc::as_ptr(&leaf) })
+    }
+
+    // the position right before `key`, or right after it when `after_key` is set
+    fn leaf_cursor<Q>(&self, key: &Q, after_key: bool) -> Option<LeafCursor<K, V>>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let leaf = self.find_leaf(key)?;
+        let index = match &*leaf.borrow() {
+            Node::Leaf { key_vals, .. } => match Node::search_leaf(key_vals, key) {
+                Ok(pos) if after_key => pos + 1,
+                Ok(pos) | Err(pos) => pos,
+            },
+            Node::Link { .. } => unreachable!("find_leaf returned a link node"),
+        };
+
+        Some(LeafCursor::new(leaf, index))
+    }
+
+    fn edge_leaf(&self, leftmost: bool) -> Option<Rc<RefCell<Node<K, V>>>> {
+        let mut node = self.root.clone()?;
+        loop {
+            let child = match &*node.borrow() {
+                Node::Leaf { .. } => None,
+                Node::Link { children, .. } if leftmost => children.first().cloned(),
+                Node::Link { children, .. } => children.last().cloned(),
+            };
+
+            match child {
+                None => return Some(node),
+                Some(child) => node = child,
+            }
+        }
+    }
+
+    // descends through the separators to the only leaf that could hold `key`
+    fn find_leaf<Q>(&self, key: &Q) -> Option<Rc<RefCell<Node<K, V>>>>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut node = self.root.clone()?;
+        loop {
+            let child = match &*node.borrow() {
+                Node::Leaf { .. } => None,
+                Node::Link {
+                    separators,
+                    children,
+                } => Some(children[Node::<K, V>::child_index(separators, key)].clone()),
+            };
+
+            match child {
+                None => return Some(node),
+                Some(child) => node = child,
+            }
+        }
     }
 }
 
 /*
 TODO: Tests could have some better helper functions to reduce code duplication
-TODO: Would be nice if the tests weren't based on DEGREE = 3
  */
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::zeyrho::btree::{CHILDREN_MAX_SIZE, DEGREE};
-    use std::ops::Deref;
+    use crate::zeyrho::btree::TEST_ORDERS;
 
-    fn create_tree() -> BPlusTree<i32, String> {
-        BPlusTree::new()
+    fn create_tree_with_order(order: Order) -> BPlusTree<i32, String> {
+        BPlusTree::builder()
+            .degree(order.degree)
+            .leaf_capacity(order.leaf_capacity)
+            .build()
     }
 
     #[test]
     fn test_single_leaf_node() {
-        let mut tree = create_tree();
-
-        for i in 0..CHILDREN_MAX_SIZE {
-            tree.insert(i as i32, i.to_string());
-        }
-        let root = tree.root.as_ref().unwrap().borrow();
-
-        if let Node::Leaf { key_vals, .. } = &*root {
-            assert_eq!(key_vals.len(), CHILDREN_MAX_SIZE);
-            let mut i = 0;
-            key_vals.iter().for_each(|(x, _)| {
-                assert_eq!(x.as_ref(), &i);
-                i += 1;
-            })
-        } else {
-            panic!("root is link node when it should be leaf node");
+        for order in TEST_ORDERS {
+            let mut tree = create_tree_with_order(order);
+
+            for i in 0..order.max_kvs_in_leaf() {
+                tree.insert(i as i32, i.to_string());
+            }
+            let root = tree.root.as_ref().unwrap().borrow();
+
+            if let Node::Leaf { key_vals, .. } = &*root {
+                assert_eq!(key_vals.len(), order.max_kvs_in_leaf());
+                let mut i = 0;
+                key_vals.iter().for_each(|(x, _)| {
+                    assert_eq!(x.as_ref(), &i);
+                    i += 1;
+                })
+            } else {
+                panic!("root is link node when it should be leaf node");
+            }
         }
     }
 
     #[test]
     fn test_root_link_node() {
-        let mut tree = create_tree();
-        for i in 0..DEGREE {
+        for order in TEST_ORDERS {
+            assert_root_link_node(order);
+        }
+    }
+
+    fn assert_root_link_node(order: Order) {
+        let mut tree = create_tree_with_order(order);
+        for i in 0..=order.max_kvs_in_leaf() {
             tree.insert(i as i32, i.to_string());
         }
         let root = tree.root.as_ref().unwrap().borrow();
@@ -111,7 +436,10 @@ mod tests {
         {
             assert_eq!(separators.len(), 1);
             assert!(!separators.is_empty());
-            assert_eq!(separators.first().unwrap().as_ref(), &1);
+            assert_eq!(
+                separators.first().unwrap().as_ref(),
+                &((order.max_kvs_in_leaf() as i32 + 1) / 2)
+            );
             assert_eq!(children.len(), 2);
 
             let mut separator_index = 0;
@@ -138,133 +466,454 @@ mod tests {
         }
     }
 
-    #[test]
-    fn test_full_root_link_node() {
-        let mut tree = create_tree();
-        for i in 0..5 {
-            tree.insert(i, i.to_string());
+    // groups `items` the way nodes split when every insert lands at the same end of the tree: once the open node
+    // holds more than `max` the first `keep` stay on the left and the rest move right. Inserting at the front leaves
+    // the node on the left open, inserting at the back the one on the right.
+    fn split_runs<T: Clone>(items: &[T], max: usize, keep: usize, at_front: bool) -> Vec<Vec<T>> {
+        let mut runs = Vec::new();
+        let mut open = Vec::new();
+        if at_front {
+            for item in items.iter().rev() {
+                open.insert(0, item.clone());
+                if open.len() > max {
+                    runs.insert(0, open.split_off(keep));
+                }
+            }
+            runs.insert(0, open);
+        } else {
+            for item in items {
+                open.push(item.clone());
+                if open.len() > max {
+                    let right = open.split_off(keep);
+                    runs.push(std::mem::replace(&mut open, right));
+                }
+            }
+            runs.push(open);
         }
+        runs
+    }
+
+    // the links under a two level root for keys 0..count that were all inserted at the same end
+    fn expected_links(order: Order, count: i32, at_front: bool) -> Vec<Vec<Vec<i32>>> {
+        let keys: Vec<i32> = (0..count).collect();
+        let leaves = split_runs(
+            &keys,
+            order.max_kvs_in_leaf(),
+            order.max_kvs_in_leaf().div_ceil(2),
+            at_front,
+        );
+        // a link splits once it has a separator too many, keeping the separators before the middle one
+        split_runs(&leaves, order.degree, order.degree / 2 + 1, at_front)
+    }
+
+    // inserts only ever take the separator from the first key of the new right node, so every separator is the
+    // smallest key under the child to its right
+    fn assert_two_level_shape(tree: &BPlusTree<i32, String>, expected: &[Vec<Vec<i32>>]) {
+        let first_key = |leaves: &Vec<Vec<i32>>| leaves[0][0];
 
         let root = tree.root.as_ref().unwrap().borrow();
-        if let Node::Link {
+        let Node::Link {
             separators,
             children,
         } = &*root
-        {
-            assert_eq!(separators.len(), 1);
-
-            let mut separator_index = 0;
-            children.iter().for_each(|child| {
-                if let Node::Leaf { key_vals, .. } = &*child.borrow() {
-                    key_vals
+        else {
+            panic!("root is leaf node when it should be link node");
+        };
+
+        let collected: Vec<i32> = separators.iter().map(|s| *s.as_ref()).collect();
+        let expected_separators: Vec<i32> = expected[1..].iter().map(first_key).collect();
+        assert_eq!(collected, expected_separators);
+        assert_eq!(children.len(), expected.len());
+
+        for (child, expected_leaves) in children.iter().zip(expected) {
+            let Node::Link {
+                separators,
+                children,
+            } = &*child.borrow()
+            else {
+                panic!("child of the root is leaf node when it should be link node");
+            };
+
+            let collected: Vec<i32> = separators.iter().map(|s| *s.as_ref()).collect();
+            let expected_separators: Vec<i32> =
+                expected_leaves[1..].iter().map(|leaf| leaf[0]).collect();
+            assert_eq!(collected, expected_separators);
+
+            let leaves: Vec<Vec<i32>> = children
+                .iter()
+                .map(|leaf| match &*leaf.borrow() {
+                    Node::Leaf { key_vals, .. } => key_vals
                         .iter()
-                        .for_each(|(key, value): &(Rc<i32>, String)| {
-                            assert!(separators[separator_index].as_ref() >= key.as_ref());
-                            assert_eq!(&key.as_ref().to_string(), value);
+                        .map(|(k, v)| {
+                            assert_eq!(&k.to_string(), v);
+                            *k.as_ref()
                         })
-                }
-                separator_index += 1;
-            });
-        } else {
-            panic!("root is leaf node when it should be link node");
+                        .collect(),
+                    Node::Link { .. } => panic!("grandchild of the root is not a leaf"),
+                })
+                .collect();
+            assert_eq!(&leaves, expected_leaves);
+        }
+    }
+
+    // the number of ascending inserts after which the root link has just split in two
+    fn keys_to_split_root(order: Order) -> i32 {
+        let leaf_capacity = order.max_kvs_in_leaf();
+        // the first leaf splits at one over capacity, after that the rightmost leaf splits again every time it takes
+        // in as many keys as the last split left behind on the left, and the root splits at one child over the degree
+        (leaf_capacity + 1 + (order.degree - 1) * leaf_capacity.div_ceil(2)) as i32
+    }
+
+    #[test]
+    fn test_full_root_link_node() {
+        for order in TEST_ORDERS {
+            let mut tree = create_tree_with_order(order);
+            let count = keys_to_split_root(order);
+
+            for i in 0..count - 1 {
+                tree.insert(i, i.to_string());
+            }
+            // one key short the root is still a single full link node
+            if let Node::Link { children, .. } = &*tree.root.as_ref().unwrap().borrow() {
+                assert_eq!(children.len(), order.degree);
+            } else {
+                panic!("root is leaf node when it should be link node");
+            }
+
+            tree.insert(count - 1, (count - 1).to_string());
+            let expected = expected_links(order, count, false);
+            assert_eq!(expected.len(), 2);
+            assert_two_level_shape(&tree, &expected);
         }
     }
 
     #[test]
     fn test_middle_inserts() {
-        let mut tree = create_tree();
-        for i in vec![0, 12, 2, 10, 4, 8, 6].iter() {
-            tree.insert(*i, i.to_string());
+        for order in TEST_ORDERS {
+            let mut tree = create_tree_with_order(order);
+            // enough keys that the tree has to grow past a single level of links
+            let count = (order.max_kvs_in_leaf() * order.degree * 2) as i32;
+            let high = (count - 1) * 2;
+
+            // every insert lands between the keys inserted so far, working in from both ends
+            let mut inserted = Vec::new();
+            for i in 0..count {
+                let key = if i % 2 == 0 { i } else { high - (i - 1) };
+                tree.insert(key, key.to_string());
+                inserted.push(key);
+            }
+            inserted.sort();
+
+            assert_eq!(collect_keys(&tree), inserted);
+            assert!(assert_balanced(tree.root.as_ref().unwrap(), true, &order) > 2);
+            assert_separators_bound_children(tree.root.as_ref().unwrap());
         }
+    }
 
-        let mut separator_index = 0;
-        let expected_separators = [vec![&2], vec![&6, &10]];
+    #[test]
+    fn test_insert_smaller_keys() {
+        for order in TEST_ORDERS {
+            let leaf_capacity = order.max_kvs_in_leaf();
+            // inserting at the front the leftmost leaf splits again every time it takes in as many keys as the last
+            // split moved right. Stop once there are two more leaves than the degree, so the root has split and its
+            // left link has grown again
+            let count =
+                leaf_capacity + 1 + order.degree * (leaf_capacity + 1 - leaf_capacity.div_ceil(2));
+            let count = count as i32;
+
+            let mut tree = create_tree_with_order(order);
+            for i in (0..count).rev() {
+                tree.insert(i, i.to_string());
+            }
+
+            let expected = expected_links(order, count, true);
+            assert_eq!(expected.len(), 2);
+            assert_eq!(expected[0].len(), order.degree / 2 + 2);
+            assert_two_level_shape(&tree, &expected);
+        }
+    }
 
-        let mut child_index = 0;
-        let expected_children = [vec![&0], vec![&2], vec![&4], vec![&6, &8], vec![&10, &12]];
+    fn leftmost_leaf(tree: &BPlusTree<i32, String>) -> Option<Rc<RefCell<Node<i32, String>>>> {
+        let mut node = tree.root.clone()?;
+        loop {
+            let next = match &*node.borrow() {
+                Node::Leaf { .. } => None,
+                Node::Link { children, .. } => Some(children[0].clone()),
+            };
+            match next {
+                None => return Some(node),
+                Some(child) => node = child,
+            }
+        }
+    }
 
-        if let Node::Link {
-            separators,
-            children,
-        } = tree.root.unwrap().borrow().deref()
-        {
-            assert_eq!(separators.len(), 1);
+    // walks the leaf chain left to right, checking that every `prev` points back at the leaf we came from
+    fn collect_keys(tree: &BPlusTree<i32, String>) -> Vec<i32> {
+        let mut keys = Vec::new();
+        let mut current = leftmost_leaf(tree);
+        let mut previous: Option<Rc<RefCell<Node<i32, String>>>> = None;
+
+        while let Some(leaf) = current {
+            let next = if let Node::Leaf {
+                key_vals,
+                next,
+                prev,
+            } = &*leaf.borrow()
+            {
+                match (&previous, prev.as_ref().and_then(|p| p.upgrade())) {
+                    (Some(expected), Some(actual)) => assert!(Rc::ptr_eq(expected, &actual)),
+                    (None, None) => {}
+                    (_, _) => panic!("prev link does not match the leaf chain"),
+                }
+                key_vals.iter().for_each(|(k, v)| {
+                    assert_eq!(&k.to_string(), v);
+                    keys.push(*k.as_ref());
+                });
+                next.as_ref().and_then(|n| n.upgrade())
+            } else {
+                panic!("leaf chain contains a link node");
+            };
+            previous = Some(leaf);
+            current = next;
+        }
 
-            children.iter().for_each(|child| {
-                if let Node::Link {
-                    separators,
-                    children,
-                    ..
-                } = &*child.borrow()
-                {
-                    let collected: Vec<&i32> = separators.iter().map(|s| s.as_ref()).collect();
-                    assert_eq!(expected_separators[separator_index], collected);
-                    separator_index += 1;
-
-                    for child in children.iter() {
-                        if let Node::Leaf { key_vals, .. } = child.borrow().deref() {
-                            let collected: Vec<&i32> = key_vals
-                                .iter()
-                                .map(|(k, _): &(Rc<i32>, String)| k.as_ref())
-                                .collect();
-                            assert_eq!(expected_children[child_index], collected);
-                        }
-                        child_index += 1;
-                    }
+        keys
+    }
+
+    fn collect_leaves(tree: &BPlusTree<i32, String>) -> Vec<Vec<i32>> {
+        let mut leaves = Vec::new();
+        let mut current = leftmost_leaf(tree);
+        while let Some(leaf) = current {
+            current = match &*leaf.borrow() {
+                Node::Leaf { key_vals, next, .. } => {
+                    leaves.push(key_vals.iter().map(|(k, _)| *k.as_ref()).collect());
+                    next.as_ref().and_then(|n| n.upgrade())
                 }
-            });
-        } else {
-            panic!("root is leaf node when it should be link node");
+                Node::Link { .. } => panic!("leaf chain contains a link node"),
+            };
+        }
+        leaves
+    }
+
+    // returns the depth of the subtree after asserting that every leaf sits at the same depth and no non-root node underflows
+    fn assert_balanced(
+        node: &Rc<RefCell<Node<i32, String>>>,
+        is_root: bool,
+        order: &Order,
+    ) -> usize {
+        match &*node.borrow() {
+            Node::Leaf { key_vals, .. } => {
+                assert!(key_vals.len() <= order.max_kvs_in_leaf());
+                assert!(is_root || key_vals.len() >= order.min_kvs_in_leaf());
+                1
+            }
+            Node::Link {
+                separators,
+                children,
+            } => {
+                assert_eq!(separators.len() + 1, children.len());
+                assert!(separators.len() <= order.separators_max_size());
+                assert!(is_root || separators.len() >= order.separators_min_size());
+                let depths: Vec<usize> = children
+                    .iter()
+                    .map(|child| assert_balanced(child, false, order))
+                    .collect();
+                assert!(depths.iter().all(|d| *d == depths[0]));
+                depths[0] + 1
+            }
+        }
+    }
+
+    // every key under a child has to sit between the separators on either side of it
+    fn assert_separators_bound_children(node: &Rc<RefCell<Node<i32, String>>>) -> (i32, i32) {
+        match &*node.borrow() {
+            Node::Leaf { key_vals, .. } => (
+                *key_vals.first().unwrap().0.as_ref(),
+                *key_vals.last().unwrap().0.as_ref(),
+            ),
+            Node::Link {
+                separators,
+                children,
+            } => {
+                let bounds: Vec<(i32, i32)> = children
+                    .iter()
+                    .map(assert_separators_bound_children)
+                    .collect();
+                for (i, separator) in separators.iter().enumerate() {
+                    assert!(bounds[i].1 < *separator.as_ref());
+                    assert!(*separator.as_ref() <= bounds[i + 1].0);
+                }
+                (bounds[0].0, bounds[bounds.len() - 1].1)
+            }
         }
     }
 
     #[test]
-    fn test_insert_smaller_keys() {
-        let mut tree = create_tree();
-        for i in (0..DEGREE * DEGREE).rev() {
-            tree.insert(i as i32, i.to_string());
+    fn test_delete_from_single_leaf() {
+        for order in TEST_ORDERS {
+            let mut tree = create_tree_with_order(order);
+            tree.insert(1, 1.to_string());
+            tree.insert(2, 2.to_string());
+
+            assert_eq!(tree.delete(&3), None);
+            assert_eq!(tree.delete(&1), Some(1.to_string()));
+            assert_eq!(collect_keys(&tree), vec![2]);
+            assert_eq!(tree.delete(&1), None);
+            assert_eq!(tree.delete(&2), Some(2.to_string()));
+            assert!(tree.root.is_none());
+            assert_eq!(tree.delete(&2), None);
         }
+    }
 
-        let mut separator_index = 0;
-        let expected_separators = [vec![&1, &3], vec![&7]];
+    #[test]
+    fn test_delete_borrows_from_sibling() {
+        for order in TEST_ORDERS {
+            let mut tree = create_tree_with_order(order);
+            let min_kvs = order.max_kvs_in_leaf().div_ceil(2) as i32;
+            // fill the rightmost leaf without splitting it, every leaf before it is left holding the minimum
+            let count = keys_to_split_root(order) + min_kvs - 1;
+            for i in 0..count {
+                tree.insert(i, i.to_string());
+            }
+
+            let mut leaves: Vec<Vec<i32>> = expected_links(order, count, false)
+                .into_iter()
+                .flatten()
+                .collect();
+            let last = leaves.len() - 1;
+            assert_eq!(leaves[last - 1].len() as i32, min_kvs);
+            assert_eq!(leaves[last].len(), order.max_kvs_in_leaf());
+
+            // the leaf left of the full one underflows and its left sibling has nothing to spare, so it has to
+            // borrow the first key of the full leaf instead of merging
+            let deleted = leaves[last - 1].remove(0);
+            let borrowed = leaves[last].remove(0);
+            leaves[last - 1].push(borrowed);
+
+            assert_eq!(tree.delete(&deleted), Some(deleted.to_string()));
+            assert_eq!(collect_leaves(&tree), leaves);
+            assert_balanced(tree.root.as_ref().unwrap(), true, &tree.order);
+        }
+    }
 
-        let mut child_index = 0;
-        let expected_children = [vec![&0], vec![&1, &2], vec![&3, &4], vec![&5, &6], vec![
-            &7, &8,
-        ]];
+    #[test]
+    fn test_delete_collapses_root() {
+        for order in TEST_ORDERS {
+            let mut tree = create_tree_with_order(order);
+            let last = order.max_kvs_in_leaf() as i32;
+            for i in 0..=last {
+                tree.insert(i, i.to_string());
+            }
+
+            for i in 0..last {
+                assert_eq!(tree.delete(&i), Some(i.to_string()));
+            }
+
+            assert!(matches!(
+                &*tree.root.as_ref().unwrap().borrow(),
+                Node::Leaf { .. }
+            ));
+            assert_eq!(collect_keys(&tree), vec![last]);
+        }
+    }
 
-        if let Node::Link {
-            separators,
-            children,
-        } = tree.root.unwrap().borrow().deref()
-        {
-            assert_eq!(separators.len(), 1);
+    #[test]
+    fn test_get() {
+        for order in TEST_ORDERS {
+            let mut tree = create_tree_with_order(order);
+            assert!(tree.get(&0).is_none());
+
+            for i in (0..500).map(|i| i * 2) {
+                tree.insert(i, i.to_string());
+            }
+
+            for i in 0..1000 {
+                if i % 2 == 0 {
+                    assert_eq!(tree.get(&i).as_deref(), Some(&i.to_string()));
+                    assert!(tree.contains_key(&i));
+                } else {
+                    assert!(tree.get(&i).is_none());
+                    assert!(!tree.contains_key(&i));
+                }
+            }
+        }
+    }
 
-            children.iter().for_each(|child| {
-                if let Node::Link {
-                    separators,
-                    children,
-                    ..
-                } = &*child.borrow()
-                {
-                    let collected: Vec<&i32> = separators.iter().map(|s| s.as_ref()).collect();
-                    assert_eq!(expected_separators[separator_index], collected);
-                    separator_index += 1;
-
-                    for child in children.iter() {
-                        if let Node::Leaf { key_vals, .. } = child.borrow().deref() {
-                            let collected: Vec<&i32> = key_vals
-                                .iter()
-                                .map(|(k, _): &(Rc<i32>, String)| k.as_ref())
-                                .collect();
-                            assert_eq!(expected_children[child_index], collected);
-                        }
-                        child_index += 1;
+    #[test]
+    fn test_get_mut() {
+        for order in TEST_ORDERS {
+            let mut tree = create_tree_with_order(order);
+            for i in 0..20 {
+                tree.insert(i, i.to_string());
+            }
+
+            tree.get_mut(&7).unwrap().push('!');
+            assert!(tree.get_mut(&20).is_none());
+            assert_eq!(tree.get(&7).as_deref(), Some(&"7!".to_string()));
+
+            // the leaf stays borrowed for as long as the value is
+            let value = tree.get(&3).unwrap();
+            assert_eq!(tree.get(&4).as_deref(), Some(&"4".to_string()));
+            assert_eq!(*value, "3");
+        }
+    }
+
+    #[test]
+    fn test_get_borrowed_key() {
+        let mut tree: BPlusTree<String, i32> = BPlusTree::new();
+        for i in 0..10 {
+            tree.insert(format!("key-{}", i), i);
+        }
+
+        assert_eq!(tree.get("key-3").map(|v| *v), Some(3));
+        assert!(tree.contains_key("key-9"));
+        assert_eq!(tree.delete("key-3"), Some(3));
+        assert!(!tree.contains_key("key-3"));
+    }
+
+    #[test]
+    fn test_delete_all_keys() {
+        let deletion_orders: Vec<Vec<i32>> = vec![
+            (0..300).collect(),
+            (0..300).rev().collect(),
+            (0..300).map(|i| (i * 37) % 300).collect(),
+        ];
+
+        for order in TEST_ORDERS {
+            for deletion_order in deletion_orders.iter() {
+                let mut tree = create_tree_with_order(order);
+                for i in 0..300 {
+                    tree.insert(i, i.to_string());
+                }
+
+                let mut remaining: Vec<i32> = (0..300).collect();
+                for key in deletion_order {
+                    assert_eq!(tree.delete(key), Some(key.to_string()));
+                    remaining.retain(|k| k != key);
+
+                    assert_eq!(collect_keys(&tree), remaining);
+                    if let Some(root) = tree.root.as_ref() {
+                        assert_balanced(root, true, &order);
                     }
                 }
-            });
-        } else {
-            panic!("root is leaf node when it should be link node");
+
+                assert!(tree.root.is_none());
+            }
         }
     }
+
+    #[test]
+    fn test_builder() {
+        let tree: BPlusTree<i32, String> = BPlusTree::builder().degree(8).leaf_capacity(32).build();
+        assert_eq!(tree.order, Order::new(8, 32));
+
+        let tree: BPlusTree<i32, String> = BPlusTree::new();
+        assert_eq!(tree.order, Order::default());
+    }
+
+    #[test]
+    #[should_panic(expected = "BPlusTree degree must be at least 3")]
+    fn test_builder_rejects_small_degree() {
+        let _: BPlusTree<i32, String> = BPlusTree::builder().degree(2).build();
+    }
 }
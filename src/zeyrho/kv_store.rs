@@ -1,41 +1,116 @@
 // This file is @generated by prost-build.
+/// Keys and values are bytes. Keys used to be strings, which have the same encoding on the wire, so older callers keep
+/// working for those. Values used to be int32: callers that still set `int32_value` get it stored as the 4 little endian
+/// bytes of the int, and can read it back through `GetResponse.int32_value`.
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct SetRequest {
-    #[prost(string, tag = "1")]
-    pub key: ::prost::alloc::string::String,
-    #[prost(int32, tag = "2")]
-    pub value: i32,
+    #[prost(bytes = "vec", tag = "1")]
+    pub key: ::prost::alloc::vec::Vec<u8>,
+    /// only respond once the write is visible to reads, instead of once it is journaled
+    #[prost(bool, tag = "4")]
+    pub wait_for_apply: bool,
+    #[prost(oneof = "set_request::Value", tags = "2, 3")]
+    pub value: ::core::option::Option<set_request::Value>,
+}
+/// Nested message and enum types in `SetRequest`.
+pub mod set_request {
+    #[derive(serde::Serialize, serde::Deserialize)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Value {
+        #[prost(int32, tag = "2")]
+        Int32Value(i32),
+        #[prost(bytes, tag = "3")]
+        BytesValue(::prost::alloc::vec::Vec<u8>),
+    }
 }
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(Clone, Copy, PartialEq, ::prost::Message)]
 pub struct SetResponse {
     #[prost(bool, tag = "1")]
     pub confirmation: bool,
+    /// pass as `min_sequence` on a read to make sure it sees this write
+    #[prost(uint64, tag = "2")]
+    pub sequence: u64,
 }
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct GetRequest {
-    #[prost(string, tag = "1")]
-    pub key: ::prost::alloc::string::String,
+    #[prost(bytes = "vec", tag = "1")]
+    pub key: ::prost::alloc::vec::Vec<u8>,
+    /// waits until the write with this sequence has been applied before reading, 0 doesn't wait
+    #[prost(uint64, tag = "2")]
+    pub min_sequence: u64,
 }
 #[derive(serde::Serialize, serde::Deserialize)]
-#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct GetResponse {
+    /// the value read back as an int32 for the older callers, only set when it is empty or 4 bytes long
     #[prost(int32, optional, tag = "1")]
-    pub value: ::core::option::Option<i32>,
+    pub int32_value: ::core::option::Option<i32>,
+    #[prost(bytes = "vec", optional, tag = "2")]
+    pub value: ::core::option::Option<::prost::alloc::vec::Vec<u8>>,
 }
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct DeleteRequest {
-    #[prost(string, tag = "1")]
-    pub key: ::prost::alloc::string::String,
+    #[prost(bytes = "vec", tag = "1")]
+    pub key: ::prost::alloc::vec::Vec<u8>,
 }
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(Clone, Copy, PartialEq, ::prost::Message)]
 pub struct DeleteResponse {
+    /// whether the key was there to delete
     #[prost(bool, tag = "1")]
     pub confirmation: bool,
+    #[prost(uint64, tag = "2")]
+    pub sequence: u64,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ScanRequest {
+    /// inclusive, empty starts at the first key
+    #[prost(bytes = "vec", tag = "1")]
+    pub start: ::prost::alloc::vec::Vec<u8>,
+    /// exclusive, unset runs to the last key
+    #[prost(bytes = "vec", optional, tag = "2")]
+    pub end: ::core::option::Option<::prost::alloc::vec::Vec<u8>>,
+    /// only keys starting with this, on top of start and end
+    #[prost(bytes = "vec", optional, tag = "3")]
+    pub prefix: ::core::option::Option<::prost::alloc::vec::Vec<u8>>,
+    /// 0 (or anything over the server's page size) returns a full page
+    #[prost(uint32, tag = "4")]
+    pub limit: u32,
+    /// streams from the end of the range backwards
+    #[prost(bool, tag = "5")]
+    pub reverse: bool,
+    /// the cursor of the last pair a previous scan returned, the scan picks up right after it
+    #[prost(bytes = "vec", optional, tag = "6")]
+    pub cursor: ::core::option::Option<::prost::alloc::vec::Vec<u8>>,
+    /// same as GetRequest.min_sequence
+    #[prost(uint64, tag = "7")]
+    pub min_sequence: u64,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ScanResponse {
+    #[prost(bytes = "vec", tag = "1")]
+    pub key: ::prost::alloc::vec::Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub value: ::prost::alloc::vec::Vec<u8>,
+    #[prost(bytes = "vec", tag = "3")]
+    pub cursor: ::prost::alloc::vec::Vec<u8>,
+}
+/// writes everything applied so far to a snapshot, after which the log before it can be dropped
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct SnapshotRequest {}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct SnapshotResponse {
+    /// the sequence number of the last write the snapshot includes
+    #[prost(uint64, tag = "1")]
+    pub sequence: u64,
 }
 /// Generated client implementations.
 pub mod kv_store_client {
@@ -182,6 +257,50 @@ pub mod kv_store_client {
             req.extensions_mut().insert(GrpcMethod::new("kv_store.KVStore", "Delete"));
             self.inner.unary(req, path, codec).await
         }
+        pub async fn scan(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ScanRequest>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::ScanResponse>>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/kv_store.KVStore/Scan");
+            let mut req = request.into_request();
+            req.extensions_mut().insert(GrpcMethod::new("kv_store.KVStore", "Scan"));
+            self.inner.server_streaming(req, path, codec).await
+        }
+        pub async fn snapshot(
+            &mut self,
+            request: impl tonic::IntoRequest<super::SnapshotRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::SnapshotResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/kv_store.KVStore/Snapshot",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut().insert(GrpcMethod::new("kv_store.KVStore", "Snapshot"));
+            self.inner.unary(req, path, codec).await
+        }
     }
 }
 /// Generated server implementations.
@@ -209,6 +328,23 @@ pub mod kv_store_server {
             &self,
             request: tonic::Request<super::DeleteRequest>,
         ) -> std::result::Result<tonic::Response<super::DeleteResponse>, tonic::Status>;
+        /// Server streaming response type for the Scan method.
+        type ScanStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::ScanResponse, tonic::Status>,
+            >
+            + std::marker::Send
+            + 'static;
+        async fn scan(
+            &self,
+            request: tonic::Request<super::ScanRequest>,
+        ) -> std::result::Result<tonic::Response<Self::ScanStream>, tonic::Status>;
+        async fn snapshot(
+            &self,
+            request: tonic::Request<super::SnapshotRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::SnapshotResponse>,
+            tonic::Status,
+        >;
     }
     #[derive(Debug)]
     pub struct KvStoreServer<T> {
@@ -415,6 +551,95 @@ pub mod kv_store_server {
                     };
                     Box::pin(fut)
                 }
+                "/kv_store.KVStore/Scan" => {
+                    #[allow(non_camel_case_types)]
+                    struct ScanSvc<T: KvStore>(pub Arc<T>);
+                    impl<
+                        T: KvStore,
+                    > tonic::server::ServerStreamingService<super::ScanRequest>
+                    for ScanSvc<T> {
+                        type Response = super::ScanResponse;
+                        type ResponseStream = T::ScanStream;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::ResponseStream>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ScanRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as KvStore>::scan(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = ScanSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/kv_store.KVStore/Snapshot" => {
+                    #[allow(non_camel_case_types)]
+                    struct SnapshotSvc<T: KvStore>(pub Arc<T>);
+                    impl<T: KvStore> tonic::server::UnaryService<super::SnapshotRequest>
+                    for SnapshotSvc<T> {
+                        type Response = super::SnapshotResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::SnapshotRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as KvStore>::snapshot(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = SnapshotSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 _ => {
                     Box::pin(async move {
                         let mut response = http::Response::new(empty_body());